@@ -1,5 +1,21 @@
 mod provider;
 mod s3;
+mod throttle;
 
+pub use crate::provider::OverwritePolicy;
 pub use provider::create_aws_config;
+pub use provider::AwsConfigBuilder;
+pub use provider::DedupGuard;
+pub use provider::IdStrategy;
+pub use provider::IdentityNamer;
+pub use provider::KeyNamer;
+pub use provider::ObjectLock;
+pub use provider::ObjectLockMode;
+pub use provider::PreviousKey;
+pub use provider::S3Checksum;
+pub use provider::StorageClass;
+pub use provider::UploadMeta;
 pub use provider::AWS;
+pub use s3::PreparedUpload;
+pub use s3::PresignedUrl;
+pub use s3::ProbeResult;
@@ -1,205 +1,5018 @@
-use crate::aws::AWS;
-use crate::crypto::hash::ChunkedHash;
-use crate::provider::{FileHash, FileSize, StorageId};
-use anyhow::{anyhow, Result};
-use aws_sdk_s3::model::{CompletedMultipartUpload, CompletedPart};
-use aws_sdk_s3::types::ByteStream;
-use bytes::BytesMut;
-use tokio::fs::{remove_file, File};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio_stream::StreamExt;
-use tracing::{error, instrument, trace};
+use crate::aws::{IdStrategy, ObjectLockMode, OverwritePolicy, S3Checksum, StorageClass, AWS};
+use crate::cloud::TempFile;
+use crate::crypto::hash::{
+    hashes_equal, ChunkedHash, HashAlgo, HashKind, MerkleHash, MerkleHasher,
+};
+use crate::error::{write_error, CloudError};
+use crate::provider::{
+    fill_buffer, try_join_buffered, CloudProvider, Compression, DownloadTarget, FileHash, FileSize,
+    StorageId, TransferEvent, TransferKind, TransferStats, UploadOutcome, UploadReceipt,
+    VerifyResult, READ_SIZE,
+};
+use anyhow::{anyhow, Context, Result};
+use async_compression::tokio::bufread::ZstdEncoder;
+use async_compression::tokio::write::ZstdDecoder;
+use aws_sdk_s3::error::HeadObjectError;
+use aws_sdk_s3::model::{
+    CompletedMultipartUpload, CompletedPart, Delete, ObjectIdentifier, ObjectLockLegalHoldStatus,
+    ObjectLockMode as S3ObjectLockMode, StorageClass as S3StorageClass,
+};
+use aws_sdk_s3::output::CompleteMultipartUploadOutput;
+use aws_sdk_s3::presigning::config::PresigningConfig;
+use aws_sdk_s3::presigning::request::PresignedRequest;
+use aws_sdk_s3::types::{ByteStream, DateTime, SdkError};
+use aws_smithy_http::body::SdkBody;
+use aws_smithy_types::date_time::Format;
+use aws_smithy_types::retry::ProvideErrorKind;
+use base64::prelude::{Engine, BASE64_STANDARD};
+use bytes::{Bytes, BytesMut};
+use futures::stream::{self, BoxStream, StreamExt, TryStreamExt};
+use md5::{Digest, Md5};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::fs::{remove_file, File, OpenOptions};
+use tokio::io::{
+    AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt, BufReader, SeekFrom,
+};
+use tokio::sync::mpsc::{self, UnboundedSender};
+use tokio::time::{sleep, timeout};
+use tokio_util::io::StreamReader;
+use tokio_util::task::AbortOnDropHandle;
+use tracing::{error, instrument, trace, warn, Span};
 use uuid::Uuid;
 
 const CHUNK_SIZE: usize = 100 * 1024 * 1024;
+const COMPRESSION_METADATA: &str = "compression";
+const HASH_ALGO_METADATA: &str = "hash-algo";
+const KEY_FINGERPRINT_METADATA: &str = "key-fingerprint";
 
-#[instrument]
-pub async fn s3_upload_file(
+type EventSender = UnboundedSender<TransferEvent>;
+
+fn compression_name(compression: Compression) -> &'static str {
+    match compression {
+        Compression::Zstd => "zstd",
+    }
+}
+
+fn object_compression(metadata: Option<&HashMap<String, String>>) -> Result<Option<Compression>> {
+    match metadata.and_then(|m| m.get(COMPRESSION_METADATA)) {
+        None => Ok(None),
+        Some(name) if name == compression_name(Compression::Zstd) => Ok(Some(Compression::Zstd)),
+        Some(name) => Err(anyhow!("Unsupported object compression {:?}", name)),
+    }
+}
+
+fn hash_algo_name(hash_algo: HashAlgo) -> &'static str {
+    match hash_algo {
+        HashAlgo::Blake2b => "blake2b",
+        HashAlgo::Sha256 => "sha256",
+    }
+}
+
+fn object_hash_algo(metadata: Option<&HashMap<String, String>>) -> Result<HashAlgo> {
+    match metadata.and_then(|m| m.get(HASH_ALGO_METADATA)) {
+        None => Ok(HashAlgo::default()),
+        Some(name) if name == hash_algo_name(HashAlgo::Blake2b) => Ok(HashAlgo::Blake2b),
+        Some(name) if name == hash_algo_name(HashAlgo::Sha256) => Ok(HashAlgo::Sha256),
+        Some(name) => Err(anyhow!("Unsupported object hash algorithm {:?}", name)),
+    }
+}
+
+// How an object was hashed on upload, from its metadata.
+#[derive(Clone, Debug, Default)]
+struct ObjectHashing {
+    hash_algo: HashAlgo,
+    // None for objects uploaded before fingerprints were recorded.
+    key_fingerprint: Option<String>,
+}
+
+fn object_hashing(metadata: Option<&HashMap<String, String>>) -> Result<ObjectHashing> {
+    Ok(ObjectHashing {
+        hash_algo: object_hash_algo(metadata)?,
+        key_fingerprint: metadata
+            .and_then(|m| m.get(KEY_FINGERPRINT_METADATA))
+            .cloned(),
+    })
+}
+
+// Downloaded data hashed under every key the object may be under, so objects of unknown key
+// still take a single pass.
+struct ObjectHash {
+    hashes: Vec<ChunkedHash>,
+}
+
+impl ObjectHash {
+    fn new(aws: &AWS, hashing: &ObjectHashing) -> ObjectHash {
+        let hashes = match hashing.hash_algo {
+            // Unkeyed, so the same under any key.
+            HashAlgo::Sha256 => vec![ChunkedHash::sha256()],
+            HashAlgo::Blake2b => aws
+                .file_hash_keys(hashing.key_fingerprint.as_deref())
+                .into_iter()
+                .map(ChunkedHash::keyed)
+                .collect(),
+        };
+
+        ObjectHash { hashes }
+    }
+
+    fn update(&mut self, data: Bytes) {
+        for hash in &mut self.hashes {
+            hash.update(data.clone());
+        }
+    }
+
+    // Hash equal to `expected` under any key, otherwise the one under the first key.
+    fn finalize(self, expected: &FileHash) -> FileHash {
+        let hashes: Vec<_> = self
+            .hashes
+            .into_iter()
+            .map(|hash| FileHash::from_bytes(hash.finalize()))
+            .collect();
+
+        hashes
+            .iter()
+            .find(|hash| *hash == expected)
+            .unwrap_or(&hashes[0])
+            .to_owned()
+    }
+}
+
+// Tags are sent as URL query string.
+fn object_tagging(tags: &BTreeMap<String, String>) -> Option<String> {
+    if tags.is_empty() {
+        return None;
+    }
+
+    Some(
+        form_urlencoded::Serializer::new(String::new())
+            .extend_pairs(tags)
+            .finish(),
+    )
+}
+
+// Keep S3 request ids from the response, AWS support needs them to investigate failures.
+// S3 rejects requests signed further than this from its own time.
+const MAX_CLOCK_SKEW: Duration = Duration::from_secs(15 * 60);
+
+fn request_error<E>(operation: &'static str, e: SdkError<E>) -> anyhow::Error
+where
+    E: std::error::Error + ProvideErrorKind + Send + Sync + 'static,
+{
+    let raw = match &e {
+        SdkError::ServiceError { raw, .. } | SdkError::ResponseError { raw, .. } => raw,
+        _ => return e.into(),
+    };
+    let header = |name| {
+        raw.http()
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_owned)
+    };
+
+    // Error responses to HEAD have no body and so no code. They are told apart by status: an
+    // expired session is the usual 400 to a well-formed HEAD, and a skewed clock shows in the
+    // Date header of a 403.
+    let code = match &e {
+        SdkError::ServiceError { err, .. } => err.code(),
+        _ => None,
+    };
+    let status = raw.http().status().as_u16();
+    let skewed = || {
+        header("Date")
+            .and_then(|date| DateTime::from_str(&date, Format::HttpDate).ok())
+            .is_some_and(|date| {
+                let now = DateTime::from(SystemTime::now());
+                date.secs().abs_diff(now.secs()) > MAX_CLOCK_SKEW.as_secs()
+            })
+    };
+    let expired = code == Some("ExpiredToken")
+        || (code.is_none() && status == 400 && operation == "HeadObject");
+    let skew =
+        code == Some("RequestTimeTooSkewed") || (code.is_none() && status == 403 && skewed());
+
+    if expired {
+        return CloudError::CredentialsExpired {
+            operation,
+            source: e.into(),
+        }
+        .into();
+    }
+
+    if skew {
+        return CloudError::ClockSkew {
+            operation,
+            source: e.into(),
+        }
+        .into();
+    }
+
+    CloudError::Request {
+        operation,
+        request_id: header("x-amz-request-id"),
+        extended_request_id: header("x-amz-id-2"),
+        source: e.into(),
+    }
+    .into()
+}
+
+// Fail stalled transfers fast instead of waiting for SDK timeouts.
+async fn with_part_timeout<T>(aws: &AWS, future: impl Future<Output = T>) -> Result<T> {
+    timeout(aws.part_timeout(), future)
+        .await
+        .map_err(|_| CloudError::Timeout(aws.part_timeout()).into())
+}
+
+// Part timeout counted from the last progress rather than the start, so slow links can send
+// parts of any size.
+async fn with_idle_timeout<T>(
     aws: &AWS,
-    path: &std::path::Path,
-) -> Result<(StorageId, FileSize, FileHash)> {
-    let storage_id = Uuid::new_v4().hyphenated().to_string();
+    progress: &Mutex<tokio::time::Instant>,
+    future: impl Future<Output = T>,
+) -> Result<T> {
+    tokio::pin!(future);
 
-    trace!(%storage_id, "uploading file");
+    loop {
+        let idle_until = *progress.lock().expect("poisoned lock") + aws.part_timeout();
 
-    let mut file = File::open(path).await?;
-    let start_resp = aws
+        tokio::select! {
+            result = &mut future => return Ok(result),
+            _ = tokio::time::sleep_until(idle_until) => {
+                if *progress.lock().expect("poisoned lock") + aws.part_timeout() <= idle_until {
+                    return Err(CloudError::Timeout(aws.part_timeout()).into());
+                }
+            }
+        }
+    }
+}
+
+// Part body is handed to the connection in slices of this size. The connection takes the next
+// one once the previous is written out, so the time of the last take tracks upload progress.
+const PART_SLICE_SIZE: usize = 256 * 1024;
+
+// Rebuilt for SDK retries, each attempt sends the whole part again.
+fn part_body(chunk: Bytes, progress: Arc<Mutex<tokio::time::Instant>>) -> ByteStream {
+    ByteStream::new(SdkBody::retryable(move || {
+        let progress = progress.clone();
+        let slices: Vec<_> = (0..chunk.len())
+            .step_by(PART_SLICE_SIZE)
+            .map(|start| chunk.slice(start..chunk.len().min(start + PART_SLICE_SIZE)))
+            .collect();
+        let body = stream::iter(slices).map(move |slice| {
+            *progress.lock().expect("poisoned lock") = tokio::time::Instant::now();
+            Ok::<_, std::io::Error>(slice)
+        });
+
+        SdkBody::from(hyper::Body::wrap_stream(body))
+    }))
+}
+
+#[instrument(skip(aws))]
+pub async fn s3_connect_check(aws: &AWS) -> Result<()> {
+    let result = aws
         .s3_client()
-        .create_multipart_upload()
+        .head_bucket()
         .bucket(aws.bucket().to_owned())
-        .key(storage_id.to_owned())
         .send()
-        .await?;
-    trace!(upload_id = ?start_resp.upload_id, "upload started");
+        .await;
 
-    match send_parts(aws, &mut file, &storage_id, &start_resp.upload_id).await {
-        Ok((parts, size, hash)) => {
-            aws.s3_client()
-                .complete_multipart_upload()
-                .bucket(aws.bucket().to_owned())
-                .key(storage_id.to_owned())
-                .set_upload_id(start_resp.upload_id)
-                .multipart_upload(parts)
-                .send()
-                .await?;
-
-            Ok((StorageId { id: storage_id }, size, hash))
+    // HeadBucket has no error body, status is all there is.
+    match result {
+        Ok(_) => Ok(()),
+        Err(SdkError::ServiceError { raw, .. }) if raw.http().status() == 404 => {
+            Err(CloudError::BucketNotFound(aws.bucket().to_owned()).into())
+        }
+        Err(SdkError::ServiceError { raw, .. }) if raw.http().status() == 403 => {
+            Err(CloudError::AccessDenied(aws.bucket().to_owned()).into())
         }
+        Err(e) => Err(request_error("HeadBucket", e)),
+    }
+}
+
+pub async fn s3_object_exists(aws: &AWS, storage_id: &StorageId) -> Result<bool> {
+    let key = match aws.find_object_key(storage_id) {
+        Some(key) => key,
+        None => return Ok(false),
+    };
+
+    head_object(aws, key)
+        .await
+        .map_err(|e| request_error("HeadObject", e))
+}
+
+// As s3_object_exists, but None if HEAD is denied, as it is to credentials allowed to put
+// objects only. A skewed clock is still an error, the upload would fail the same way.
+async fn s3_object_stored(aws: &AWS, storage_id: &StorageId) -> Result<Option<bool>> {
+    let key = match aws.find_object_key(storage_id) {
+        Some(key) => key,
+        None => return Ok(Some(false)),
+    };
+
+    match head_object(aws, key).await {
+        Ok(exists) => Ok(Some(exists)),
         Err(e) => {
-            trace!(error = %e, "upload failed");
+            let denied =
+                matches!(&e, SdkError::ServiceError { raw, .. } if raw.http().status() == 403);
+            let error = request_error("HeadObject", e);
 
-            if let Err(error) = aws
-                .s3_client()
-                .abort_multipart_upload()
-                .bucket(aws.bucket().to_owned())
-                .key(storage_id.to_owned())
-                .set_upload_id(start_resp.upload_id)
-                .send()
-                .await
-            {
-                error!(%error, "error aborting upload");
+            match error.downcast_ref::<CloudError>() {
+                Some(CloudError::Request { .. }) if denied => Ok(None),
+                _ => Err(error),
             }
-
-            Err(e)
         }
     }
 }
 
-async fn send_parts(
-    aws: &AWS,
-    file: &mut File,
-    storage_id: &String,
-    upload_id: &Option<String>,
-) -> Result<(CompletedMultipartUpload, FileSize, FileHash)> {
-    let mut filesize = 0;
-    let mut hash = ChunkedHash::keyed(&aws.file_hash_key());
-    let mut parts = CompletedMultipartUpload::builder();
+async fn head_object(aws: &AWS, key: String) -> Result<bool, SdkError<HeadObjectError>> {
+    let result = aws
+        .s3_client()
+        .head_object()
+        .set_request_payer(aws.request_payer())
+        .bucket(aws.bucket().to_owned())
+        .key(key)
+        .send()
+        .await;
 
-    for partnum in 1.. {
-        let mut buffer = BytesMut::with_capacity(CHUNK_SIZE);
+    match result {
+        Ok(_) => Ok(true),
+        Err(SdkError::ServiceError { raw, .. }) if raw.http().status() == 404 => Ok(false),
+        Err(e) => Err(e),
+    }
+}
 
-        // Tokio::io reads file in 16KB pieces; collate them before uploading.
-        while buffer.len() < buffer.capacity() {
-            if file.read_buf(&mut buffer).await? == 0 {
-                break;
-            }
+// Pinned SDK can't send If-None-Match with the upload, so this check is not atomic, but still
+// rules out clobbering any object that existed before upload started. Only ids that can be
+// taken already are checked: random ones can't collide, and content hash ones are looked up
+// before deciding to upload at all. Upload goes ahead if the check is denied.
+async fn check_not_stored(aws: &AWS, storage_id: &StorageId) -> Result<()> {
+    match aws.id_strategy() {
+        IdStrategy::Fixed(_) => (),
+        #[cfg(test)]
+        IdStrategy::Seeded(_) => (),
+        IdStrategy::Random | IdStrategy::ContentHash => return Ok(()),
+    }
+
+    match s3_object_stored(aws, storage_id).await? {
+        Some(true) => Err(CloudError::AlreadyExists(aws.object_key(storage_id)?).into()),
+        Some(false) => Ok(()),
+        None => {
+            warn!(
+                storage_id = storage_id.as_str(),
+                "access denied checking for existing object"
+            );
+            Ok(())
         }
+    }
+}
 
-        if buffer.is_empty() {
-            trace!("eof reached");
-            break;
+#[instrument(skip(aws))]
+pub async fn s3_upload_file(aws: &AWS, path: &std::path::Path) -> Result<UploadReceipt> {
+    s3_upload_file_with_events(aws, path, None)
+        .await
+        .map(UploadOutcome::into_receipt)
+}
+
+// Skipped only for content hash ids, when identical content is stored already.
+#[instrument(skip(aws))]
+pub async fn s3_upload_file_outcome(aws: &AWS, path: &std::path::Path) -> Result<UploadOutcome> {
+    s3_upload_file_with_events(aws, path, None).await
+}
+
+// Receiver may be gone, events are only informational.
+fn send_event(events: Option<&EventSender>, event: TransferEvent) {
+    if let Some(events) = events {
+        let _ = events.send(event);
+    }
+}
+
+// Part events as parts are sent, completion is reported by the caller.
+pub async fn s3_upload_file_with_events(
+    aws: &AWS,
+    path: &std::path::Path,
+    events: Option<&EventSender>,
+) -> Result<UploadOutcome> {
+    let mut file = File::open(path)
+        .await
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+    file.set_max_buf_size(READ_SIZE);
+
+    // Compressed size is only known while sending, but plain files can be rejected upfront.
+    if let (Some(limit), None) = (aws.max_upload_size(), aws.compression()) {
+        if file.metadata().await?.len() > limit {
+            return Err(CloudError::SizeLimitExceeded(limit).into());
         }
+    }
 
-        let chunk = buffer.freeze();
+    if let IdStrategy::ContentHash = aws.id_strategy() {
+        return s3_upload_content_addressed(aws, &mut file, events).await;
+    }
 
-        trace!(
-            part = partnum,
-            part_offset = filesize,
-            part_len = chunk.len(),
-            "uploading chunk"
-        );
-        filesize += chunk.len();
-        hash.update(chunk.to_owned());
+    let receipt = if aws.force_single_put() {
+        s3_upload_single_put(aws, new_storage_id(aws)?, &mut file, events).await?
+    } else {
+        s3_upload_with_id(aws, new_storage_id(aws)?, &mut file, events).await?
+    };
 
-        let upload_resp = aws
-            .s3_client()
-            .upload_part()
-            .bucket(aws.bucket().to_owned())
-            .key(storage_id.to_owned())
-            .part_number(partnum)
-            .set_upload_id(upload_id.to_owned())
-            .body(ByteStream::from(chunk))
-            .send()
-            .await?;
+    Ok(UploadOutcome::Uploaded(receipt))
+}
 
-        parts = parts.parts(
-            CompletedPart::builder()
-                .set_e_tag(upload_resp.e_tag)
-                .part_number(partnum)
-                .build(),
-        );
+// Size is discovered while sending parts, the size limit is checked per part.
+pub async fn s3_upload_stream(
+    aws: &AWS,
+    source: &mut (dyn AsyncRead + Unpin + Send),
+) -> Result<UploadReceipt> {
+    if aws.force_single_put() {
+        return Err(anyhow!(
+            "Single PUT uploads need a file source, stream length is unknown"
+        ));
     }
 
-    Ok((
-        parts.build(),
-        FileSize {
-            size: filesize as u64,
-        },
-        FileHash {
-            hash: hex::encode(hash.finalize()),
-        },
-    ))
+    s3_upload_with_id(aws, new_storage_id(aws)?, source, None).await
 }
 
-#[instrument]
-pub async fn s3_download_file(
+fn new_storage_id(aws: &AWS) -> Result<StorageId> {
+    match aws.id_strategy() {
+        IdStrategy::Random => Ok(StorageId::generate()),
+        IdStrategy::Fixed(id) => StorageId::parse(id),
+        #[cfg(test)]
+        IdStrategy::Seeded(seed) => Ok(StorageId::seeded(*seed, aws.next_seeded_index())),
+        IdStrategy::ContentHash => Err(anyhow!(
+            "Content hash ids need a file source, streams can't be read twice"
+        )),
+    }
+}
+
+// Id is the keyed hash of stored (possibly compressed) data, so identical files share an
+// object. Picking the id before upload takes a first pass over the file just for hashing:
+// the file is read, and compressed, twice. Already stored content is not uploaded again.
+async fn s3_upload_content_addressed(
+    aws: &AWS,
+    file: &mut File,
+    events: Option<&EventSender>,
+) -> Result<UploadOutcome> {
+    let (size, hash) = hash_stored_data(aws, file).await?;
+
+    s3_store_content(aws, file, size, &hash, events).await
+}
+
+// Content hash upload of a file already hashed as stored.
+async fn s3_store_content(
+    aws: &AWS,
+    file: &mut File,
+    size: FileSize,
+    hash: &FileHash,
+    events: Option<&EventSender>,
+) -> Result<UploadOutcome> {
+    let storage_id = content_storage_id(hash);
+
+    // Held until the object is stored, so a concurrent upload of the same content finds it.
+    let _in_flight = match aws.dedup_guard() {
+        Some(guard) => Some(guard.lock(&storage_id).await),
+        None => None,
+    };
+
+    // Uploaded if denied, it can't be told from missing.
+    if s3_object_stored(aws, &storage_id).await? == Some(true) {
+        trace!(storage_id = storage_id.as_str(), "content already stored");
+        let receipt = UploadReceipt {
+            object_key: aws.recorded_object_key(&storage_id),
+            ..UploadReceipt::new(storage_id, size, hash.to_owned())
+        };
+        return Ok(UploadOutcome::Skipped(receipt));
+    }
+
+    s3_upload_hashed(aws, storage_id, file, hash, events)
+        .await
+        .map(UploadOutcome::Uploaded)
+}
+
+// Upload of a file hashed in an earlier pass. Object is deleted if the file changed since:
+// under a content hash id it wouldn't match its content, and callers may have acted on the
+// earlier hash.
+async fn s3_upload_hashed(
     aws: &AWS,
     storage_id: StorageId,
-    expected_hash: &FileHash,
-    expected_size: &FileSize,
-    path: &std::path::Path,
-) -> Result<()> {
-    let result = s3_download_file_impl(aws, storage_id, expected_hash, expected_size, path).await;
+    file: &mut File,
+    hash: &FileHash,
+    events: Option<&EventSender>,
+) -> Result<UploadReceipt> {
+    file.rewind().await?;
+    let receipt = if aws.force_single_put() {
+        s3_upload_single_put(aws, storage_id, file, events).await?
+    } else {
+        s3_upload_with_id(aws, storage_id, file, events).await?
+    };
 
-    // Cleanup failed downloads
-    if let Err(e) = &result {
-        trace!(error= ?e, "download failed");
+    if receipt.hash != *hash {
+        s3_delete_object(aws, &receipt.storage_id).await?;
+        s3_delete_part_hashes(aws, &receipt.storage_id, receipt.size.size).await;
+        return Err(anyhow!("File changed during upload"));
+    }
 
-        if let Err(error) = remove_file(path).await {
-            error!(?error, "error deleting partial download");
+    Ok(receipt)
+}
+
+// File hashed by s3_prepare_upload, to be sent by s3_commit_upload.
+#[derive(Debug)]
+pub struct PreparedUpload {
+    path: PathBuf,
+    size: FileSize,
+    hash: FileHash,
+    storage_id: Option<StorageId>,
+}
+
+impl PreparedUpload {
+    // Size and hash of the file as it will be stored.
+    pub fn size(&self) -> &FileSize {
+        &self.size
+    }
+
+    pub fn hash(&self) -> &FileHash {
+        &self.hash
+    }
+
+    // Id the upload will get, known up front for content hash ids only.
+    pub fn storage_id(&self) -> Option<&StorageId> {
+        self.storage_id.as_ref()
+    }
+}
+
+// First phase of a two-phase upload: a full read of the file, compressing it if configured,
+// for the hash alone. Sending it in s3_commit_upload reads and compresses it again, so this
+// costs an extra pass over the file, worth it only when the hash often finds the content
+// already stored. Size limit is checked here, on the size as stored.
+#[instrument(skip(aws))]
+pub async fn s3_prepare_upload(aws: &AWS, path: &std::path::Path) -> Result<PreparedUpload> {
+    let mut file = File::open(path)
+        .await
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+    file.set_max_buf_size(READ_SIZE);
+
+    let (size, hash) = hash_stored_data(aws, &mut file).await?;
+
+    if let Some(limit) = aws.max_upload_size() {
+        if size.size > limit {
+            return Err(CloudError::SizeLimitExceeded(limit).into());
         }
     }
 
-    result
+    let storage_id = match aws.id_strategy() {
+        IdStrategy::ContentHash => Some(content_storage_id(&hash)),
+        _ => None,
+    };
+
+    Ok(PreparedUpload {
+        path: path.to_owned(),
+        size,
+        hash,
+        storage_id,
+    })
 }
 
-async fn s3_download_file_impl(
+// Second phase: sends the prepared file, failing if it no longer matches the prepared hash.
+// Content hash uploads still skip content stored since preparation, reported as Skipped.
+#[instrument(skip(aws, prepared), fields(path = %prepared.path.display()))]
+pub async fn s3_commit_upload(aws: &AWS, prepared: PreparedUpload) -> Result<UploadOutcome> {
+    let mut file = File::open(&prepared.path)
+        .await
+        .with_context(|| format!("Failed to open {}", prepared.path.display()))?;
+    file.set_max_buf_size(READ_SIZE);
+
+    match aws.id_strategy() {
+        IdStrategy::ContentHash => {
+            s3_store_content(aws, &mut file, prepared.size, &prepared.hash, None).await
+        }
+        _ => s3_upload_hashed(aws, new_storage_id(aws)?, &mut file, &prepared.hash, None)
+            .await
+            .map(UploadOutcome::Uploaded),
+    }
+}
+
+// Storage ids must be UUIDs, so they take the first 16 bytes of the hash.
+fn content_storage_id(hash: &FileHash) -> StorageId {
+    let mut bytes = [0; 16];
+    bytes.copy_from_slice(&hash.as_bytes()[..16]);
+
+    StorageId::parse(&Uuid::from_bytes(bytes).hyphenated().to_string())
+        .expect("hyphenated UUID is a valid storage id")
+}
+
+async fn hash_stored_data(aws: &AWS, source: &mut File) -> Result<(FileSize, FileHash)> {
+    let mut reader = stored_data_reader(aws, source);
+    let mut hash = ChunkedHash::with_algo(aws.hash_algo(), aws.hash_key(HashKind::File));
+    let mut size = 0;
+
+    loop {
+        let mut buffer = BytesMut::with_capacity(READ_SIZE);
+        let more = fill_buffer(&mut reader, &mut buffer).await?;
+        size += buffer.len() as u64;
+        hash.update(buffer.freeze());
+
+        if !more {
+            break;
+        }
+    }
+
+    Ok((FileSize { size }, FileHash::from_bytes(hash.finalize())))
+}
+
+// Source as it is stored, compressed if configured.
+fn stored_data_reader<'a>(
+    aws: &AWS,
+    source: &'a mut (dyn AsyncRead + Unpin + Send),
+) -> Box<dyn AsyncRead + Unpin + Send + 'a> {
+    match aws.compression() {
+        Some(Compression::Zstd) => Box::new(ZstdEncoder::new(BufReader::with_capacity(
+            READ_SIZE, source,
+        ))),
+        None => Box::new(source),
+    }
+}
+
+async fn s3_upload_with_id(
     aws: &AWS,
     storage_id: StorageId,
-    expected_hash: &FileHash,
-    expected_size: &FileSize,
-    path: &std::path::Path,
-) -> Result<()> {
-    trace!("downloading file");
-    let mut resp = aws
+    source: &mut (dyn AsyncRead + Unpin + Send),
+    events: Option<&EventSender>,
+) -> Result<UploadReceipt> {
+    let mut reader = stored_data_reader(aws, source);
+
+    s3_upload_stored(aws, storage_id, &mut reader, object_settings(aws), events).await
+}
+
+// Multipart upload of data as it is to be stored. A read error aborts the upload,
+// nothing becomes visible.
+#[instrument(
+    skip(aws, storage_id, reader, settings, events),
+    fields(storage_id = storage_id.as_str(), upload_id, hash)
+)]
+async fn s3_upload_stored(
+    aws: &AWS,
+    storage_id: StorageId,
+    mut reader: &mut (dyn AsyncRead + Unpin + Send),
+    settings: ObjectSettings,
+    events: Option<&EventSender>,
+) -> Result<UploadReceipt> {
+    let start = Instant::now();
+
+    trace!("uploading file");
+
+    check_not_stored(aws, &storage_id).await?;
+
+    let key = aws.new_object_key(&storage_id);
+
+    let start_resp = aws
         .s3_client()
-        .get_object()
+        .create_multipart_upload()
+        .set_request_payer(aws.request_payer())
         .bucket(aws.bucket().to_owned())
-        .key(storage_id.id)
+        .key(key.key().to_owned())
+        .set_metadata(Some(settings.metadata))
+        .set_tagging(settings.tagging)
+        .set_cache_control(settings.cache_control)
+        .set_content_disposition(settings.content_disposition)
+        .set_object_lock_mode(settings.lock_mode)
+        .set_object_lock_retain_until_date(settings.retain_until)
+        .set_object_lock_legal_hold_status(settings.legal_hold)
         .send()
-        .await?;
+        .await
+        .map_err(|e| request_error("CreateMultipartUpload", e))?;
+    Span::current().record(
+        "upload_id",
+        start_resp.upload_id.as_deref().unwrap_or_default(),
+    );
+    trace!("upload started");
 
-    trace!(content_length = resp.content_length, "download started");
+    let result =
+        match send_parts(aws, &mut reader, &storage_id, &start_resp.upload_id, events).await {
+            Ok((parts, size, hash, part_hashes)) => {
+                let stats = TransferStats {
+                    bytes: size.size,
+                    parts: parts.parts().map_or(0, |parts| parts.len()) as u32,
+                    ..Default::default()
+                };
 
-    if resp.content_length() < 0 || resp.content_length() as u64 != expected_size.size {
-        return Err(anyhow!(
-            "File size mismatch: expected {}, got {}",
-            expected_size.size,
-            resp.content_length(),
-        ));
+                complete_upload(aws, &storage_id, &start_resp.upload_id, parts)
+                    .await
+                    .map(|(complete_retries, completed)| {
+                        let stats = TransferStats {
+                            complete_retries,
+                            ..stats
+                        };
+                        (size, hash, part_hashes, stats, completed)
+                    })
+            }
+            Err(e) => Err(e),
+        };
+
+    match result {
+        Ok((size, hash, part_hashes, stats, completed)) => {
+            Span::current().record("hash", hash.to_string().as_str());
+            let object_key = key.commit();
+
+            // Object is complete already, missing part hashes only cost early detection.
+            if has_part_hashes(size.size) {
+                let part_hashes = PartHashes {
+                    key_fingerprint: aws.key_fingerprint(),
+                    tree: part_hashes,
+                };
+
+                if let Err(error) = s3_put_part_hashes(aws, &storage_id, &part_hashes).await {
+                    error!(%error, "error storing part hashes");
+                }
+            }
+
+            aws.report_transfer(
+                TransferKind::Upload,
+                &storage_id,
+                &TransferStats {
+                    duration: start.elapsed(),
+                    ..stats
+                },
+            );
+
+            Ok(UploadReceipt {
+                storage_id,
+                size,
+                hash,
+                etag: completed.e_tag,
+                version_id: completed.version_id,
+                part_count: stats.parts,
+                part_size: CHUNK_SIZE,
+                object_key,
+            })
+        }
+        Err(e) => {
+            trace!(error = %e, "upload failed");
+
+            if let Err(error) = abort_upload(aws, key.key().to_owned(), start_resp.upload_id).await
+            {
+                error!(%error, "error aborting upload");
+            }
+
+            Err(e)
+        }
     }
+}
+
+// Set when an object is created, by either upload kind.
+struct ObjectSettings {
+    metadata: HashMap<String, String>,
+    tagging: Option<String>,
+    cache_control: Option<String>,
+    content_disposition: Option<String>,
+    lock_mode: Option<S3ObjectLockMode>,
+    retain_until: Option<DateTime>,
+    legal_hold: Option<ObjectLockLegalHoldStatus>,
+}
+
+fn object_settings(aws: &AWS) -> ObjectSettings {
+    let mut metadata = HashMap::new();
 
-    let mut hash = ChunkedHash::keyed(&aws.file_hash_key());
-    let mut file = File::create(path).await?;
+    if let Some(compression) = aws.compression() {
+        metadata.insert(
+            COMPRESSION_METADATA.to_owned(),
+            compression_name(compression).to_owned(),
+        );
+    }
 
-    while let Some(mut bytes) = resp.body.try_next().await? {
-        trace!(size = bytes.len(), "received body chunk");
-        hash.update(bytes.clone());
-        file.write_all_buf(&mut bytes).await?;
+    // Absent metadata means the default, so objects from older versions verify unchanged.
+    if aws.hash_algo() != HashAlgo::default() {
+        metadata.insert(
+            HASH_ALGO_METADATA.to_owned(),
+            hash_algo_name(aws.hash_algo()).to_owned(),
+        );
     }
 
-    trace!("eof reached");
-    file.flush().await?;
+    metadata.insert(KEY_FINGERPRINT_METADATA.to_owned(), aws.key_fingerprint());
 
-    let actual_hash = hex::encode(hash.finalize());
+    let lock = aws.object_lock();
+    let retain_until = lock.map(|lock| {
+        let retention = Duration::from_secs(u64::from(lock.retain_days) * 24 * 60 * 60);
+        DateTime::from(SystemTime::now() + retention)
+    });
 
-    if actual_hash != expected_hash.hash {
-        return Err(anyhow!(
-            "File hash mismatch: expected {}, got {}",
-            expected_hash.hash,
-            actual_hash,
-        ));
+    ObjectSettings {
+        metadata,
+        tagging: object_tagging(aws.tags()),
+        cache_control: aws.cache_control().map(str::to_owned),
+        content_disposition: aws.content_disposition().map(str::to_owned),
+        lock_mode: lock.map(|lock| object_lock_mode(lock.mode)),
+        retain_until,
+        legal_hold: lock
+            .filter(|lock| lock.legal_hold)
+            .map(|_| ObjectLockLegalHoldStatus::On),
     }
+}
 
-    Ok(())
+// PutObject limit.
+const SINGLE_PUT_LIMIT: u64 = 5 * 1024 * 1024 * 1024;
+
+// Whole file in one PutObject, body streamed from the file and hashed on the way. Length is
+// sent upfront, config validation rules out compression and Content-MD5 that would change
+// or need it. Body can't be replayed, so the SDK doesn't retry the request, and part timeout
+// doesn't apply: the request is the whole file.
+#[instrument(
+    skip(aws, storage_id, file, events),
+    fields(storage_id = storage_id.as_str(), hash)
+)]
+async fn s3_upload_single_put(
+    aws: &AWS,
+    storage_id: StorageId,
+    file: &mut File,
+    events: Option<&EventSender>,
+) -> Result<UploadReceipt> {
+    let start = Instant::now();
+    let len = file.metadata().await?.len() - file.stream_position().await?;
+
+    if len > SINGLE_PUT_LIMIT {
+        return Err(anyhow!(
+            "Single PUT uploads are limited to {} bytes, file has {}",
+            SINGLE_PUT_LIMIT,
+            len
+        ));
+    }
+
+    trace!(len, "uploading file in single put");
+
+    check_not_stored(aws, &storage_id).await?;
+
+    let key = aws.new_object_key(&storage_id);
+
+    // Body stream must own its source, it shares the file position with `file`.
+    let source = file.try_clone().await?;
+    let hashed = Arc::new(Mutex::new((
+        ChunkedHash::with_algo(aws.hash_algo(), aws.hash_key(HashKind::File)),
+        0,
+    )));
+    let body_hashed = hashed.clone();
+    let chunks = stream::try_unfold(source.take(len), |mut source| async move {
+        let mut buffer = BytesMut::with_capacity(READ_SIZE);
+
+        match source.read_buf(&mut buffer).await? {
+            0 => Ok::<_, std::io::Error>(None),
+            _ => Ok(Some((buffer.freeze(), source))),
+        }
+    })
+    .inspect_ok(move |chunk| {
+        let mut hashed = body_hashed.lock().expect("poisoned lock");
+        hashed.0.update(chunk.clone());
+        hashed.1 += chunk.len() as u64;
+    });
+
+    let settings = object_settings(aws);
+    send_event(events, TransferEvent::PartStarted { part: 1 });
+
+    let stored = aws
+        .s3_client()
+        .put_object()
+        .set_request_payer(aws.request_payer())
+        .bucket(aws.bucket().to_owned())
+        .key(key.key().to_owned())
+        .content_length(len as i64)
+        .body(ByteStream::from(hyper::Body::wrap_stream(chunks)))
+        .set_metadata(Some(settings.metadata))
+        .set_tagging(settings.tagging)
+        .set_cache_control(settings.cache_control)
+        .set_content_disposition(settings.content_disposition)
+        .send()
+        .await
+        .map_err(|e| request_error("PutObject", e))?;
+
+    let (hash, size) = std::mem::take(&mut *hashed.lock().expect("poisoned lock"));
+
+    // File shrinking during upload leaves the body short of content length, failing the
+    // request. Checked anyway, the hash must cover what was stored.
+    if size != len {
+        s3_delete_object(aws, &storage_id).await?;
+        return Err(anyhow!("File changed during upload"));
+    }
+    let object_key = key.commit();
+
+    send_event(
+        events,
+        TransferEvent::PartCompleted {
+            part: 1,
+            bytes: size,
+        },
+    );
+
+    let hash = FileHash::from_bytes(hash.finalize());
+    Span::current().record("hash", hash.to_string().as_str());
+    aws.report_transfer(
+        TransferKind::Upload,
+        &storage_id,
+        &TransferStats {
+            bytes: size,
+            duration: start.elapsed(),
+            parts: 1,
+            complete_retries: 0,
+        },
+    );
+
+    Ok(UploadReceipt {
+        storage_id,
+        size: FileSize { size },
+        hash,
+        etag: stored.e_tag,
+        version_id: stored.version_id,
+        part_count: 1,
+        part_size: size as usize,
+        object_key,
+    })
+}
+
+// Parts are already stored, so a failed complete is worth retrying before aborting the upload.
+// Same part list makes the retry idempotent. Returns number of retries made and
+// the service response.
+const COMPLETE_ATTEMPTS: u32 = 3;
+const COMPLETE_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+async fn complete_upload(
+    aws: &AWS,
+    storage_id: &StorageId,
+    upload_id: &Option<String>,
+    parts: CompletedMultipartUpload,
+) -> Result<(u32, CompleteMultipartUploadOutput)> {
+    let mut attempt = 1;
+
+    loop {
+        let complete = aws
+            .s3_client()
+            .complete_multipart_upload()
+            .set_request_payer(aws.request_payer())
+            .bucket(aws.bucket().to_owned())
+            .key(aws.object_key(storage_id)?)
+            .set_upload_id(upload_id.to_owned())
+            .multipart_upload(parts.clone())
+            .send();
+
+        let error = match timeout(aws.complete_timeout(), complete).await {
+            Ok(Ok(completed)) => return Ok((attempt - 1, completed)),
+            Ok(Err(e)) if is_permanent(&e) => {
+                return Err(request_error("CompleteMultipartUpload", e))
+            }
+            Ok(Err(e)) => request_error("CompleteMultipartUpload", e),
+            // Hung attempt is retried like a failed one.
+            Err(_) => CloudError::Timeout(aws.complete_timeout()).into(),
+        };
+
+        if attempt == COMPLETE_ATTEMPTS {
+            return Err(error);
+        }
+
+        trace!(?error, attempt, "complete failed, retrying");
+        sleep(COMPLETE_RETRY_DELAY * attempt).await;
+        attempt += 1;
+    }
+}
+
+// Called on error paths, which must not hang on a stuck connection: the caller logs the
+// failure and moves on, parts left behind expire by bucket lifecycle rules if there are any.
+async fn abort_upload(aws: &AWS, key: String, upload_id: Option<String>) -> Result<()> {
+    let abort = aws
+        .s3_client()
+        .abort_multipart_upload()
+        .set_request_payer(aws.request_payer())
+        .bucket(aws.bucket().to_owned())
+        .key(key)
+        .set_upload_id(upload_id)
+        .send();
+
+    match timeout(aws.complete_timeout(), abort).await {
+        Ok(result) => result
+            .map(|_| ())
+            .map_err(|e| request_error("AbortMultipartUpload", e)),
+        Err(_) => Err(CloudError::Timeout(aws.complete_timeout()).into()),
+    }
+}
+
+// Client errors (e.g. NoSuchUpload, InvalidPart) won't go away on retry.
+fn is_permanent<E>(e: &SdkError<E>) -> bool {
+    match e {
+        SdkError::ConstructionFailure(_) => true,
+        SdkError::ServiceError { raw, .. } => raw.http().status().is_client_error(),
+        _ => false,
+    }
+}
+
+#[instrument(
+    skip(aws, reader, storage_id, upload_id, events),
+    fields(
+        storage_id = storage_id.as_str(),
+        upload_id = upload_id.as_deref().unwrap_or_default()
+    )
+)]
+async fn send_parts(
+    aws: &AWS,
+    reader: &mut (impl AsyncRead + Unpin),
+    storage_id: &StorageId,
+    upload_id: &Option<String>,
+    events: Option<&EventSender>,
+) -> Result<(CompletedMultipartUpload, FileSize, FileHash, MerkleHash)> {
+    let mut filesize = 0;
+    let mut hash = ChunkedHash::with_algo(aws.hash_algo(), aws.hash_key(HashKind::File));
+    let hasher = MerkleHasher::new(aws.hash_key(HashKind::Chunk), CHUNK_SIZE)?;
+    let mut part_hashes = Vec::new();
+    let mut parts = CompletedMultipartUpload::builder();
+
+    let mut eof = false;
+
+    for partnum in 1.. {
+        if eof {
+            trace!("eof reached");
+            break;
+        }
+
+        let mut buffer = BytesMut::with_capacity(CHUNK_SIZE);
+        eof = !fill_buffer(reader, &mut buffer).await?;
+
+        if buffer.is_empty() {
+            trace!("eof reached");
+            break;
+        }
+
+        let chunk = buffer.freeze();
+
+        // File may grow after the upfront check, and compressed size isn't known before.
+        if let Some(limit) = aws.max_upload_size() {
+            if (filesize + chunk.len()) as u64 > limit {
+                return Err(CloudError::SizeLimitExceeded(limit).into());
+            }
+        }
+
+        trace!(
+            part = partnum,
+            part_offset = filesize,
+            part_len = chunk.len(),
+            "uploading chunk"
+        );
+        filesize += chunk.len();
+        hash.update(chunk.to_owned());
+
+        part_hashes.push(hasher.hash_block(partnum as u64 - 1, chunk.to_owned()));
+
+        let part = partnum as u32;
+        let bytes = chunk.len() as u64;
+        send_event(events, TransferEvent::PartStarted { part });
+
+        let mut request = aws
+            .s3_client()
+            .upload_part()
+            .set_request_payer(aws.request_payer())
+            .bucket(aws.bucket().to_owned())
+            .key(aws.object_key(storage_id)?)
+            .part_number(partnum)
+            .set_upload_id(upload_id.to_owned());
+
+        if let Some(S3Checksum::Md5) = aws.s3_checksum() {
+            request = request.content_md5(BASE64_STANDARD.encode(Md5::digest(&chunk)));
+        }
+
+        let progress = Arc::new(Mutex::new(tokio::time::Instant::now()));
+        let request = request
+            .content_length(bytes as i64)
+            .body(part_body(chunk, progress.clone()))
+            .send();
+        let upload_resp = with_idle_timeout(aws, &progress, request)
+            .await?
+            .map_err(|e| request_error("UploadPart", e))?;
+        send_event(events, TransferEvent::PartCompleted { part, bytes });
+
+        parts = parts.parts(
+            CompletedPart::builder()
+                .set_e_tag(upload_resp.e_tag)
+                .part_number(partnum)
+                .build(),
+        );
+    }
+
+    Ok((
+        parts.build(),
+        FileSize {
+            size: filesize as u64,
+        },
+        FileHash::from_bytes(hash.finalize()),
+        hasher.tree(filesize as u64, part_hashes),
+    ))
+}
+
+// Per-part hashes of multipart uploads, stored next to the object: metadata is fixed when the
+// upload starts, before any part is hashed. Parts are the blocks of a Merkle tree under the chunk
+// key. Downloads check them to fail on the first bad part rather than at the end, the whole-file
+// hash is still checked as before.
+const PART_HASHES_SUFFIX: &str = ".parts";
+
+#[derive(Debug, Serialize, Deserialize)]
+struct PartHashes {
+    // Of the master key the hashes are under.
+    key_fingerprint: String,
+    #[serde(flatten)]
+    tree: MerkleHash,
+}
+
+// Single part objects have nothing to gain and are stored without part hashes.
+fn has_part_hashes(size: u64) -> bool {
+    size > CHUNK_SIZE as u64
+}
+
+async fn part_hashes_key(aws: &AWS, storage_id: &StorageId) -> Result<String> {
+    Ok(format!(
+        "{}{}",
+        aws.object_key(storage_id)?,
+        PART_HASHES_SUFFIX
+    ))
+}
+
+async fn s3_put_part_hashes(
+    aws: &AWS,
+    storage_id: &StorageId,
+    part_hashes: &PartHashes,
+) -> Result<()> {
+    aws.s3_client()
+        .put_object()
+        .set_request_payer(aws.request_payer())
+        .bucket(aws.bucket().to_owned())
+        .key(part_hashes_key(aws, storage_id).await?)
+        .body(ByteStream::from(serde_json::to_vec(part_hashes)?))
+        .send()
+        .await
+        .map_err(|e| request_error("PutObject", e))?;
+
+    Ok(())
+}
+
+// None for objects without part hashes. Unreadable ones are ignored the same way,
+// the whole-file hash still catches corruption.
+async fn s3_part_hashes(aws: &AWS, storage_id: &StorageId) -> Result<Option<PartHashes>> {
+    let result = aws
+        .s3_client()
+        .get_object()
+        .set_request_payer(aws.request_payer())
+        .bucket(aws.bucket().to_owned())
+        .key(part_hashes_key(aws, storage_id).await?)
+        .send()
+        .await;
+
+    let resp = match result {
+        Ok(resp) => resp,
+        Err(SdkError::ServiceError { raw, .. }) if raw.http().status() == 404 => return Ok(None),
+        Err(e) => return Err(request_error("GetObject", e)),
+    };
+    let body = resp.body.collect().await?.into_bytes();
+
+    match serde_json::from_slice(&body) {
+        Ok(part_hashes) => Ok(Some(part_hashes)),
+        Err(error) => {
+            error!(%error, "ignoring malformed part hashes");
+            Ok(None)
+        }
+    }
+}
+
+// Object is gone already, a leftover only takes space.
+async fn s3_delete_part_hashes(aws: &AWS, storage_id: &StorageId, size: u64) {
+    if !has_part_hashes(size) {
+        return;
+    }
+
+    let result = match part_hashes_key(aws, storage_id).await {
+        Ok(key) => aws
+            .s3_client()
+            .delete_object()
+            .set_request_payer(aws.request_payer())
+            .bucket(aws.bucket().to_owned())
+            .key(key)
+            .send()
+            .await
+            .map(|_| ())
+            .map_err(|e| request_error("DeleteObject", e)),
+        Err(e) => Err(e),
+    };
+
+    if let Err(error) = result {
+        error!(%error, "error deleting part hashes");
+    }
+}
+
+// Checks stored data against part hashes as it arrives, starting at `offset` into the object.
+// A part entered midway on resume can't be checked, the whole-file hash still covers it.
+struct PartCheck<'a> {
+    hasher: MerkleHasher,
+    part_hashes: &'a MerkleHash,
+    offset: u64,
+    hash: Option<ChunkedHash>,
+}
+
+impl<'a> PartCheck<'a> {
+    // None when the hashes are under a master key no longer configured, or don't add up to
+    // their root.
+    fn new(aws: &AWS, part_hashes: &'a PartHashes, offset: u64) -> Option<PartCheck<'a>> {
+        let key = aws.recorded_hash_key(HashKind::Chunk, &part_hashes.key_fingerprint)?;
+        let part_hashes = &part_hashes.tree;
+        let hasher = MerkleHasher::new(key, usize::try_from(part_hashes.block_size).ok()?).ok()?;
+
+        if !hasher.verify_root(part_hashes) {
+            error!("ignoring part hashes not matching their root");
+            return None;
+        }
+
+        let hash = offset
+            .is_multiple_of(part_hashes.block_size)
+            .then(|| hasher.block_hash(offset / part_hashes.block_size));
+
+        Some(PartCheck {
+            hasher,
+            part_hashes,
+            offset,
+            hash,
+        })
+    }
+
+    fn update(&mut self, mut data: &[u8]) -> Result<()> {
+        let part_size = self.part_hashes.block_size;
+
+        while !data.is_empty() {
+            let part_end = (self.offset / part_size + 1) * part_size;
+            let len = (data.len() as u64).min(part_end - self.offset) as usize;
+
+            if let Some(hash) = &mut self.hash {
+                hash.update(&data[..len]);
+            }
+
+            self.offset += len as u64;
+            data = &data[len..];
+
+            if self.offset == part_end {
+                self.check_part()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // At the end of the object, the last part is usually shorter.
+    fn finish(&mut self) -> Result<()> {
+        if !self.offset.is_multiple_of(self.part_hashes.block_size) {
+            self.check_part()?;
+        }
+
+        Ok(())
+    }
+
+    fn check_part(&mut self) -> Result<()> {
+        let part = (self.offset - 1) / self.part_hashes.block_size;
+        let next = Some(self.hasher.block_hash(part + 1));
+
+        if let Some(hash) = std::mem::replace(&mut self.hash, next) {
+            let matches = self
+                .part_hashes
+                .blocks
+                .get(part as usize)
+                .is_some_and(|expected| hashes_equal(&hash.finalize(), expected));
+
+            if !matches {
+                return Err(CloudError::PartCorrupted {
+                    part: part as u32 + 1,
+                    offset: part * self.part_hashes.block_size,
+                }
+                .into());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Attempts for downloads interrupted mid-stream. The SDK retries failed requests on its own,
+// but an error while streaming the body would otherwise throw away everything received.
+const DOWNLOAD_ATTEMPTS: u32 = 3;
+const PREFIX_READ_SIZE: usize = 1024 * 1024;
+// Same as upload part size, each range is a separate request.
+const PARALLEL_RANGE_SIZE: u64 = CHUNK_SIZE as u64;
+const PARALLEL_DOWNLOADS: usize = 4;
+
+enum DownloadError {
+    // Body stream was interrupted; partial file is flushed and the download can resume.
+    Interrupted(anyhow::Error),
+    Failed(anyhow::Error),
+}
+
+fn failed(e: impl Into<anyhow::Error>) -> DownloadError {
+    DownloadError::Failed(e.into())
+}
+
+#[instrument(
+    skip(aws, storage_id, expected_hash, expected_size),
+    fields(storage_id = storage_id.as_str(), hash)
+)]
+pub async fn s3_download_file(
+    aws: &AWS,
+    storage_id: StorageId,
+    expected_hash: &FileHash,
+    expected_size: &FileSize,
+    path: &std::path::Path,
+    overwrite: OverwritePolicy,
+) -> Result<()> {
+    let start = Instant::now();
+
+    let target = DownloadTarget::prepare(path, overwrite).await?;
+    let path = target.path();
+
+    if !aws.skip_space_check() {
+        check_free_space(path, expected_size.size)?;
+    }
+
+    let part_hashes = if has_part_hashes(expected_size.size) {
+        s3_part_hashes(aws, &storage_id).await?
+    } else {
+        None
+    };
+
+    if aws.parallel_download() {
+        let result = s3_download_file_parallel(
+            aws,
+            &storage_id,
+            expected_hash,
+            expected_size,
+            part_hashes.as_ref(),
+            path,
+            PARALLEL_RANGE_SIZE,
+        )
+        .await;
+
+        match result {
+            Ok(Some(parts)) => {
+                aws.report_transfer(
+                    TransferKind::Download,
+                    &storage_id,
+                    &TransferStats {
+                        bytes: expected_size.size,
+                        duration: start.elapsed(),
+                        parts,
+                        complete_retries: 0,
+                    },
+                );
+
+                return target.commit().await;
+            }
+            // Compressed object, ranges of it can't be decompressed separately.
+            Ok(None) => trace!("falling back to sequential download"),
+            Err(e) => {
+                trace!(error = ?e, "download failed");
+
+                if let Err(error) = remove_file(path).await {
+                    error!(?error, "error deleting partial download");
+                }
+
+                return Err(e);
+            }
+        }
+    }
+
+    let mut resume = ResumeState::default();
+    let mut attempt = 1;
+
+    loop {
+        let result = s3_download_file_impl(
+            aws,
+            &storage_id,
+            expected_hash,
+            expected_size,
+            part_hashes.as_ref(),
+            DownloadSink::File(path),
+            &mut resume,
+        )
+        .await
+        .and_then(|result| {
+            mismatch_error(aws, &storage_id, resume.hashing.as_ref(), result).map_err(failed)
+        });
+
+        let e = match result {
+            Ok(()) => {
+                aws.report_transfer(
+                    TransferKind::Download,
+                    &storage_id,
+                    &TransferStats {
+                        bytes: expected_size.size,
+                        duration: start.elapsed(),
+                        parts: attempt,
+                        complete_retries: 0,
+                    },
+                );
+
+                return target.commit().await;
+            }
+            Err(DownloadError::Interrupted(e)) if attempt < DOWNLOAD_ATTEMPTS => {
+                trace!(
+                    error = ?e,
+                    attempt,
+                    offset = resume.offset,
+                    "download interrupted, resuming"
+                );
+                attempt += 1;
+                continue;
+            }
+            Err(DownloadError::Interrupted(e)) | Err(DownloadError::Failed(e)) => e,
+        };
+
+        // Cleanup failed downloads
+        trace!(error= ?e, "download failed");
+
+        if let Err(error) = remove_file(path).await {
+            error!(?error, "error deleting partial download");
+        }
+
+        return Err(e);
+    }
+}
+
+// Kept between attempts of a sequential download.
+#[derive(Default)]
+struct ResumeState {
+    // Bytes already present in the file, advanced as data is written.
+    offset: u64,
+    // From the first response, for rehashing the prefix.
+    hashing: Option<ObjectHashing>,
+    // From the first response, resumed requests only match the same object.
+    etag: Option<String>,
+}
+
+// Where a sequential download puts received data. Verification only hashes it, so an
+// interrupted attempt has no prefix to rehash and starts over.
+#[derive(Clone, Copy)]
+enum DownloadSink<'a> {
+    File(&'a std::path::Path),
+    Null,
+}
+
+// Size and hash mismatches are reported as verification would, downloads turn them into errors.
+fn mismatch_error(
+    aws: &AWS,
+    storage_id: &StorageId,
+    hashing: Option<&ObjectHashing>,
+    result: VerifyResult,
+) -> Result<()> {
+    match result {
+        VerifyResult::Ok => Ok(()),
+        VerifyResult::SizeMismatch { expected, actual } => Err(anyhow!(
+            "File size mismatch: expected {}, got {}",
+            expected.size,
+            actual.size,
+        )),
+        VerifyResult::HashMismatch { expected, actual } => Err(hash_mismatch_error(
+            aws,
+            storage_id,
+            hashing.cloned().unwrap_or_default(),
+            &expected,
+            &actual,
+        )),
+        VerifyResult::Missing => {
+            Err(CloudError::ObjectNotFound(storage_id.as_str().to_owned()).into())
+        }
+    }
+}
+
+// Keyed hash of data under the wrong key looks like corruption. An object recorded under a key
+// not configured is reported as such instead, objects without a fingerprint can't be told.
+fn hash_mismatch_error(
+    aws: &AWS,
+    storage_id: &StorageId,
+    hashing: ObjectHashing,
+    expected: &FileHash,
+    actual: &FileHash,
+) -> anyhow::Error {
+    match hashing.key_fingerprint {
+        Some(fingerprint)
+            if hashing.hash_algo == HashAlgo::Blake2b
+                && aws
+                    .recorded_hash_key(HashKind::File, &fingerprint)
+                    .is_none() =>
+        {
+            CloudError::WrongKey {
+                storage_id: storage_id.to_string(),
+                fingerprint,
+            }
+            .into()
+        }
+        _ => anyhow!("File hash mismatch: expected {}, got {}", expected, actual,),
+    }
+}
+
+async fn open_download_file(path: &std::path::Path, offset: u64) -> Result<File> {
+    if offset == 0 {
+        trace!("downloading file");
+        return File::create(path)
+            .await
+            .with_context(|| format!("Failed to create {}", path.display()));
+    }
+
+    trace!(offset, "resuming download");
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .await
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+
+    file.set_len(offset).await?;
+
+    Ok(file)
+}
+
+// Download the object starting at `resume.offset`, so that a retry can continue from
+// wherever the previous attempt stopped.
+async fn s3_download_file_impl(
+    aws: &AWS,
+    storage_id: &StorageId,
+    expected_hash: &FileHash,
+    expected_size: &FileSize,
+    part_hashes: Option<&PartHashes>,
+    sink: DownloadSink<'_>,
+    resume: &mut ResumeState,
+) -> Result<VerifyResult, DownloadError> {
+    let mut file = match sink {
+        DownloadSink::File(path) => Some(
+            open_download_file(path, resume.offset)
+                .await
+                .map_err(failed)?,
+        ),
+        DownloadSink::Null => None,
+    };
+
+    let resp = if resume.offset < expected_size.size {
+        let key = aws.object_key(storage_id).map_err(failed)?;
+        let mut request = aws
+            .s3_client()
+            .get_object()
+            .set_request_payer(aws.request_payer())
+            .bucket(aws.bucket().to_owned())
+            .key(key.to_owned());
+
+        if resume.offset > 0 {
+            request = request
+                .range(format!("bytes={}-", resume.offset))
+                .set_if_match(resume.etag.clone());
+        }
+
+        // Restarting wouldn't help, the new object can't match the expected hash.
+        let resp = match request.send().await {
+            Ok(resp) => resp,
+            Err(SdkError::ServiceError { raw, .. }) if raw.http().status() == 412 => {
+                return Err(failed(CloudError::ObjectChanged(key)));
+            }
+            Err(SdkError::ServiceError { raw, .. }) if raw.http().status() == 404 => {
+                return Err(failed(CloudError::ObjectNotFound(key)));
+            }
+            Err(e) => return Err(failed(request_error("GetObject", e))),
+        };
+
+        trace!(content_length = resp.content_length, "download started");
+
+        if resp.content_length() < 0 {
+            return Err(failed(anyhow!(
+                "Invalid content length {}",
+                resp.content_length()
+            )));
+        }
+
+        if resp.content_length() as u64 != expected_size.size - resume.offset {
+            return Ok(VerifyResult::SizeMismatch {
+                expected: *expected_size,
+                actual: FileSize {
+                    size: resume.offset + resp.content_length() as u64,
+                },
+            });
+        }
+
+        resume.hashing = Some(object_hashing(resp.metadata()).map_err(failed)?);
+
+        if resume.offset == 0 {
+            resume.etag = resp.e_tag().map(str::to_owned);
+        }
+
+        Some(resp)
+    } else {
+        None
+    };
+
+    let mut hash = ObjectHash::new(aws, &resume.hashing.clone().unwrap_or_default());
+
+    match &mut file {
+        // Hash state can't be saved, so recompute it over the kept prefix.
+        // Reading the prefix leaves the file positioned for appending.
+        Some(file) if resume.offset > 0 => hash_file_prefix(file, &mut hash, resume.offset)
+            .await
+            .map_err(failed)?,
+        _ => (),
+    }
+
+    if let Some(resp) = resp {
+        let mut part_check =
+            part_hashes.and_then(|part_hashes| PartCheck::new(aws, part_hashes, resume.offset));
+        let compression = object_compression(resp.metadata()).map_err(failed)?;
+
+        // Decompressed size isn't known, compressed output grows as written.
+        if let (Some(file), None) = (&file, compression) {
+            preallocate(file, expected_size.size)
+                .await
+                .map_err(|e| failed(write_error(e, resume.offset)))?;
+        }
+
+        // Decompressor state can't be restored,
+        // so interrupted compressed downloads restart from scratch.
+        let resumable = file.is_some() && compression.is_none();
+        let mut writer: Box<dyn AsyncWrite + Unpin + Send> = match (file, compression) {
+            (Some(file), Some(Compression::Zstd)) => Box::new(ZstdDecoder::new(file)),
+            (Some(file), None) => Box::new(file),
+            // Hash covers stored data, there is no need to decompress it.
+            (None, _) => Box::new(tokio::io::sink()),
+        };
+        // Counted independently of content length, which a proxy may drop or misreport.
+        let mut received = resume.offset;
+        let mut body = ReadAhead::new(aws, resp.body);
+
+        loop {
+            match body.next().await {
+                Ok(Some(mut bytes)) => {
+                    trace!(size = bytes.len(), "received body chunk");
+                    let len = bytes.len();
+                    received += len as u64;
+                    hash.update(bytes.clone());
+
+                    if let Some(part_check) = &mut part_check {
+                        part_check.update(&bytes).map_err(failed)?;
+                    }
+
+                    writer
+                        .write_all_buf(&mut bytes)
+                        .await
+                        .map_err(|e| failed(write_error(e, received - bytes.len() as u64)))?;
+
+                    if resumable {
+                        resume.offset += len as u64;
+                    }
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    writer
+                        .flush()
+                        .await
+                        .map_err(|e| failed(write_error(e, received)))?;
+                    return Err(DownloadError::Interrupted(e));
+                }
+            }
+        }
+
+        trace!(received, "eof reached");
+        // Finishes decompression and flushes file.
+        writer
+            .shutdown()
+            .await
+            .map_err(|e| failed(write_error(e, received)))?;
+
+        if received < expected_size.size {
+            return Err(failed(CloudError::Truncated {
+                expected: expected_size.size,
+                actual: received,
+            }));
+        }
+
+        if let Some(part_check) = &mut part_check {
+            part_check.finish().map_err(failed)?;
+        }
+    }
+
+    let actual_hash = hash.finalize(expected_hash);
+    Span::current().record("hash", actual_hash.to_string().as_str());
+
+    if actual_hash != *expected_hash {
+        return Ok(VerifyResult::HashMismatch {
+            expected: expected_hash.to_owned(),
+            actual: actual_hash,
+        });
+    }
+
+    Ok(VerifyResult::Ok)
+}
+
+// Body chunks fetched by a separate task, up to `download_readahead` ahead of the consumer.
+// Task is stopped when this is dropped.
+struct ReadAhead {
+    receiver: mpsc::Receiver<Result<Option<Bytes>>>,
+    _task: AbortOnDropHandle<()>,
+}
+
+impl ReadAhead {
+    fn new(aws: &AWS, mut body: ByteStream) -> ReadAhead {
+        let (sender, receiver) = mpsc::channel(aws.download_readahead());
+        let part_timeout = aws.part_timeout();
+
+        let task = tokio::spawn(async move {
+            loop {
+                let next = timeout(part_timeout, body.try_next())
+                    .await
+                    .map_err(|_| CloudError::Timeout(part_timeout).into())
+                    .and_then(|next| Ok(next?));
+                let done = !matches!(next, Ok(Some(_)));
+
+                if sender.send(next).await.is_err() || done {
+                    break;
+                }
+            }
+        });
+
+        ReadAhead {
+            receiver,
+            _task: AbortOnDropHandle::new(task),
+        }
+    }
+
+    async fn next(&mut self) -> Result<Option<Bytes>> {
+        match self.receiver.recv().await {
+            Some(next) => next,
+            None => Err(anyhow!("Body reader stopped")),
+        }
+    }
+}
+
+// Fail before transfer rather than near its end. Compressed objects are checked against
+// stored size, decompressed output needs more.
+fn check_free_space(path: &std::path::Path, needed: u64) -> Result<()> {
+    let dir = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => std::path::Path::new("."),
+    };
+    let available = fs2::available_space(dir)?;
+
+    if available < needed {
+        return Err(CloudError::InsufficientSpace { needed, available }.into());
+    }
+
+    Ok(())
+}
+
+// Reserves space for the whole file up front, so a full disk fails before data is fetched and
+// large files get fewer fragments. Without fallocate, on other systems or filesystems lacking
+// it, only the length is set and the file stays sparse. Not posix_fallocate: where the
+// filesystem can't reserve space it writes every block instead. Data already in the file is kept.
+async fn preallocate(file: &File, len: u64) -> std::io::Result<()> {
+    #[cfg(target_os = "linux")]
+    if len > 0 {
+        use std::os::fd::AsRawFd;
+
+        // Task owns its handle, it keeps running if the download is cancelled.
+        let handle = file.try_clone().await?.into_std().await;
+        let result = tokio::task::spawn_blocking(move || {
+            match unsafe { libc::fallocate(handle.as_raw_fd(), 0, 0, len as libc::off_t) } {
+                0 => Ok(()),
+                _ => Err(std::io::Error::last_os_error()),
+            }
+        })
+        .await?;
+
+        match result {
+            Ok(()) => (),
+            Err(e) if matches!(e.raw_os_error(), Some(libc::EOPNOTSUPP | libc::ENOSYS)) => {
+                trace!(error = %e, "fallocate not supported, file stays sparse")
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    file.set_len(len).await
+}
+
+// Ranges of `range_size` are fetched concurrently into a file preallocated to the object size,
+// then the file is read back for the hash check. Returns the number of ranges, None for
+// compressed objects, which have to be streamed through the decompressor instead.
+async fn s3_download_file_parallel(
+    aws: &AWS,
+    storage_id: &StorageId,
+    expected_hash: &FileHash,
+    expected_size: &FileSize,
+    part_hashes: Option<&PartHashes>,
+    path: &std::path::Path,
+    range_size: u64,
+) -> Result<Option<u32>> {
+    let head = aws
+        .s3_client()
+        .head_object()
+        .set_request_payer(aws.request_payer())
+        .bucket(aws.bucket().to_owned())
+        .key(aws.object_key(storage_id)?)
+        .send()
+        .await
+        .map_err(|e| request_error("HeadObject", e))?;
+
+    if object_compression(head.metadata())?.is_some() {
+        return Ok(None);
+    }
+
+    if head.content_length() < 0 || head.content_length() as u64 != expected_size.size {
+        return Err(anyhow!(
+            "File size mismatch: expected {}, got {}",
+            expected_size.size,
+            head.content_length(),
+        ));
+    }
+
+    let hashing = object_hashing(head.metadata())?;
+
+    trace!("downloading file in ranges");
+    let file = File::create(path)
+        .await
+        .with_context(|| format!("Failed to create {}", path.display()))?;
+    preallocate(&file, expected_size.size)
+        .await
+        .map_err(|e| write_error(e, 0))?;
+    drop(file);
+
+    let ranges = download_ranges(expected_size.size, range_size);
+    let parts = ranges.len() as u32;
+    // Across all ranges, for reporting full disk. Refetched ranges are counted again.
+    let written = AtomicU64::new(0);
+    // Each range is then one part, checked on its own.
+    let part_hashes = part_hashes.filter(|part_hashes| part_hashes.tree.block_size == range_size);
+
+    let downloads = ranges
+        .into_iter()
+        .map(|(start, end)| {
+            s3_download_range_checked(aws, storage_id, path, start, end, part_hashes, &written)
+        })
+        .collect();
+    try_join_buffered(downloads, PARALLEL_DOWNLOADS).await?;
+
+    verify_file_hash(aws, storage_id, path, hashing, expected_hash, expected_size).await?;
+
+    Ok(Some(parts))
+}
+
+// Inclusive byte ranges covering `size` bytes, as used in Range header.
+fn download_ranges(size: u64, range_size: u64) -> Vec<(u64, u64)> {
+    (0..size)
+        .step_by(range_size as usize)
+        .map(|start| (start, (start + range_size).min(size) - 1))
+        .collect()
+}
+
+// Range interrupted mid-stream, cut short or failing its part hash is fetched again from its
+// start, rather than failing the whole download. Other ranges are kept.
+async fn s3_download_range_checked(
+    aws: &AWS,
+    storage_id: &StorageId,
+    path: &std::path::Path,
+    start: u64,
+    end: u64,
+    part_hashes: Option<&PartHashes>,
+    written: &AtomicU64,
+) -> Result<()> {
+    let mut attempt = 1;
+
+    loop {
+        match s3_download_range(aws, storage_id, path, start, end, part_hashes, written).await {
+            Ok(()) => return Ok(()),
+            Err(DownloadError::Interrupted(e)) if attempt < DOWNLOAD_ATTEMPTS => {
+                error!(error = %e, attempt, start, "refetching range");
+                attempt += 1;
+            }
+            Err(DownloadError::Interrupted(e)) | Err(DownloadError::Failed(e)) => return Err(e),
+        }
+    }
+}
+
+async fn s3_download_range(
+    aws: &AWS,
+    storage_id: &StorageId,
+    path: &std::path::Path,
+    start: u64,
+    end: u64,
+    part_hashes: Option<&PartHashes>,
+    written: &AtomicU64,
+) -> Result<(), DownloadError> {
+    trace!(start, end, "downloading range");
+    let mut resp = aws
+        .s3_client()
+        .get_object()
+        .set_request_payer(aws.request_payer())
+        .bucket(aws.bucket().to_owned())
+        .key(aws.object_key(storage_id).map_err(failed)?)
+        .range(format!("bytes={}-{}", start, end))
+        .send()
+        .await
+        .map_err(|e| failed(request_error("GetObject", e)))?;
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .open(path)
+        .await
+        .map_err(failed)?;
+    file.seek(SeekFrom::Start(start)).await.map_err(failed)?;
+    let mut received = 0;
+    let mut part_check =
+        part_hashes.and_then(|part_hashes| PartCheck::new(aws, part_hashes, start));
+
+    // Body errors, short bodies and corrupted data only affect this attempt, the range is
+    // fetched again from its start.
+    loop {
+        let mut bytes = match with_part_timeout(aws, resp.body.try_next()).await {
+            Ok(Ok(Some(bytes))) => bytes,
+            Ok(Ok(None)) => break,
+            Ok(Err(e)) => return Err(DownloadError::Interrupted(e.into())),
+            Err(e) => return Err(DownloadError::Interrupted(e)),
+        };
+        received += bytes.len() as u64;
+
+        if start + received > end + 1 {
+            return Err(failed(anyhow!(
+                "Range {}-{} is longer than requested",
+                start,
+                end
+            )));
+        }
+
+        if let Some(part_check) = &mut part_check {
+            part_check
+                .update(&bytes)
+                .map_err(DownloadError::Interrupted)?;
+        }
+
+        // Buffer is advanced past whatever was written, even on error.
+        let len = bytes.len() as u64;
+        let result = file.write_all_buf(&mut bytes).await;
+        let saved = len - bytes.len() as u64;
+        let total = written.fetch_add(saved, Ordering::SeqCst) + saved;
+        result.map_err(|e| failed(write_error(e, total)))?;
+    }
+
+    file.flush()
+        .await
+        .map_err(|e| failed(write_error(e, written.load(Ordering::SeqCst))))?;
+
+    if start + received <= end {
+        return Err(DownloadError::Interrupted(
+            CloudError::Truncated {
+                expected: end + 1 - start,
+                actual: received,
+            }
+            .into(),
+        ));
+    }
+
+    if let Some(part_check) = &mut part_check {
+        part_check.finish().map_err(DownloadError::Interrupted)?;
+    }
+
+    Ok(())
+}
+
+// Part of stored object as is. Offsets into compressed data are meaningless to callers,
+// compressed objects fail before anything is written.
+#[instrument(skip(aws, storage_id), fields(storage_id = storage_id.as_str()))]
+pub async fn s3_download_part(
+    aws: &AWS,
+    storage_id: &StorageId,
+    offset: u64,
+    len: u64,
+    path: &std::path::Path,
+    overwrite: OverwritePolicy,
+) -> Result<()> {
+    let target = DownloadTarget::prepare(path, overwrite).await?;
+    let path = target.path();
+
+    if len == 0 {
+        File::create(path)
+            .await
+            .with_context(|| format!("Failed to create {}", path.display()))?;
+
+        return target.commit().await;
+    }
+
+    let mut resp = aws
+        .s3_client()
+        .get_object()
+        .set_request_payer(aws.request_payer())
+        .bucket(aws.bucket().to_owned())
+        .key(aws.object_key(storage_id)?)
+        .range(format!("bytes={}-{}", offset, offset + len - 1))
+        .send()
+        .await
+        .map_err(|e| request_error("GetObject", e))?;
+
+    if object_compression(resp.metadata())?.is_some() {
+        return Err(anyhow!("Ranged download of compressed object"));
+    }
+
+    let mut file = File::create(path)
+        .await
+        .with_context(|| format!("Failed to create {}", path.display()))?;
+    let mut received = 0;
+
+    while let Some(bytes) = with_part_timeout(aws, resp.body.try_next()).await?? {
+        if received + bytes.len() as u64 > len {
+            return Err(anyhow!("Range at {} is longer than requested", offset));
+        }
+
+        file.write_all(&bytes)
+            .await
+            .map_err(|e| write_error(e, received))?;
+        received += bytes.len() as u64;
+    }
+
+    file.flush().await.map_err(|e| write_error(e, received))?;
+
+    if received < len {
+        return Err(CloudError::Truncated {
+            expected: len,
+            actual: received,
+        }
+        .into());
+    }
+
+    target.commit().await
+}
+
+// Separate pass over completed file, ranges arrive out of order so they can't be hashed
+// as they are received.
+async fn verify_file_hash(
+    aws: &AWS,
+    storage_id: &StorageId,
+    path: &std::path::Path,
+    hashing: ObjectHashing,
+    expected_hash: &FileHash,
+    expected_size: &FileSize,
+) -> Result<()> {
+    let mut file = File::open(path)
+        .await
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+    let mut hash = ObjectHash::new(aws, &hashing);
+    hash_file_prefix(&mut file, &mut hash, expected_size.size).await?;
+
+    let actual_hash = hash.finalize(expected_hash);
+    Span::current().record("hash", actual_hash.to_string().as_str());
+
+    if actual_hash != *expected_hash {
+        return Err(hash_mismatch_error(
+            aws,
+            storage_id,
+            hashing,
+            expected_hash,
+            &actual_hash,
+        ));
+    }
+
+    Ok(())
+}
+
+async fn hash_file_prefix(file: &mut File, hash: &mut ObjectHash, len: u64) -> Result<()> {
+    let mut reader = (&mut *file).take(len);
+    let mut hashed = 0;
+    let mut buffer = BytesMut::with_capacity(PREFIX_READ_SIZE);
+
+    loop {
+        buffer.reserve(PREFIX_READ_SIZE);
+
+        let size = reader.read_buf(&mut buffer).await?;
+        if size == 0 {
+            break;
+        }
+
+        hashed += size as u64;
+        hash.update(buffer.split().freeze());
+    }
+
+    if hashed != len {
+        return Err(anyhow!(
+            "Partial download is shorter than expected: expected {}, got {}",
+            len,
+            hashed,
+        ));
+    }
+
+    Ok(())
+}
+
+#[instrument(skip(aws, manifest))]
+pub async fn s3_verify_all(
+    aws: &AWS,
+    manifest: &[(StorageId, FileHash, FileSize)],
+    concurrency: usize,
+) -> Result<Vec<(StorageId, Result<VerifyResult>)>> {
+    if concurrency == 0 {
+        return Err(anyhow!("Verification concurrency must be positive"));
+    }
+
+    trace!(files = manifest.len(), "verifying files");
+
+    let checks = manifest
+        .iter()
+        .map(|(storage_id, expected_hash, expected_size)| async move {
+            let result = s3_verify_file(aws, storage_id, expected_hash, expected_size).await;
+            Ok((storage_id.to_owned(), result))
+        })
+        .collect();
+
+    try_join_buffered(checks, concurrency).await
+}
+
+// Same as download, but received data is only hashed. Part hashes aren't checked, the whole
+// object is read anyway. A body ending short counts as a size mismatch.
+#[instrument(
+    skip(aws, storage_id, expected_hash, expected_size),
+    fields(storage_id = storage_id.as_str(), hash)
+)]
+pub async fn s3_verify_file(
+    aws: &AWS,
+    storage_id: &StorageId,
+    expected_hash: &FileHash,
+    expected_size: &FileSize,
+) -> Result<VerifyResult> {
+    trace!("verifying file");
+    let mut resume = ResumeState::default();
+    let mut attempt = 1;
+
+    loop {
+        let result = s3_download_file_impl(
+            aws,
+            storage_id,
+            expected_hash,
+            expected_size,
+            None,
+            DownloadSink::Null,
+            &mut resume,
+        )
+        .await;
+
+        let e = match result {
+            Ok(result) => return Ok(result),
+            Err(DownloadError::Interrupted(e)) if attempt < DOWNLOAD_ATTEMPTS => {
+                trace!(error = ?e, attempt, "verification interrupted, restarting");
+                attempt += 1;
+                continue;
+            }
+            Err(DownloadError::Interrupted(e)) | Err(DownloadError::Failed(e)) => e,
+        };
+
+        return match e.downcast_ref::<CloudError>() {
+            Some(CloudError::Truncated { actual, .. }) => Ok(VerifyResult::SizeMismatch {
+                expected: *expected_size,
+                actual: FileSize { size: *actual },
+            }),
+            Some(CloudError::ObjectNotFound(_)) => Ok(VerifyResult::Missing),
+            _ => Err(e),
+        };
+    }
+}
+
+// CopyObject limit, larger objects are copied in parts.
+const COPY_SINGLE_LIMIT: u64 = 5 * 1024 * 1024 * 1024;
+const COPY_PART_SIZE: u64 = 1024 * 1024 * 1024;
+
+// Copy source is "bucket/key", with the key percent-encoded except for separators.
+fn copy_source(bucket: &str, key: &str) -> String {
+    let mut source = format!("{}/", bucket);
+
+    for b in key.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                source.push(b as char)
+            }
+            _ => source.push_str(&format!("%{:02X}", b)),
+        }
+    }
+
+    source
+}
+
+// Server-side copy, data doesn't leave S3. Metadata and tags are kept, so the copy verifies
+// and downloads like the original. Existing target is not overwritten. Returns the key of the
+// copy to record, as for uploads.
+#[instrument(skip(aws), fields(from = from.as_str(), to = to.as_str()))]
+pub async fn s3_copy(aws: &AWS, from: &StorageId, to: &StorageId) -> Result<Option<String>> {
+    let from_key = aws.object_key(from)?;
+
+    if s3_object_exists(aws, to).await? {
+        return Err(CloudError::AlreadyExists(aws.object_key(to)?).into());
+    }
+
+    let to_key = aws.new_object_key(to);
+
+    let head = aws
+        .s3_client()
+        .head_object()
+        .set_request_payer(aws.request_payer())
+        .bucket(aws.bucket().to_owned())
+        .key(from_key.to_owned())
+        .send()
+        .await
+        .map_err(|e| request_error("HeadObject", e))?;
+    let source = copy_source(aws.bucket(), &from_key);
+    let size = head.content_length().max(0) as u64;
+
+    if size <= COPY_SINGLE_LIMIT {
+        trace!(size, "copying object");
+        aws.s3_client()
+            .copy_object()
+            .set_request_payer(aws.request_payer())
+            .bucket(aws.bucket().to_owned())
+            .key(to_key.key().to_owned())
+            .copy_source(source)
+            .send()
+            .await
+            .map_err(|e| request_error("CopyObject", e))?;
+        let object_key = to_key.commit();
+        s3_copy_part_hashes(aws, from, to, size).await;
+
+        return Ok(object_key);
+    }
+
+    trace!(size, "copying object in parts");
+
+    // Unlike CopyObject, multipart copy starts from scratch, so metadata, tags and headers
+    // are carried over explicitly.
+    let tags: BTreeMap<_, _> = aws
+        .s3_client()
+        .get_object_tagging()
+        .set_request_payer(aws.request_payer())
+        .bucket(aws.bucket().to_owned())
+        .key(from_key)
+        .send()
+        .await
+        .map_err(|e| request_error("GetObjectTagging", e))?
+        .tag_set
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|tag| Some((tag.key?, tag.value?)))
+        .collect();
+
+    let start_resp = aws
+        .s3_client()
+        .create_multipart_upload()
+        .set_request_payer(aws.request_payer())
+        .bucket(aws.bucket().to_owned())
+        .key(to_key.key().to_owned())
+        .set_metadata(head.metadata)
+        .set_tagging(object_tagging(&tags))
+        .set_cache_control(head.cache_control)
+        .set_content_disposition(head.content_disposition)
+        .send()
+        .await
+        .map_err(|e| request_error("CreateMultipartUpload", e))?;
+
+    let result = match copy_parts(aws, &source, to, &start_resp.upload_id, size).await {
+        Ok(parts) => complete_upload(aws, to, &start_resp.upload_id, parts)
+            .await
+            .map(|_| ()),
+        Err(e) => Err(e),
+    };
+
+    if let Err(e) = result {
+        trace!(error = %e, "copy failed");
+
+        if let Err(error) = abort_upload(aws, to_key.key().to_owned(), start_resp.upload_id).await {
+            error!(%error, "error aborting copy");
+        }
+
+        return Err(e);
+    }
+
+    let object_key = to_key.commit();
+    s3_copy_part_hashes(aws, from, to, size).await;
+
+    Ok(object_key)
+}
+
+fn storage_class(class: StorageClass) -> S3StorageClass {
+    match class {
+        StorageClass::Standard => S3StorageClass::Standard,
+        StorageClass::StandardIa => S3StorageClass::StandardIa,
+        StorageClass::OneZoneIa => S3StorageClass::OnezoneIa,
+        StorageClass::IntelligentTiering => S3StorageClass::IntelligentTiering,
+        StorageClass::GlacierIr => S3StorageClass::GlacierIr,
+        StorageClass::Glacier => S3StorageClass::Glacier,
+        StorageClass::DeepArchive => S3StorageClass::DeepArchive,
+    }
+}
+
+// Copies the object onto itself with the new class, which is how S3 changes it. The copy is
+// billed as a request, plus retrieval when leaving an infrequent access class, and leaving a
+// class with a minimum storage duration early charges for the rest of it. Metadata and tags
+// are kept, and so is Object Lock retention, which S3 doesn't copy: the copy is a new version,
+// the locked original stays and is billed until it expires. Objects over the CopyObject limit
+// can't be copied in place, archived ones need a restore first. Part hashes are small and stay
+// where they are.
+#[instrument(skip(aws, storage_id), fields(storage_id = storage_id.as_str()))]
+pub async fn s3_set_storage_class(
+    aws: &AWS,
+    storage_id: &StorageId,
+    class: StorageClass,
+) -> Result<()> {
+    let key = aws.object_key(storage_id)?;
+    let head = aws
+        .s3_client()
+        .head_object()
+        .set_request_payer(aws.request_payer())
+        .bucket(aws.bucket().to_owned())
+        .key(key.to_owned())
+        .send()
+        .await
+        .map_err(|e| request_error("HeadObject", e))?;
+    let class = storage_class(class);
+
+    // S3 rejects a copy onto itself that changes nothing. Standard objects have no class set.
+    if head
+        .storage_class
+        .as_ref()
+        .unwrap_or(&S3StorageClass::Standard)
+        == &class
+    {
+        trace!("storage class unchanged");
+        return Ok(());
+    }
+
+    if head.content_length() as u64 > COPY_SINGLE_LIMIT {
+        return Err(anyhow!(
+            "Object {:?} is too large to change storage class in place",
+            key
+        ));
+    }
+
+    trace!(?class, "changing storage class");
+    aws.s3_client()
+        .copy_object()
+        .set_request_payer(aws.request_payer())
+        .bucket(aws.bucket().to_owned())
+        .key(key.to_owned())
+        .copy_source(copy_source(aws.bucket(), &key))
+        .storage_class(class)
+        .set_object_lock_mode(head.object_lock_mode)
+        .set_object_lock_retain_until_date(head.object_lock_retain_until_date)
+        .set_object_lock_legal_hold_status(head.object_lock_legal_hold_status)
+        .send()
+        .await
+        .map_err(|e| request_error("CopyObject", e))?;
+
+    Ok(())
+}
+
+// Stored data isn't encrypted, master keys only key the hashes. Rewrapping uploads a copy of
+// the object hashed under the current key, streamed through memory rather than a local file.
+// Data is checked against `expected_hash` under its recorded key as it passes, a mismatch
+// aborts the copy before it becomes visible. The original is kept, for callers to delete once
+// the new id and hash are recorded.
+#[instrument(
+    skip(aws, storage_id, expected_hash, expected_size),
+    fields(storage_id = storage_id.as_str())
+)]
+pub async fn s3_rewrap(
+    aws: &AWS,
+    storage_id: &StorageId,
+    expected_hash: &FileHash,
+    expected_size: &FileSize,
+) -> Result<UploadReceipt> {
+    if aws.force_single_put() {
+        return Err(anyhow!("Rewrap uploads in parts, single PUT is forced"));
+    }
+
+    let new_id = new_storage_id(aws)?;
+    let resp = aws
+        .s3_client()
+        .get_object()
+        .set_request_payer(aws.request_payer())
+        .bucket(aws.bucket().to_owned())
+        .key(aws.object_key(storage_id)?)
+        .send()
+        .await
+        .map_err(|e| request_error("GetObject", e))?;
+
+    if resp.content_length() < 0 || resp.content_length() as u64 != expected_size.size {
+        return Err(anyhow!(
+            "File size mismatch: expected {}, got {}",
+            expected_size.size,
+            resp.content_length(),
+        ));
+    }
+
+    let hash = ObjectHash::new(aws, &object_hashing(resp.metadata())?);
+    let compression = object_compression(resp.metadata())?;
+
+    // Stored data is uploaded as is, compressed or not.
+    let mut settings = object_settings(aws);
+    settings.metadata.remove(COMPRESSION_METADATA);
+
+    if let Some(compression) = compression {
+        settings.metadata.insert(
+            COMPRESSION_METADATA.to_owned(),
+            compression_name(compression).to_owned(),
+        );
+    }
+
+    // Check fails the last read, before the last part is sent.
+    let body = stream::try_unfold(
+        (resp.body, hash, 0),
+        |(mut body, mut hash, size)| async move {
+            match body.try_next().await.map_err(std::io::Error::other)? {
+                Some(bytes) => {
+                    hash.update(bytes.clone());
+                    let size = size + bytes.len() as u64;
+
+                    Ok(Some((bytes, (body, hash, size))))
+                }
+                None => {
+                    if size != expected_size.size {
+                        return Err(std::io::Error::other(format!(
+                            "File size mismatch: expected {}, got {}",
+                            expected_size.size, size,
+                        )));
+                    }
+
+                    let actual_hash = hash.finalize(expected_hash);
+
+                    if actual_hash != *expected_hash {
+                        return Err(std::io::Error::other(format!(
+                            "File hash mismatch: expected {}, got {}",
+                            expected_hash, actual_hash,
+                        )));
+                    }
+
+                    Ok(None)
+                }
+            }
+        },
+    );
+    let mut reader = StreamReader::new(Box::pin(body));
+
+    s3_upload_stored(aws, new_id, &mut reader, settings, None).await
+}
+
+// Copy is complete already, without part hashes it's only checked as a whole.
+async fn s3_copy_part_hashes(aws: &AWS, from: &StorageId, to: &StorageId, size: u64) {
+    if !has_part_hashes(size) {
+        return;
+    }
+
+    let result = match (
+        part_hashes_key(aws, from).await,
+        part_hashes_key(aws, to).await,
+    ) {
+        (Ok(from_key), Ok(to_key)) => aws
+            .s3_client()
+            .copy_object()
+            .set_request_payer(aws.request_payer())
+            .bucket(aws.bucket().to_owned())
+            .key(to_key)
+            .copy_source(copy_source(aws.bucket(), &from_key))
+            .send()
+            .await
+            .map_or_else(
+                |e| match e {
+                    // Uploaded before part hashes were stored.
+                    SdkError::ServiceError { raw, .. } if raw.http().status() == 404 => Ok(()),
+                    e => Err(request_error("CopyObject", e)),
+                },
+                |_| Ok(()),
+            ),
+        (Err(e), _) | (_, Err(e)) => Err(e),
+    };
+
+    if let Err(error) = result {
+        error!(%error, "error copying part hashes");
+    }
+}
+
+async fn copy_parts(
+    aws: &AWS,
+    source: &str,
+    to: &StorageId,
+    upload_id: &Option<String>,
+    size: u64,
+) -> Result<CompletedMultipartUpload> {
+    let mut parts = CompletedMultipartUpload::builder();
+
+    for (partnum, (start, end)) in (1..).zip(download_ranges(size, COPY_PART_SIZE)) {
+        trace!(part = partnum, start, end, "copying part");
+        let resp = aws
+            .s3_client()
+            .upload_part_copy()
+            .set_request_payer(aws.request_payer())
+            .bucket(aws.bucket().to_owned())
+            .key(aws.object_key(to)?)
+            .copy_source(source)
+            .copy_source_range(format!("bytes={}-{}", start, end))
+            .part_number(partnum)
+            .set_upload_id(upload_id.to_owned())
+            .send()
+            .await
+            .map_err(|e| request_error("UploadPartCopy", e))?;
+
+        parts = parts.parts(
+            CompletedPart::builder()
+                .set_e_tag(resp.copy_part_result.and_then(|result| result.e_tag))
+                .part_number(partnum)
+                .build(),
+        );
+    }
+
+    Ok(parts.build())
+}
+
+fn object_lock_mode(mode: ObjectLockMode) -> S3ObjectLockMode {
+    match mode {
+        ObjectLockMode::Governance => S3ObjectLockMode::Governance,
+        ObjectLockMode::Compliance => S3ObjectLockMode::Compliance,
+    }
+}
+
+// Deleted by key, so in a versioned bucket the data stays behind a delete marker and bucket
+// lifecycle rules decide when it goes; deleting a version would destroy it for good. The lock
+// is checked first to report ObjectLocked rather than S3's plain AccessDenied. Check and delete
+// aren't atomic: a lock placed in between still fails the delete, just with AccessDenied.
+#[instrument(skip(aws, storage_id), fields(storage_id = storage_id.as_str()))]
+pub async fn s3_delete_file(aws: &AWS, storage_id: &StorageId) -> Result<()> {
+    let key = aws.object_key(storage_id)?;
+    let head = aws
+        .s3_client()
+        .head_object()
+        .set_request_payer(aws.request_payer())
+        .bucket(aws.bucket().to_owned())
+        .key(key.to_owned())
+        .send()
+        .await
+        .map_err(|e| request_error("HeadObject", e))?;
+
+    let retained = head
+        .object_lock_retain_until_date()
+        .is_some_and(|until| until.secs() > DateTime::from(SystemTime::now()).secs());
+    let held = head.object_lock_legal_hold_status() == Some(&ObjectLockLegalHoldStatus::On);
+
+    if retained || held {
+        return Err(CloudError::ObjectLocked(key).into());
+    }
+
+    trace!("deleting file");
+
+    aws.s3_client()
+        .delete_object()
+        .set_request_payer(aws.request_payer())
+        .bucket(aws.bucket().to_owned())
+        .key(key)
+        .send()
+        .await
+        .map_err(|e| request_error("DeleteObject", e))?;
+
+    s3_delete_part_hashes(aws, storage_id, head.content_length().max(0) as u64).await;
+
+    Ok(())
+}
+
+// DeleteObjects limit.
+const DELETE_BATCH_SIZE: usize = 1000;
+
+// One request per batch rather than per object. Batch delete can't report locked objects, so
+// with Object Lock configured objects are deleted one by one as by s3_delete_file. Part hashes
+// are deleted in the same request, whether they exist or not.
+#[instrument(skip(aws, storage_ids), fields(count = storage_ids.len()))]
+pub async fn s3_delete_files(
+    aws: &AWS,
+    storage_ids: &[StorageId],
+) -> Result<Vec<(StorageId, Result<()>)>> {
+    let mut results = Vec::with_capacity(storage_ids.len());
+
+    if aws.object_lock().is_some() {
+        for storage_id in storage_ids {
+            results.push((storage_id.clone(), s3_delete_file(aws, storage_id).await));
+        }
+
+        return Ok(results);
+    }
+
+    for batch in storage_ids.chunks(DELETE_BATCH_SIZE / 2) {
+        let mut keys = Vec::with_capacity(batch.len());
+        let mut delete = Delete::builder().quiet(true);
+
+        for storage_id in batch {
+            let key = aws.object_key(storage_id)?;
+            delete = delete
+                .objects(ObjectIdentifier::builder().key(key.to_owned()).build())
+                .objects(
+                    ObjectIdentifier::builder()
+                        .key(format!("{}{}", key, PART_HASHES_SUFFIX))
+                        .build(),
+                );
+            keys.push(key);
+        }
+
+        trace!(objects = batch.len(), "deleting batch");
+        let resp = aws
+            .s3_client()
+            .delete_objects()
+            .set_request_payer(aws.request_payer())
+            .bucket(aws.bucket().to_owned())
+            .delete(delete.build())
+            .send()
+            .await
+            .map_err(|e| request_error("DeleteObjects", e))?;
+
+        // Quiet mode only lists failures.
+        let mut errors: HashMap<_, _> = resp
+            .errors()
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|error| {
+                let message = format!(
+                    "{}: {}",
+                    error.code().unwrap_or("unknown error"),
+                    error.message().unwrap_or_default()
+                );
+
+                Some((error.key()?, message))
+            })
+            .collect();
+
+        for (storage_id, key) in batch.iter().zip(&keys) {
+            let result = match errors.remove(key.as_str()) {
+                None => Ok(()),
+                Some(message) => Err(anyhow!("Failed to delete {:?}: {}", key, message)),
+            };
+
+            results.push((storage_id.clone(), result));
+        }
+
+        // Left are part hashes, a leftover only takes space.
+        for (key, message) in errors {
+            error!(key, message, "error deleting part hashes");
+        }
+    }
+
+    Ok(results)
+}
+
+async fn s3_delete_object(aws: &AWS, storage_id: &StorageId) -> Result<()> {
+    aws.s3_client()
+        .delete_object()
+        .set_request_payer(aws.request_payer())
+        .bucket(aws.bucket().to_owned())
+        .key(aws.object_key(storage_id)?)
+        .send()
+        .await
+        .map_err(|e| request_error("DeleteObject", e))?;
+
+    Ok(())
+}
+
+const PROBE_DATA: &[u8] = b"private-cloud probe";
+
+// Time of each phase of a probe round trip.
+#[derive(Copy, Debug, Clone, Eq, PartialEq, Hash)]
+pub struct ProbeResult {
+    pub upload: Duration,
+    pub download: Duration,
+    pub delete: Duration,
+}
+
+// Full round trip of a tiny object through the regular upload and download paths.
+// Probe object is deleted even if download fails.
+#[instrument(skip(aws))]
+pub async fn s3_probe(aws: &AWS) -> Result<ProbeResult> {
+    let start = Instant::now();
+    let UploadReceipt {
+        storage_id,
+        size,
+        hash,
+        ..
+    } = s3_upload_stream(aws, &mut &PROBE_DATA[..]).await?;
+    let upload = start.elapsed();
+
+    let start = Instant::now();
+    let target = TempFile::new(&aws.temp_dir(), "probe");
+    let downloaded = s3_download_file(
+        aws,
+        storage_id.to_owned(),
+        &hash,
+        &size,
+        &target.path,
+        OverwritePolicy::Fail,
+    )
+    .await;
+    let download = start.elapsed();
+
+    let start = Instant::now();
+    let deleted = s3_delete_object(aws, &storage_id).await;
+    let delete = start.elapsed();
+
+    downloaded?;
+    deleted?;
+
+    Ok(ProbeResult {
+        upload,
+        download,
+        delete,
+    })
+}
+
+// Objects outside of key prefix or with foreign names are skipped.
+pub fn s3_list_files_stream(aws: &AWS) -> BoxStream<'_, Result<StorageId>> {
+    s3_list_objects(aws)
+        .map_ok(|(storage_id, _)| storage_id)
+        .boxed()
+}
+
+// Storage ids with their object keys.
+pub fn s3_list_objects(aws: &AWS) -> BoxStream<'_, Result<(StorageId, String)>> {
+    aws.s3_client()
+        .list_objects_v2()
+        .set_request_payer(aws.request_payer())
+        .bucket(aws.bucket().to_owned())
+        .prefix(aws.key_prefix())
+        .into_paginator()
+        .send()
+        .map_err(|e| request_error("ListObjectsV2", e))
+        .map_ok(|page| {
+            trace!(key_count = page.key_count, "received listing page");
+            let objects = page
+                .contents
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|object| {
+                    let key = object.key?;
+                    Some((aws.storage_id(&key)?, key))
+                });
+
+            stream::iter(objects.map(Ok).collect::<Vec<_>>())
+        })
+        .try_flatten()
+        .boxed()
+}
+
+pub async fn s3_list_files_by_tag(aws: &AWS, key: &str, value: &str) -> Result<Vec<StorageId>> {
+    let mut ids = vec![];
+    let mut files = s3_list_objects(aws);
+
+    while let Some((storage_id, object_key)) = files.try_next().await? {
+        let resp = aws
+            .s3_client()
+            .get_object_tagging()
+            .set_request_payer(aws.request_payer())
+            .bucket(aws.bucket().to_owned())
+            .key(object_key)
+            .send()
+            .await
+            .map_err(|e| request_error("GetObjectTagging", e))?;
+
+        let tagged = resp
+            .tag_set
+            .unwrap_or_default()
+            .iter()
+            .any(|tag| tag.key.as_deref() == Some(key) && tag.value.as_deref() == Some(value));
+
+        if tagged {
+            ids.push(storage_id);
+        }
+    }
+
+    Ok(ids)
+}
+
+// Presigned request. Headers were signed along with the URL, so requests must send them too:
+// `x-amz-request-payer` with request payer on, which S3 wants as a header.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct PresignedUrl {
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    // Key the object gets when uploaded with the URL, to record as for uploads.
+    pub object_key: Option<String>,
+}
+
+impl From<PresignedRequest> for PresignedUrl {
+    fn from(request: PresignedRequest) -> Self {
+        PresignedUrl {
+            url: request.uri().to_string(),
+            headers: request
+                .headers()
+                .iter()
+                .filter_map(|(name, value)| {
+                    Some((name.as_str().to_owned(), value.to_str().ok()?.to_owned()))
+                })
+                .collect(),
+            object_key: None,
+        }
+    }
+}
+
+// URL for fetching the object without credentials until it expires, at most 7 days.
+// Nothing checks what is fetched: it's the stored object as is, compressed if it was
+// uploaded with compression, and its hash is not verified.
+#[instrument(skip(aws, storage_id), fields(storage_id = storage_id.as_str()))]
+pub async fn s3_presign_get(
+    aws: &AWS,
+    storage_id: &StorageId,
+    expires_in: Duration,
+) -> Result<PresignedUrl> {
+    let request = aws
+        .s3_client()
+        .get_object()
+        .set_request_payer(aws.request_payer())
+        .bucket(aws.bucket().to_owned())
+        .key(aws.object_key(storage_id)?)
+        .presigned(PresigningConfig::expires_in(expires_in)?)
+        .await
+        .map_err(|e| request_error("GetObject", e))?;
+
+    Ok(request.into())
+}
+
+// URL for storing an object under `storage_id` without credentials until it expires. Data is
+// stored as the uploader sends it, without compression, metadata or tags, and only its hash
+// under some key, e.g. from a download, makes it verifiable. Unlike our uploads, an existing
+// object is overwritten.
+#[instrument(skip(aws, storage_id), fields(storage_id = storage_id.as_str()))]
+pub async fn s3_presign_put(
+    aws: &AWS,
+    storage_id: &StorageId,
+    expires_in: Duration,
+) -> Result<PresignedUrl> {
+    let key = aws.new_object_key(storage_id);
+    let request = aws
+        .s3_client()
+        .put_object()
+        .set_request_payer(aws.request_payer())
+        .bucket(aws.bucket().to_owned())
+        .key(key.key().to_owned())
+        .presigned(PresigningConfig::expires_in(expires_in)?)
+        .await
+        .map_err(|e| request_error("PutObject", e))?;
+    // Remembered like an upload's key, the object goes there if the URL is used.
+    Ok(PresignedUrl {
+        object_key: key.commit(),
+        ..request.into()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::aws::s3::{
+        check_free_space, complete_upload, content_storage_id, copy_source, download_ranges,
+        object_hash_algo, object_tagging, preallocate, s3_commit_upload, s3_connect_check, s3_copy,
+        s3_delete_file, s3_delete_files, s3_delete_object, s3_download_file, s3_download_file_impl,
+        s3_download_file_parallel, s3_download_part, s3_object_exists, s3_part_hashes,
+        s3_prepare_upload, s3_presign_get, s3_presign_put, s3_probe, s3_rewrap,
+        s3_set_storage_class, s3_upload_file, s3_upload_file_outcome, s3_upload_stream,
+        s3_verify_all, s3_verify_file, DownloadError, DownloadSink, ObjectHashing, PartCheck,
+        PartHashes, ReadAhead, ResumeState, CHUNK_SIZE, COPY_SINGLE_LIMIT, PARALLEL_RANGE_SIZE,
+        PART_SLICE_SIZE, PROBE_DATA,
+    };
+    use crate::aws::throttle::{AdaptiveLimit, Throttled};
+    use crate::aws::{
+        DedupGuard, IdStrategy, KeyNamer, ObjectLock, ObjectLockMode, OverwritePolicy, S3Checksum,
+        StorageClass, UploadMeta, AWS,
+    };
+    use crate::config::key_fingerprint;
+    use crate::crypto::hash::{ChunkedHash, HashAlgo, HashKeys, HashKind, MerkleHasher};
+    use crate::crypto::init;
+    use crate::crypto::master_key::MasterKey;
+    use crate::error::CloudError;
+    use crate::provider::{
+        CloudProvider, FileHash, FileSize, StorageId, TransferEvent, TransferKind, TransferStats,
+        UploadOutcome, UploadReceipt, VerifyResult,
+    };
+    use aws_sdk_s3::model::CompletedMultipartUpload;
+    use aws_sdk_s3::types::ByteStream;
+    use aws_sdk_s3::{Credentials, Region, RetryConfig};
+    use aws_smithy_client::bounds::SmithyConnector;
+    use aws_smithy_client::test_connection::TestConnection;
+    use aws_smithy_http::body::SdkBody;
+    use aws_smithy_http::result::ConnectorError;
+    use base64::prelude::{Engine, BASE64_STANDARD};
+    use futures::future::BoxFuture;
+    use futures::StreamExt;
+    use md5::{Digest, Md5};
+    use std::collections::{BTreeMap, HashMap};
+    use std::sync::{Arc, Mutex};
+    use std::task::{Context, Poll};
+    use std::time::{Duration, SystemTime};
+    use tokio::fs::OpenOptions;
+
+    #[test]
+    fn tagging() {
+        assert_eq!(object_tagging(&BTreeMap::new()), None);
+
+        let tags = BTreeMap::from([
+            ("job".to_owned(), "nightly".to_owned()),
+            ("host".to_owned(), "my laptop&co".to_owned()),
+        ]);
+        assert_eq!(
+            object_tagging(&tags).as_deref(),
+            Some("host=my+laptop%26co&job=nightly")
+        );
+    }
+
+    #[test]
+    fn hash_algo_metadata() {
+        let metadata = |name: &str| HashMap::from([("hash-algo".to_owned(), name.to_owned())]);
+
+        assert_eq!(object_hash_algo(None).ok(), Some(HashAlgo::Blake2b));
+        assert_eq!(
+            object_hash_algo(Some(&HashMap::new())).ok(),
+            Some(HashAlgo::Blake2b)
+        );
+        assert_eq!(
+            object_hash_algo(Some(&metadata("sha256"))).ok(),
+            Some(HashAlgo::Sha256)
+        );
+        assert!(matches!(
+            object_hash_algo(Some(&metadata("md5"))),
+            Err { .. }
+        ));
+    }
+
+    fn canned_response(
+        status: u16,
+        headers: &[(&str, &str)],
+        body: &'static str,
+    ) -> (http::Request<SdkBody>, http::Response<&'static str>) {
+        let mut response = http::Response::builder().status(status);
+
+        for (name, value) in headers {
+            response = response.header(*name, *value);
+        }
+
+        (
+            http::Request::builder()
+                .body(SdkBody::empty())
+                .expect("failed to build request"),
+            response.body(body).expect("failed to build response"),
+        )
+    }
+
+    // SDK retries are disabled, so only our own retry is exercised.
+    fn test_aws<C>(connection: C) -> AWS
+    where
+        C: SmithyConnector<Error = ConnectorError> + Send + 'static,
+    {
+        let config = aws_sdk_s3::Config::builder()
+            .region(Region::new("us-east-1"))
+            .credentials_provider(Credentials::new("keyid", "secret", None, None, "test"))
+            .retry_config(RetryConfig::disabled())
+            .build();
+        let client = aws_sdk_s3::Client::from_conf_conn(config, connection);
+
+        AWS::with_client(client).expect("failed to create provider")
+    }
+
+    #[tokio::test]
+    async fn complete_retried() {
+        let connection = TestConnection::new(vec![
+            canned_response(
+                500,
+                &[],
+                "<Error><Code>InternalError</Code><Message>retry</Message></Error>",
+            ),
+            canned_response(
+                200,
+                &[],
+                "<CompleteMultipartUploadResult><Key>key</Key></CompleteMultipartUploadResult>",
+            ),
+        ]);
+        let aws = test_aws(connection.clone());
+        let upload_id = Some("upload".to_owned());
+
+        let retries = complete_upload(
+            &aws,
+            &StorageId::generate(),
+            &upload_id,
+            CompletedMultipartUpload::builder().build(),
+        )
+        .await
+        .expect("complete failed");
+        assert_eq!(retries.0, 1);
+        assert_eq!(connection.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn complete_permanent_failure() {
+        let connection = TestConnection::new(vec![
+            canned_response(
+                404,
+                &[],
+                "<Error><Code>NoSuchUpload</Code><Message>gone</Message></Error>",
+            ),
+            canned_response(
+                200,
+                &[],
+                "<CompleteMultipartUploadResult><Key>key</Key></CompleteMultipartUploadResult>",
+            ),
+        ]);
+        let aws = test_aws(connection.clone());
+        let upload_id = Some("upload".to_owned());
+
+        let result = complete_upload(
+            &aws,
+            &StorageId::generate(),
+            &upload_id,
+            CompletedMultipartUpload::builder().build(),
+        )
+        .await;
+        assert!(matches!(result, Err { .. }));
+        assert_eq!(connection.requests().len(), 1);
+    }
+
+    // Never answers complete and abort requests, like a connection hung in the middle of them.
+    #[derive(Clone)]
+    struct HangingCompletion(TestConnection<&'static str>);
+
+    impl tower::Service<http::Request<SdkBody>> for HangingCompletion {
+        type Response = http::Response<SdkBody>;
+        type Error = ConnectorError;
+        type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, request: http::Request<SdkBody>) -> Self::Future {
+            let query = request.uri().query().unwrap_or_default();
+
+            if query.contains("uploadId") && request.method() != http::Method::PUT {
+                return Box::pin(futures::future::pending());
+            }
+
+            Box::pin(self.0.call(request))
+        }
+    }
+
+    // Retries back off for seconds between complete attempts, paused time skips the waits.
+    #[tokio::test(start_paused = true)]
+    async fn upload_cleanup_timeout() {
+        let connection = TestConnection::new(vec![
+            canned_response(
+                200, &[],
+                "<InitiateMultipartUploadResult><UploadId>upload</UploadId></InitiateMultipartUploadResult>",
+            ),
+            canned_response(200, &[], ""),
+        ]);
+        let mut aws = test_aws(HangingCompletion(connection.clone()));
+        aws.set_complete_timeout(Duration::from_millis(10));
+
+        // Every complete attempt times out, then so does the abort, which doesn't hide why
+        // the upload failed.
+        let error = s3_upload_stream(&aws, &mut &b"data"[..])
+            .await
+            .expect_err("upload succeeded");
+        assert!(matches!(
+            error.downcast_ref::<CloudError>(),
+            Some(CloudError::Timeout(_))
+        ));
+        assert_eq!(connection.requests().len(), 2);
+    }
+
+    // Reads request bodies a frame at a time with a delay before each, like a slow link.
+    #[derive(Clone)]
+    struct SlowConnection {
+        inner: TestConnection<&'static str>,
+        delay: Duration,
+    }
+
+    impl tower::Service<http::Request<SdkBody>> for SlowConnection {
+        type Response = http::Response<SdkBody>;
+        type Error = ConnectorError;
+        type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, request: http::Request<SdkBody>) -> Self::Future {
+            let mut connection = self.inner.clone();
+            let delay = self.delay;
+
+            Box::pin(async move {
+                let (parts, mut body) = request.into_parts();
+                let mut data = Vec::new();
+
+                loop {
+                    tokio::time::sleep(delay).await;
+
+                    match hyper::body::HttpBody::data(&mut body).await {
+                        Some(frame) => data
+                            .extend_from_slice(&frame.map_err(|e| ConnectorError::other(e, None))?),
+                        None => break,
+                    }
+                }
+
+                connection
+                    .call(http::Request::from_parts(parts, SdkBody::from(data)))
+                    .await
+            })
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn part_idle_timeout() {
+        let responses = || {
+            vec![
+                canned_response(
+                    200, &[],
+                    "<InitiateMultipartUploadResult><UploadId>upload</UploadId></InitiateMultipartUploadResult>",
+                ),
+                canned_response(200, &[], ""),
+                canned_response(
+                    200, &[],
+                    "<CompleteMultipartUploadResult><Key>key</Key></CompleteMultipartUploadResult>",
+                ),
+            ]
+        };
+        let data = vec![7; 3 * PART_SLICE_SIZE];
+        let upload = |delay| {
+            let connection = TestConnection::new(responses());
+            let mut aws = test_aws(SlowConnection {
+                inner: connection.clone(),
+                delay,
+            });
+            aws.set_part_timeout(Duration::from_secs(10));
+            let data = data.clone();
+
+            async move {
+                let result = s3_upload_stream(&aws, &mut &data[..]).await;
+                (result, connection)
+            }
+        };
+
+        // Part takes longer than the timeout, but never stops moving for that long.
+        let (result, connection) = upload(Duration::from_secs(6)).await;
+        result.expect("upload failed");
+        assert_eq!(
+            connection.requests()[1]
+                .actual
+                .body()
+                .bytes()
+                .map(<[u8]>::len),
+            Some(data.len())
+        );
+
+        let (result, _) = upload(Duration::from_secs(20)).await;
+        assert!(matches!(
+            result
+                .expect_err("upload succeeded")
+                .downcast_ref::<CloudError>(),
+            Some(CloudError::Timeout(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn connect_check() {
+        let connection = TestConnection::new(vec![
+            canned_response(200, &[], ""),
+            canned_response(404, &[], ""),
+            canned_response(403, &[], ""),
+        ]);
+        let aws = test_aws(connection);
+
+        s3_connect_check(&aws).await.expect("check failed");
+
+        let result = s3_connect_check(&aws).await;
+        assert!(matches!(
+            result.unwrap_err().downcast_ref::<CloudError>(),
+            Some(CloudError::BucketNotFound(_))
+        ));
+
+        let result = s3_connect_check(&aws).await;
+        assert!(matches!(
+            result.unwrap_err().downcast_ref::<CloudError>(),
+            Some(CloudError::AccessDenied(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn upload_existence_check() {
+        let upload_responses = || {
+            vec![
+                canned_response(
+                    200, &[],
+                    "<InitiateMultipartUploadResult><UploadId>upload</UploadId></InitiateMultipartUploadResult>",
+                ),
+                canned_response(200, &[], ""),
+                canned_response(
+                    200, &[],
+                    "<CompleteMultipartUploadResult><Key>key</Key></CompleteMultipartUploadResult>",
+                ),
+            ]
+        };
+
+        // Random ids are not checked.
+        let connection = TestConnection::new(upload_responses());
+        let aws = test_aws(connection.clone());
+        s3_upload_stream(&aws, &mut &b"data"[..])
+            .await
+            .expect("upload failed");
+        let requests = connection.requests();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[0].actual.method(), "POST");
+        drop(requests);
+
+        // Denied check doesn't stop the upload.
+        let mut responses = vec![canned_response(403, &[], "")];
+        responses.extend(upload_responses());
+        let connection = TestConnection::new(responses);
+        let mut aws = test_aws(connection.clone());
+        aws.set_id_strategy(IdStrategy::Fixed(StorageId::generate().as_str().to_owned()));
+        s3_upload_stream(&aws, &mut &b"data"[..])
+            .await
+            .expect("upload failed");
+        let requests = connection.requests();
+        assert_eq!(requests.len(), 4);
+        assert_eq!(requests[0].actual.method(), "HEAD");
+    }
+
+    #[tokio::test]
+    async fn upload_missing_file() {
+        let dir = tempfile::tempdir().expect("failed to create temp dir");
+        let path = dir.path().join("missing");
+        let aws = test_aws(TestConnection::<&str>::new(vec![]));
+
+        let error = s3_upload_file(&aws, &path).await.unwrap_err();
+        assert!(error.to_string().contains(&path.display().to_string()));
+        assert!(error.downcast_ref::<std::io::Error>().is_some());
+    }
+
+    #[tokio::test]
+    async fn upload_fixed_id() {
+        let id = "0b5c2d1e-3f4a-4b6c-8d7e-9f0a1b2c3d4e";
+        let connection = TestConnection::new(vec![canned_response(200, &[], "")]);
+        let mut aws = test_aws(connection.clone());
+        aws.set_id_strategy(IdStrategy::Fixed(id.to_owned()));
+
+        let result = s3_upload_stream(&aws, &mut &b"data"[..]).await;
+        assert!(matches!(
+            result.unwrap_err().downcast_ref::<CloudError>(),
+            Some(CloudError::AlreadyExists(key)) if key.ends_with(id)
+        ));
+        assert_eq!(connection.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn upload_seeded_ids() {
+        let connection = TestConnection::new(vec![
+            canned_response(200, &[], ""),
+            canned_response(200, &[], ""),
+        ]);
+        let mut aws = test_aws(connection.clone());
+        aws.set_id_strategy(IdStrategy::Seeded(7));
+
+        for index in 0..2 {
+            let id = StorageId::seeded(7, index);
+            let result = s3_upload_stream(&aws, &mut &b"data"[..]).await;
+            assert!(matches!(
+                result.unwrap_err().downcast_ref::<CloudError>(),
+                Some(CloudError::AlreadyExists(key)) if key.ends_with(id.as_str())
+            ));
+        }
+        assert_eq!(connection.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn upload_content_hash_dedup() {
+        let file = tempfile::NamedTempFile::new().expect("failed to create temp file");
+        std::fs::write(file.path(), b"data").expect("failed to write temp file");
+        let connection = TestConnection::new(vec![canned_response(200, &[], "")]);
+        let mut aws = test_aws(connection.clone());
+        aws.set_id_strategy(IdStrategy::ContentHash);
+
+        let mut hash = ChunkedHash::keyed(aws.hash_key(HashKind::File));
+        hash.update(&b"data"[..]);
+        let hash = FileHash::from_bytes(hash.finalize());
+
+        // Stored object is found by HEAD, nothing gets uploaded.
+        let outcome = s3_upload_file_outcome(&aws, file.path())
+            .await
+            .expect("upload failed");
+        assert!(outcome.is_skipped());
+        let UploadReceipt {
+            storage_id,
+            size,
+            hash: uploaded_hash,
+            ..
+        } = outcome.into_receipt();
+        assert_eq!(storage_id, content_storage_id(&hash));
+        assert_eq!(size.size, 4);
+        assert_eq!(uploaded_hash, hash);
+        assert_eq!(connection.requests().len(), 1);
+        assert_eq!(connection.requests()[0].actual.method(), "HEAD");
+
+        let result = s3_upload_stream(&aws, &mut &b"data"[..]).await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn upload_dedup_guard() {
+        let file = tempfile::NamedTempFile::new().expect("failed to create temp file");
+        std::fs::write(file.path(), b"data").expect("failed to write temp file");
+        let connection = TestConnection::new(vec![
+            canned_response(404, &[], ""),
+            canned_response(
+                200, &[],
+                "<InitiateMultipartUploadResult><UploadId>upload</UploadId></InitiateMultipartUploadResult>",
+            ),
+            canned_response(200, &[], ""),
+            canned_response(
+                200, &[],
+                "<CompleteMultipartUploadResult><Key>key</Key></CompleteMultipartUploadResult>",
+            ),
+            canned_response(200, &[], ""),
+        ]);
+        let mut aws = test_aws(connection.clone());
+        let guard = Arc::new(DedupGuard::new());
+        aws.set_id_strategy(IdStrategy::ContentHash);
+        aws.set_dedup_guard(guard.clone());
+
+        // Second upload waits for the first, then finds the object by HEAD.
+        let (first, second) = futures::join!(
+            s3_upload_file(&aws, file.path()),
+            s3_upload_file(&aws, file.path())
+        );
+        let first = first.expect("upload failed");
+        let second = second.expect("upload failed");
+        assert_eq!(first.storage_id, second.storage_id);
+        assert_eq!(first.hash, second.hash);
+        assert_eq!(guard.in_flight(), 0);
+
+        let requests = connection.requests();
+        assert_eq!(requests.len(), 5);
+        assert_eq!(requests[0].actual.method(), "HEAD");
+        assert_eq!(requests[4].actual.method(), "HEAD");
+    }
+
+    #[tokio::test]
+    async fn two_phase_upload() {
+        let file = tempfile::NamedTempFile::new().expect("failed to create temp file");
+        std::fs::write(file.path(), b"data").expect("failed to write temp file");
+        let upload_responses = || {
+            vec![
+                canned_response(404, &[], ""),
+                canned_response(
+                    200, &[],
+                    "<InitiateMultipartUploadResult><UploadId>upload</UploadId></InitiateMultipartUploadResult>",
+                ),
+                canned_response(200, &[], ""),
+                canned_response(
+                    200, &[],
+                    "<CompleteMultipartUploadResult><Key>key</Key></CompleteMultipartUploadResult>",
+                ),
+            ]
+        };
+        let mut responses = upload_responses();
+        responses.extend(upload_responses());
+        responses.push(canned_response(204, &[], ""));
+        responses.push(canned_response(200, &[], ""));
+        let connection = TestConnection::new(responses);
+        let mut aws = test_aws(connection.clone());
+        aws.set_id_strategy(IdStrategy::Seeded(7));
+
+        let mut hash = ChunkedHash::keyed(aws.hash_key(HashKind::File));
+        hash.update(&b"data"[..]);
+        let hash = FileHash::from_bytes(hash.finalize());
+
+        // Hashing alone sends nothing.
+        let prepared = s3_prepare_upload(&aws, file.path())
+            .await
+            .expect("prepare failed");
+        assert_eq!(prepared.hash(), &hash);
+        assert_eq!(prepared.size().size, 4);
+        assert_eq!(prepared.storage_id(), None);
+        assert!(connection.requests().is_empty());
+
+        let outcome = s3_commit_upload(&aws, prepared)
+            .await
+            .expect("commit failed");
+        assert!(!outcome.is_skipped());
+        let UploadReceipt {
+            storage_id,
+            size,
+            hash: uploaded_hash,
+            ..
+        } = outcome.into_receipt();
+        assert_eq!(storage_id, StorageId::seeded(7, 0));
+        assert_eq!(size.size, 4);
+        assert_eq!(uploaded_hash, hash);
+        assert_eq!(connection.requests().len(), 4);
+
+        // File changed between phases, the object is deleted.
+        let prepared = s3_prepare_upload(&aws, file.path())
+            .await
+            .expect("prepare failed");
+        std::fs::write(file.path(), b"atad").expect("failed to write temp file");
+        assert!(matches!(s3_commit_upload(&aws, prepared).await, Err { .. }));
+        let requests = connection.requests();
+        assert_eq!(requests.len(), 9);
+        assert_eq!(requests[8].actual.method(), "DELETE");
+        drop(requests);
+
+        // Content hash id is known before anything is sent.
+        aws.set_id_strategy(IdStrategy::ContentHash);
+        let prepared = s3_prepare_upload(&aws, file.path())
+            .await
+            .expect("prepare failed");
+        assert_eq!(
+            prepared.storage_id(),
+            Some(&content_storage_id(prepared.hash()))
+        );
+
+        // Found stored by then, nothing is sent.
+        let storage_id = prepared.storage_id().cloned();
+        let outcome = s3_commit_upload(&aws, prepared)
+            .await
+            .expect("commit failed");
+        assert!(matches!(
+            outcome,
+            UploadOutcome::Skipped(receipt) if Some(&receipt.storage_id) == storage_id.as_ref()
+        ));
+        assert_eq!(connection.requests().len(), 10);
+    }
+
+    #[tokio::test]
+    async fn download_reports_stats() {
+        let dest = tempfile::tempdir().expect("failed to create temp dir");
+        let connection = TestConnection::new(vec![canned_response(
+            200,
+            &[("Content-Length", "4")],
+            "data",
+        )]);
+        let mut aws = test_aws(connection);
+        let reported = Arc::new(Mutex::new(Vec::new()));
+        let sink = reported.clone();
+        aws.set_transfer_callback(move |kind, _, stats| {
+            sink.lock().expect("poisoned lock").push((kind, *stats))
+        });
+
+        let mut hash = ChunkedHash::keyed(aws.hash_key(HashKind::File));
+        hash.update(&b"data"[..]);
+
+        s3_download_file(
+            &aws,
+            StorageId::generate(),
+            &FileHash::from_bytes(hash.finalize()),
+            &FileSize { size: 4 },
+            &dest.path().join("file"),
+            OverwritePolicy::Fail,
+        )
+        .await
+        .expect("download failed");
+
+        let reported = reported.lock().expect("poisoned lock");
+        assert!(matches!(
+            reported[..],
+            [(
+                TransferKind::Download,
+                TransferStats {
+                    bytes: 4,
+                    parts: 1,
+                    complete_retries: 0,
+                    ..
+                }
+            )]
+        ));
+    }
+
+    #[tokio::test]
+    async fn download_resume() {
+        let dest = tempfile::tempdir().expect("failed to create temp dir");
+        let path = dest.path().join("file");
+        let rest = || canned_response(206, &[("Content-Length", "2")], "ta");
+        let connection = TestConnection::new(vec![rest(), canned_response(412, &[], "")]);
+        let aws = test_aws(connection.clone());
+        let storage_id = StorageId::generate();
+
+        let mut hash = ChunkedHash::keyed(aws.hash_key(HashKind::File));
+        hash.update(&b"data"[..]);
+        let hash = FileHash::from_bytes(hash.finalize());
+        let resume = || ResumeState {
+            offset: 2,
+            hashing: Some(ObjectHashing::default()),
+            etag: Some("\"etag\"".to_owned()),
+        };
+
+        std::fs::write(&path, b"da").expect("failed to write file");
+        let result = s3_download_file_impl(
+            &aws,
+            &storage_id,
+            &hash,
+            &FileSize { size: 4 },
+            None,
+            DownloadSink::File(&path),
+            &mut resume(),
+        )
+        .await;
+        assert!(matches!(result, Ok(VerifyResult::Ok)));
+        assert_eq!(std::fs::read(&path).expect("failed to read file"), b"data");
+
+        // Replaced object fails the precondition.
+        std::fs::write(&path, b"da").expect("failed to write file");
+        let result = s3_download_file_impl(
+            &aws,
+            &storage_id,
+            &hash,
+            &FileSize { size: 4 },
+            None,
+            DownloadSink::File(&path),
+            &mut resume(),
+        )
+        .await;
+        assert!(matches!(
+            result,
+            Err(DownloadError::Failed(e))
+                if matches!(e.downcast_ref::<CloudError>(), Some(CloudError::ObjectChanged(_)))
+        ));
+
+        let requests = connection.requests();
+        assert_eq!(requests[0].actual.headers()["range"], "bytes=2-");
+        assert_eq!(requests[0].actual.headers()["if-match"], "\"etag\"");
+    }
+
+    #[tokio::test]
+    async fn read_ahead() {
+        let aws = test_aws(TestConnection::<&str>::new(vec![]));
+        let chunks = vec![
+            Ok("da"),
+            Ok("ta"),
+            Err(std::io::Error::other("connection reset")),
+        ];
+        let body = ByteStream::from(hyper::Body::wrap_stream(futures::stream::iter(chunks)));
+        let mut body = ReadAhead::new(&aws, body);
+
+        let next = body.next().await.expect("read failed");
+        assert_eq!(next.as_deref(), Some(&b"da"[..]));
+        let next = body.next().await.expect("read failed");
+        assert_eq!(next.as_deref(), Some(&b"ta"[..]));
+        assert!(matches!(body.next().await, Err { .. }));
+    }
+
+    // Fast link, slow disk: the body yields a chunk every 10ms and each takes 10ms to save.
+    // Read ahead overlaps the two, on virtual time so the numbers are exact.
+    #[tokio::test(start_paused = true)]
+    async fn read_ahead_overlap() {
+        const CHUNKS: u32 = 8;
+        const DELAY: Duration = Duration::from_millis(10);
+
+        let aws = test_aws(TestConnection::<&str>::new(vec![]));
+        let body = || {
+            let chunks = futures::stream::unfold(0, |n| async move {
+                if n == CHUNKS {
+                    return None;
+                }
+
+                tokio::time::sleep(DELAY).await;
+                Some((Ok::<_, std::io::Error>("data"), n + 1))
+            });
+
+            ByteStream::from(hyper::Body::wrap_stream(chunks))
+        };
+
+        let start = tokio::time::Instant::now();
+        let mut sequential = body();
+        while let Some(_chunk) = sequential.next().await.transpose().expect("read failed") {
+            tokio::time::sleep(DELAY).await;
+        }
+        let sequential = start.elapsed();
+
+        let start = tokio::time::Instant::now();
+        let mut ahead = ReadAhead::new(&aws, body());
+        while let Some(_chunk) = ahead.next().await.expect("read failed") {
+            tokio::time::sleep(DELAY).await;
+        }
+        let overlapped = start.elapsed();
+
+        assert_eq!(sequential, DELAY * CHUNKS * 2);
+        assert!(overlapped <= DELAY * (CHUNKS + 1), "took {:?}", overlapped);
+    }
+
+    #[tokio::test]
+    async fn download_truncated() {
+        let dest = tempfile::tempdir().expect("failed to create temp dir");
+        let connection =
+            TestConnection::new(vec![canned_response(200, &[("Content-Length", "4")], "da")]);
+        let aws = test_aws(connection);
+        let path = dest.path().join("file");
+
+        let result = s3_download_file(
+            &aws,
+            StorageId::generate(),
+            &FileHash::default(),
+            &FileSize { size: 4 },
+            &path,
+            OverwritePolicy::Fail,
+        )
+        .await;
+        assert!(matches!(
+            result.unwrap_err().downcast_ref::<CloudError>(),
+            Some(CloudError::Truncated {
+                expected: 4,
+                actual: 2
+            })
+        ));
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn download_overwrite() {
+        let dest = tempfile::tempdir().expect("failed to create temp dir");
+        let response = |body| canned_response(200, &[("Content-Length", "4")], body);
+        let connection =
+            TestConnection::new(vec![response("data"), response("dXta"), response("data")]);
+        let aws = test_aws(connection.clone());
+        let mut hash = ChunkedHash::keyed(aws.hash_key(HashKind::File));
+        hash.update(&b"data"[..]);
+        let hash = FileHash::from_bytes(hash.finalize());
+        let size = FileSize { size: 4 };
+        let path = dest.path().join("file");
+        let download = |overwrite| {
+            s3_download_file(&aws, StorageId::generate(), &hash, &size, &path, overwrite)
+        };
+
+        // Refused before any request.
+        std::fs::write(&path, b"live").expect("failed to write file");
+        let error = download(OverwritePolicy::Fail)
+            .await
+            .expect_err("download succeeded");
+        assert!(matches!(
+            error.downcast_ref::<CloudError>(),
+            Some(CloudError::TargetExists { .. })
+        ));
+        assert!(connection.requests().is_empty());
+        assert_eq!(std::fs::read(&path).expect("failed to read file"), b"live");
+
+        // Moved to the first free number.
+        std::fs::write(dest.path().join("file.1"), b"older").expect("failed to write file");
+        download(OverwritePolicy::RenameExisting)
+            .await
+            .expect("download failed");
+        assert_eq!(std::fs::read(&path).expect("failed to read file"), b"data");
+        assert_eq!(
+            std::fs::read(dest.path().join("file.2")).expect("failed to read file"),
+            b"live"
+        );
+
+        // Failed download leaves the existing file, and no temp file next to it.
+        std::fs::write(&path, b"live").expect("failed to write file");
+        download(OverwritePolicy::Overwrite)
+            .await
+            .expect_err("download succeeded");
+        assert_eq!(std::fs::read(&path).expect("failed to read file"), b"live");
+        assert_eq!(
+            std::fs::read_dir(dest.path())
+                .expect("failed to list dir")
+                .count(),
+            3
+        );
+
+        download(OverwritePolicy::Overwrite)
+            .await
+            .expect("download failed");
+        assert_eq!(std::fs::read(&path).expect("failed to read file"), b"data");
+    }
+
+    #[test]
+    fn part_check() {
+        let aws = test_aws(TestConnection::<&str>::new(vec![]));
+        let hasher =
+            MerkleHasher::new(aws.hash_key(HashKind::Chunk), 4).expect("failed to create hasher");
+        let part_hashes = PartHashes {
+            key_fingerprint: aws.key_fingerprint(),
+            tree: hasher.hash(b"abcdefghij"),
+        };
+
+        let mut check = PartCheck::new(&aws, &part_hashes, 0).expect("no part check");
+        check.update(b"abc").expect("part check failed");
+        check.update(b"defghi").expect("part check failed");
+        check.update(b"j").expect("part check failed");
+        check.finish().expect("part check failed");
+
+        // Fails at the end of the bad part, not of the object.
+        let mut check = PartCheck::new(&aws, &part_hashes, 0).expect("no part check");
+        check.update(b"abcd").expect("part check failed");
+        let error = check.update(b"efgX").expect_err("bad part accepted");
+        assert!(matches!(
+            error.downcast_ref::<CloudError>(),
+            Some(CloudError::PartCorrupted { part: 2, offset: 4 })
+        ));
+
+        // Resumed midway into the second part, the third is still checked.
+        let mut check = PartCheck::new(&aws, &part_hashes, 6).expect("no part check");
+        check.update(b"XXij").expect("part check failed");
+        assert!(check.finish().is_ok());
+        let mut check = PartCheck::new(&aws, &part_hashes, 6).expect("no part check");
+        check.update(b"ghiX").expect("part check failed");
+        assert!(matches!(check.finish(), Err { .. }));
+
+        // Parts swapped in the recorded hashes no longer add up to the root.
+        let mut swapped = hasher.hash(b"abcdefghij");
+        swapped.blocks.swap(0, 1);
+        let swapped = PartHashes {
+            key_fingerprint: aws.key_fingerprint(),
+            tree: swapped,
+        };
+        assert!(PartCheck::new(&aws, &swapped, 0).is_none());
+
+        let unknown_key = PartHashes {
+            key_fingerprint: "0000000000000000".to_owned(),
+            ..part_hashes
+        };
+        assert!(PartCheck::new(&aws, &unknown_key, 0).is_none());
+    }
+
+    #[tokio::test]
+    async fn part_hashes_object() {
+        let connection = TestConnection::new(vec![
+            canned_response(404, &[], ""),
+            canned_response(
+                200,
+                &[],
+                r#"{"key_fingerprint":"abcd","block_size":4,"size":0,"blocks":[],"root":"0000000000000000000000000000000000000000000000000000000000000000"}"#,
+            ),
+            canned_response(200, &[], "not json"),
+        ]);
+        let aws = test_aws(connection.clone());
+        let storage_id = StorageId::generate();
+
+        let part_hashes = s3_part_hashes(&aws, &storage_id)
+            .await
+            .expect("failed to get part hashes");
+        assert!(part_hashes.is_none());
+        assert_eq!(
+            connection.requests()[0].actual.uri().path(),
+            format!("/bucket/{}.parts", storage_id.as_str())
+        );
+
+        let part_hashes = s3_part_hashes(&aws, &storage_id)
+            .await
+            .expect("failed to get part hashes")
+            .expect("no part hashes");
+        assert_eq!(part_hashes.tree.block_size, 4);
+        assert_eq!(part_hashes.key_fingerprint, "abcd");
+
+        let part_hashes = s3_part_hashes(&aws, &storage_id)
+            .await
+            .expect("failed to get part hashes");
+        assert!(part_hashes.is_none());
+    }
+
+    #[test]
+    fn ranges() {
+        assert_eq!(download_ranges(0, 4), []);
+        assert_eq!(download_ranges(3, 4), [(0, 2)]);
+        assert_eq!(download_ranges(8, 4), [(0, 3), (4, 7)]);
+        assert_eq!(download_ranges(9, 4), [(0, 3), (4, 7), (8, 8)]);
+    }
+
+    // Answers HeadObject with the size of `data` and each ranged GetObject with that range.
+    #[derive(Clone)]
+    struct RangeConnection {
+        data: &'static [u8],
+        ranges: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl tower::Service<http::Request<SdkBody>> for RangeConnection {
+        type Response = http::Response<SdkBody>;
+        type Error = ConnectorError;
+        type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, request: http::Request<SdkBody>) -> Self::Future {
+            let response = match request.headers().get("range") {
+                None => http::Response::builder()
+                    .status(200)
+                    .header("Content-Length", self.data.len())
+                    .body(SdkBody::empty()),
+                Some(range) => {
+                    let range = range.to_str().expect("invalid range").to_owned();
+                    let (start, end) = range
+                        .strip_prefix("bytes=")
+                        .and_then(|range| range.split_once('-'))
+                        .expect("invalid range");
+                    let start: usize = start.parse().expect("invalid range start");
+                    let end: usize = end.parse().expect("invalid range end");
+                    self.ranges.lock().expect("lock poisoned").push(range);
+
+                    http::Response::builder()
+                        .status(206)
+                        .header("Content-Length", end + 1 - start)
+                        .body(SdkBody::from(&self.data[start..=end]))
+                }
+            };
+
+            Box::pin(async move { Ok(response.expect("failed to build response")) })
+        }
+    }
+
+    #[tokio::test]
+    async fn parallel_download() {
+        let dest = tempfile::tempdir().expect("failed to create temp dir");
+        let connection = RangeConnection {
+            data: b"0123456789",
+            ranges: Arc::new(Mutex::new(Vec::new())),
+        };
+        let aws = test_aws(connection.clone());
+        let path = dest.path().join("file");
+
+        let mut hash = ChunkedHash::keyed(aws.hash_key(HashKind::File));
+        hash.update(&b"0123456789"[..]);
+
+        // Last range is short.
+        let parts = s3_download_file_parallel(
+            &aws,
+            &StorageId::generate(),
+            &FileHash::from_bytes(hash.finalize()),
+            &FileSize { size: 10 },
+            None,
+            &path,
+            4,
+        )
+        .await
+        .expect("download failed");
+        assert_eq!(parts, Some(3));
+        assert_eq!(
+            std::fs::read(&path).expect("failed to read file"),
+            b"0123456789"
+        );
+
+        let mut ranges = connection.ranges.lock().expect("lock poisoned").clone();
+        ranges.sort();
+        assert_eq!(ranges, ["bytes=0-3", "bytes=4-7", "bytes=8-9"]);
+    }
+
+    #[tokio::test]
+    async fn parallel_download_refetch() {
+        let dest = tempfile::tempdir().expect("failed to create temp dir");
+        let response = |status, body| canned_response(status, &[("Content-Length", "4")], body);
+        let connection = TestConnection::new(vec![
+            response(200, ""),
+            response(206, "dXta"),
+            response(206, "data"),
+        ]);
+        let aws = test_aws(connection.clone());
+        let path = dest.path().join("file");
+        let hash = |kind| {
+            let mut hash = ChunkedHash::keyed(aws.hash_key(kind));
+            hash.update(&b"data"[..]);
+            FileHash::from_bytes(hash.finalize())
+        };
+        let part_hashes = PartHashes {
+            key_fingerprint: aws.key_fingerprint(),
+            tree: MerkleHasher::new(aws.hash_key(HashKind::Chunk), PARALLEL_RANGE_SIZE as usize)
+                .expect("failed to create hasher")
+                .hash(b"data"),
+        };
+
+        // Corrupted range is fetched again, not reported by the whole file check.
+        s3_download_file_parallel(
+            &aws,
+            &StorageId::generate(),
+            &hash(HashKind::File),
+            &FileSize { size: 4 },
+            Some(&part_hashes),
+            &path,
+            PARALLEL_RANGE_SIZE,
+        )
+        .await
+        .expect("download failed");
+        assert_eq!(std::fs::read(&path).expect("failed to read file"), b"data");
+        assert_eq!(connection.requests().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn parallel_download_truncated_range() {
+        let dest = tempfile::tempdir().expect("failed to create temp dir");
+        let response = |status, body| canned_response(status, &[("Content-Length", "4")], body);
+        let connection = TestConnection::new(vec![
+            response(200, ""),
+            response(206, "da"),
+            response(206, "data"),
+        ]);
+        let aws = test_aws(connection.clone());
+        let path = dest.path().join("file");
+        let mut hash = ChunkedHash::keyed(aws.hash_key(HashKind::File));
+        hash.update(&b"data"[..]);
+
+        // Range cut short is fetched again from its start.
+        s3_download_file_parallel(
+            &aws,
+            &StorageId::generate(),
+            &FileHash::from_bytes(hash.finalize()),
+            &FileSize { size: 4 },
+            None,
+            &path,
+            PARALLEL_RANGE_SIZE,
+        )
+        .await
+        .expect("download failed");
+        assert_eq!(std::fs::read(&path).expect("failed to read file"), b"data");
+        assert_eq!(connection.requests().len(), 3);
+    }
+
+    #[test]
+    fn free_space() {
+        let dest = tempfile::tempdir().expect("failed to create temp dir");
+        let path = dest.path().join("file");
+
+        check_free_space(&path, 0).expect("check failed");
+
+        let result = check_free_space(&path, u64::MAX);
+        assert!(matches!(
+            result.unwrap_err().downcast_ref::<CloudError>(),
+            Some(CloudError::InsufficientSpace {
+                needed: u64::MAX,
+                ..
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn preallocate_file() {
+        let dest = tempfile::tempdir().expect("failed to create temp dir");
+        let path = dest.path().join("file");
+        std::fs::write(&path, b"data").expect("failed to write file");
+        let file = OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .await
+            .expect("failed to open file");
+
+        preallocate(&file, 1 << 20)
+            .await
+            .expect("preallocate failed");
+        let contents = std::fs::read(&path).expect("failed to read file");
+        assert_eq!(contents.len(), 1 << 20);
+        assert_eq!(&contents[..4], b"data");
+
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::unix::fs::MetadataExt;
+
+            let metadata = std::fs::metadata(&path).expect("failed to stat file");
+            assert!(metadata.blocks() * 512 >= 1 << 20);
+        }
+    }
+
+    #[test]
+    fn copy_source_encoding() {
+        assert_eq!(
+            copy_source("bucket", "machine a/67e55044-10b1-426f-9247-bb680e5fe0c8"),
+            "bucket/machine%20a/67e55044-10b1-426f-9247-bb680e5fe0c8"
+        );
+    }
+
+    #[tokio::test]
+    async fn copy() {
+        let head = canned_response(200, &[("Content-Length", "4")], "");
+        let connection = TestConnection::new(vec![
+            canned_response(404, &[], ""),
+            head,
+            canned_response(
+                200,
+                &[],
+                "<CopyObjectResult><ETag>tag</ETag></CopyObjectResult>",
+            ),
+        ]);
+        let aws = test_aws(connection.clone());
+        let from = StorageId::generate();
+        let to = StorageId::generate();
+
+        s3_copy(&aws, &from, &to).await.expect("copy failed");
+
+        let requests = connection.requests();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(
+            requests[2]
+                .actual
+                .headers()
+                .get("x-amz-copy-source")
+                .map(|v| v.as_bytes()),
+            Some(format!("bucket/{}", from).as_bytes())
+        );
+    }
+
+    #[tokio::test]
+    async fn set_storage_class() {
+        let head = |class: Option<&str>, length: u64| {
+            let length = length.to_string();
+            let mut headers = vec![("Content-Length", length.as_str())];
+
+            if let Some(class) = class {
+                headers.push(("x-amz-storage-class", class));
+            }
+
+            canned_response(200, &headers, "")
+        };
+        let connection = TestConnection::new(vec![
+            head(None, 4),
+            canned_response(
+                200,
+                &[],
+                "<CopyObjectResult><ETag>tag</ETag></CopyObjectResult>",
+            ),
+            head(Some("GLACIER"), 4),
+            head(None, COPY_SINGLE_LIMIT + 1),
+        ]);
+        let aws = test_aws(connection.clone());
+        let storage_id = StorageId::generate();
+
+        s3_set_storage_class(&aws, &storage_id, StorageClass::Glacier)
+            .await
+            .expect("storage class change failed");
+        let requests = connection.requests();
+        let copy = &requests[1].actual;
+        assert_eq!(copy.method(), "PUT");
+        assert_eq!(copy.uri().path(), format!("/bucket/{}", storage_id));
+        assert_eq!(
+            copy.headers()["x-amz-copy-source"],
+            format!("bucket/{}", storage_id).as_str()
+        );
+        assert_eq!(copy.headers()["x-amz-storage-class"], "GLACIER");
+        drop(requests);
+
+        // Already there, nothing is copied.
+        s3_set_storage_class(&aws, &storage_id, StorageClass::Glacier)
+            .await
+            .expect("storage class change failed");
+        assert_eq!(connection.requests().len(), 3);
+
+        let result = s3_set_storage_class(&aws, &storage_id, StorageClass::StandardIa).await;
+        assert!(matches!(result, Err { .. }));
+        assert_eq!(connection.requests().len(), 4);
+    }
+
+    #[tokio::test]
+    async fn probe() {
+        let temp_dir = tempfile::tempdir().expect("failed to create temp dir");
+        let get = canned_response(
+            200,
+            &[("Content-Length", &PROBE_DATA.len().to_string())],
+            std::str::from_utf8(PROBE_DATA).expect("probe data is not utf8"),
+        );
+        let connection = TestConnection::new(vec![
+            canned_response(
+                200, &[],
+                "<InitiateMultipartUploadResult><UploadId>upload</UploadId></InitiateMultipartUploadResult>",
+            ),
+            canned_response(200, &[], ""),
+            canned_response(
+                200, &[],
+                "<CompleteMultipartUploadResult><Key>key</Key></CompleteMultipartUploadResult>",
+            ),
+            get,
+            canned_response(204, &[], ""),
+        ]);
+        let mut aws = test_aws(connection.clone());
+        aws.set_temp_dir(temp_dir.path().to_owned());
+
+        s3_probe(&aws).await.expect("probe failed");
+        assert_eq!(connection.requests().len(), 5);
+        assert_eq!(
+            std::fs::read_dir(temp_dir.path())
+                .expect("failed to read dir")
+                .count(),
+            0
+        );
+    }
+
+    #[tokio::test]
+    async fn upload_object_lock() {
+        let connection = TestConnection::new(vec![
+            canned_response(
+                200, &[],
+                "<InitiateMultipartUploadResult><UploadId>upload</UploadId></InitiateMultipartUploadResult>",
+            ),
+            canned_response(200, &[], ""),
+            canned_response(
+                200, &[],
+                "<CompleteMultipartUploadResult><Key>key</Key></CompleteMultipartUploadResult>",
+            ),
+        ]);
+        let mut aws = test_aws(connection.clone());
+        aws.set_object_lock(ObjectLock {
+            mode: ObjectLockMode::Compliance,
+            retain_days: 30,
+            legal_hold: true,
+        });
+
+        s3_upload_stream(&aws, &mut &b"data"[..])
+            .await
+            .expect("upload failed");
+
+        let requests = connection.requests();
+        let create = requests[0].actual.headers();
+        assert_eq!(create["x-amz-object-lock-mode"], "COMPLIANCE");
+        assert_eq!(create["x-amz-object-lock-legal-hold"], "ON");
+        assert!(create.contains_key("x-amz-object-lock-retain-until-date"));
+        assert!(requests[1].actual.headers().contains_key("content-md5"));
+    }
+
+    #[tokio::test]
+    async fn upload_part_md5() {
+        for checksum in [None, Some(S3Checksum::Md5)] {
+            let connection = TestConnection::new(vec![
+                canned_response(
+                    200, &[],
+                    "<InitiateMultipartUploadResult><UploadId>upload</UploadId></InitiateMultipartUploadResult>",
+                ),
+                canned_response(200, &[], ""),
+                canned_response(
+                    200, &[],
+                    "<CompleteMultipartUploadResult><Key>key</Key></CompleteMultipartUploadResult>",
+                ),
+            ]);
+            let mut aws = test_aws(DrainingConnection(connection.clone()));
+            if let Some(checksum) = checksum {
+                aws.set_s3_checksum(checksum);
+            }
+
+            s3_upload_stream(&aws, &mut &b"data"[..])
+                .await
+                .expect("upload failed");
+
+            let requests = connection.requests();
+            let part = &requests[1].actual;
+            let body = part.body().bytes().expect("part body not drained");
+            match checksum {
+                Some(S3Checksum::Md5) => assert_eq!(
+                    part.headers()["content-md5"],
+                    BASE64_STANDARD.encode(Md5::digest(body)).as_str()
+                ),
+                None => assert!(!part.headers().contains_key("content-md5")),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn delete_locked() {
+        let head = |header, value| canned_response(200, &[(header, value)], "");
+        let connection = TestConnection::new(vec![
+            head(
+                "x-amz-object-lock-retain-until-date",
+                "2099-01-01T00:00:00Z",
+            ),
+            head("x-amz-object-lock-legal-hold", "ON"),
+        ]);
+        let aws = test_aws(connection.clone());
+
+        for _ in 0..2 {
+            let result = s3_delete_file(&aws, &StorageId::generate()).await;
+            assert!(matches!(
+                result.unwrap_err().downcast_ref::<CloudError>(),
+                Some(CloudError::ObjectLocked(_))
+            ));
+        }
+        assert_eq!(connection.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn delete_files() {
+        let connection = TestConnection::new(vec![
+            canned_response(
+                200, &[],
+                "<DeleteResult>\
+                    <Error><Key>67e55044-10b1-426f-9247-bb680e5fe0c8</Key><Code>AccessDenied</Code></Error>\
+                    <Error><Key>67e55044-10b1-426f-9247-bb680e5fe0c8.parts</Key><Code>AccessDenied</Code></Error>\
+                </DeleteResult>",
+            ),
+            canned_response(200, &[], "<DeleteResult></DeleteResult>"),
+        ]);
+        let aws = test_aws(connection.clone());
+        let denied = StorageId::parse("67e55044-10b1-426f-9247-bb680e5fe0c8").expect("valid id");
+
+        // Two batches, each object goes with its part hashes.
+        let mut storage_ids = vec![denied.clone()];
+        storage_ids.extend((0..500).map(|_| StorageId::generate()));
+
+        let results = s3_delete_files(&aws, &storage_ids)
+            .await
+            .expect("delete failed");
+        assert_eq!(results.len(), 501);
+        assert_eq!(results[0].0, denied);
+        assert!(matches!(results[0].1, Err { .. }));
+        assert!(results[1..].iter().all(|(_, result)| result.is_ok()));
+
+        let requests = connection.requests();
+        assert_eq!(requests.len(), 2);
+        let body = std::str::from_utf8(requests[0].actual.body().bytes().expect("no body"))
+            .expect("body is not UTF-8");
+        assert!(body.contains(&format!("<Key>{}.parts</Key>", storage_ids[1].as_str())));
+    }
+
+    #[tokio::test]
+    async fn delete_keeps_versions() {
+        let head = canned_response(
+            200,
+            &[
+                (
+                    "x-amz-object-lock-retain-until-date",
+                    "2000-01-01T00:00:00Z",
+                ),
+                ("x-amz-version-id", "v1"),
+            ],
+            "",
+        );
+        let connection = TestConnection::new(vec![head, canned_response(204, &[], "")]);
+        let aws = test_aws(connection.clone());
+
+        s3_delete_file(&aws, &StorageId::generate())
+            .await
+            .expect("delete failed");
+
+        let requests = connection.requests();
+        assert_eq!(requests[1].actual.method(), "DELETE");
+        assert!(!requests[1]
+            .actual
+            .uri()
+            .query()
+            .is_some_and(|query| query.contains("versionId")));
+    }
+
+    #[tokio::test]
+    async fn session_errors_head() {
+        let skewed = canned_response(403, &[("Date", "Sat, 01 Jan 2000 00:00:00 GMT")], "");
+        let connection = TestConnection::new(vec![
+            canned_response(400, &[], ""),
+            skewed,
+            canned_response(403, &[], ""),
+        ]);
+        let aws = test_aws(connection);
+        let storage_id = StorageId::generate();
+
+        let error = s3_object_exists(&aws, &storage_id).await.unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<CloudError>(),
+            Some(CloudError::CredentialsExpired {
+                operation: "HeadObject",
+                ..
+            })
+        ));
+        // SDK error is kept as the source.
+        assert!(error.chain().count() > 1);
+        assert!(matches!(
+            s3_object_exists(&aws, &storage_id)
+                .await
+                .unwrap_err()
+                .downcast_ref::<CloudError>(),
+            Some(CloudError::ClockSkew {
+                operation: "HeadObject",
+                ..
+            })
+        ));
+        assert!(matches!(
+            s3_object_exists(&aws, &storage_id)
+                .await
+                .unwrap_err()
+                .downcast_ref::<CloudError>(),
+            Some(CloudError::Request { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn session_errors() {
+        let dest = tempfile::tempdir().expect("failed to create temp dir");
+        let connection = TestConnection::new(vec![
+            canned_response(
+                400,
+                &[],
+                "<Error><Code>ExpiredToken</Code><Message>expired</Message></Error>",
+            ),
+            canned_response(
+                403,
+                &[],
+                "<Error><Code>RequestTimeTooSkewed</Code><Message>skewed</Message></Error>",
+            ),
+            canned_response(
+                403,
+                &[],
+                "<Error><Code>SignatureDoesNotMatch</Code><Message>bad key</Message></Error>",
+            ),
+        ]);
+        let aws = test_aws(connection);
+        let path = dest.path().join("part");
+        let storage_id = StorageId::generate();
+        let download = || s3_download_part(&aws, &storage_id, 0, 1, &path, OverwritePolicy::Fail);
+
+        assert!(matches!(
+            download().await.unwrap_err().downcast_ref::<CloudError>(),
+            Some(CloudError::CredentialsExpired {
+                operation: "GetObject",
+                ..
+            })
+        ));
+        assert!(matches!(
+            download().await.unwrap_err().downcast_ref::<CloudError>(),
+            Some(CloudError::ClockSkew {
+                operation: "GetObject",
+                ..
+            })
+        ));
+        assert!(matches!(
+            download().await.unwrap_err().downcast_ref::<CloudError>(),
+            Some(CloudError::Request { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn previous_keys() {
+        init();
+        let object = |fingerprint: Option<&str>| {
+            let mut headers = vec![("Content-Length", "4")];
+
+            if let Some(fingerprint) = fingerprint {
+                headers.push(("x-amz-meta-key-fingerprint", fingerprint));
+            }
+
+            canned_response(200, &headers, "data")
+        };
+        let previous_keys = HashKeys::new(&MasterKey::new().expect("failed to create key"), 0)
+            .expect("failed to derive keys");
+        let previous_fingerprint = key_fingerprint(&previous_keys);
+
+        let mut hash = ChunkedHash::keyed(previous_keys.get(HashKind::File));
+        hash.update(&b"data"[..]);
+        let hash = FileHash::from_bytes(hash.finalize());
+
+        let connection = TestConnection::new(vec![
+            object(None),
+            object(Some(&previous_fingerprint)),
+            object(Some("0123456789abcdef")),
+        ]);
+        let mut aws = test_aws(connection.clone());
+        let current_fingerprint = aws.key_fingerprint();
+        aws.add_previous_key(previous_keys);
+
+        // Objects without a known fingerprint are checked against every key.
+        let manifest: Vec<_> = (0..3)
+            .map(|_| (StorageId::generate(), hash.clone(), FileSize { size: 4 }))
+            .collect();
+        let results = s3_verify_all(&aws, &manifest, 1)
+            .await
+            .expect("verify failed");
+        assert!(results
+            .iter()
+            .all(|(_, result)| matches!(result, Ok(VerifyResult::Ok))));
+
+        let connection = TestConnection::new(vec![object(Some(&current_fingerprint))]);
+        let mut aws = test_aws(connection);
+        aws.add_previous_key(
+            HashKeys::new(&MasterKey::new().expect("failed to create key"), 0)
+                .expect("failed to derive keys"),
+        );
+
+        let results = s3_verify_all(&aws, &manifest[..1], 1)
+            .await
+            .expect("verify failed");
+        assert!(matches!(
+            results[..],
+            [(_, Ok(VerifyResult::HashMismatch { .. }))]
+        ));
+    }
+
+    #[tokio::test]
+    async fn verify_all_missing() {
+        let object = || canned_response(200, &[("Content-Length", "4")], "data");
+        let connection = TestConnection::new(vec![
+            object(),
+            canned_response(404, &[], ""),
+            canned_response(403, &[], ""),
+            object(),
+        ]);
+        let aws = test_aws(connection);
+
+        let mut hash = ChunkedHash::keyed(aws.hash_key(HashKind::File));
+        hash.update(&b"data"[..]);
+        let hash = FileHash::from_bytes(hash.finalize());
+        let manifest: Vec<_> = (0..4)
+            .map(|_| (StorageId::generate(), hash.clone(), FileSize { size: 4 }))
+            .collect();
+
+        // Missing and unreadable objects are reported along with the rest.
+        let results = s3_verify_all(&aws, &manifest, 1)
+            .await
+            .expect("verify failed");
+        assert!(matches!(
+            results[..],
+            [
+                (_, Ok(VerifyResult::Ok)),
+                (_, Ok(VerifyResult::Missing)),
+                (_, Err(_)),
+                (_, Ok(VerifyResult::Ok)),
+            ]
+        ));
+        assert!(results
+            .iter()
+            .zip(&manifest)
+            .all(|((result_id, _), (storage_id, _, _))| result_id == storage_id));
+    }
+
+    #[tokio::test]
+    async fn verify_file() {
+        let object = |length, body| canned_response(200, &[("Content-Length", length)], body);
+        let connection = TestConnection::new(vec![
+            object("4", "data"),
+            object("4", "date"),
+            object("5", "data!"),
+            object("4", "da"),
+        ]);
+        let aws = test_aws(connection.clone());
+        let storage_id = StorageId::generate();
+        let size = FileSize { size: 4 };
+
+        let mut hash = ChunkedHash::keyed(aws.hash_key(HashKind::File));
+        hash.update(&b"data"[..]);
+        let hash = FileHash::from_bytes(hash.finalize());
+
+        let result = s3_verify_file(&aws, &storage_id, &hash, &size).await;
+        assert!(matches!(result, Ok(VerifyResult::Ok)));
+        let result = s3_verify_file(&aws, &storage_id, &hash, &size).await;
+        assert!(matches!(result, Ok(VerifyResult::HashMismatch { .. })));
+        let result = s3_verify_file(&aws, &storage_id, &hash, &size).await;
+        assert!(matches!(
+            result,
+            Ok(VerifyResult::SizeMismatch {
+                actual: FileSize { size: 5 },
+                ..
+            })
+        ));
+
+        // Short body is a size mismatch, nothing is requested by range.
+        let result = s3_verify_file(&aws, &storage_id, &hash, &size).await;
+        assert!(matches!(
+            result,
+            Ok(VerifyResult::SizeMismatch {
+                actual: FileSize { size: 2 },
+                ..
+            })
+        ));
+        let requests = connection.requests();
+        assert_eq!(requests.len(), 4);
+        assert!(requests
+            .iter()
+            .all(|request| !request.actual.headers().contains_key("range")));
+    }
+
+    #[tokio::test]
+    async fn download_wrong_key() {
+        let object = |fingerprint: Option<&str>| {
+            let mut headers = vec![("Content-Length", "4")];
+
+            if let Some(fingerprint) = fingerprint {
+                headers.push(("x-amz-meta-key-fingerprint", fingerprint));
+            }
+
+            canned_response(200, &headers, "data")
+        };
+        let previous_keys = HashKeys::new(&MasterKey::new().expect("failed to create key"), 0)
+            .expect("failed to derive keys");
+        let connection = TestConnection::new(vec![
+            object(Some("0123456789abcdef")),
+            object(Some(&key_fingerprint(&previous_keys))),
+            object(None),
+        ]);
+        let mut aws = test_aws(connection);
+        aws.add_previous_key(previous_keys);
+        let dir = tempfile::tempdir().expect("failed to create temp dir");
+        let path = dir.path().join("file");
+        let hash = FileHash::from_bytes([0; 32]);
+        let size = FileSize { size: 4 };
+
+        let error = s3_download_file(
+            &aws,
+            StorageId::generate(),
+            &hash,
+            &size,
+            &path,
+            OverwritePolicy::Fail,
+        )
+        .await
+        .expect_err("download succeeded");
+        assert!(matches!(
+            error.downcast_ref::<CloudError>(),
+            Some(CloudError::WrongKey { fingerprint, .. }) if fingerprint == "0123456789abcdef"
+        ));
+
+        // Under a configured key, or not recorded, it may as well be damage.
+        for _ in 0..2 {
+            let error = s3_download_file(
+                &aws,
+                StorageId::generate(),
+                &hash,
+                &size,
+                &path,
+                OverwritePolicy::Fail,
+            )
+            .await
+            .expect_err("download succeeded");
+            assert!(error.downcast_ref::<CloudError>().is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn upload_key_fingerprint() {
+        let connection = TestConnection::new(vec![
+            canned_response(
+                200, &[],
+                "<InitiateMultipartUploadResult><UploadId>upload</UploadId></InitiateMultipartUploadResult>",
+            ),
+            canned_response(200, &[], ""),
+            canned_response(
+                200, &[],
+                "<CompleteMultipartUploadResult><Key>key</Key></CompleteMultipartUploadResult>",
+            ),
+        ]);
+        let aws = test_aws(connection.clone());
+
+        s3_upload_stream(&aws, &mut &b"data"[..])
+            .await
+            .expect("upload failed");
+
+        let requests = connection.requests();
+        assert_eq!(
+            requests[0].actual.headers()["x-amz-meta-key-fingerprint"],
+            aws.key_fingerprint().as_str()
+        );
+    }
+
+    #[tokio::test]
+    async fn presign() {
+        let connection = TestConnection::<&str>::new(vec![]);
+        let aws = test_aws(connection.clone());
+        let storage_id = StorageId::generate();
+
+        let get = s3_presign_get(&aws, &storage_id, Duration::from_secs(60))
+            .await
+            .expect("failed to presign get");
+        let put = s3_presign_put(&aws, &storage_id, Duration::from_secs(3600))
+            .await
+            .expect("failed to presign put");
+        assert!(get.headers.is_empty());
+        let (get, put) = (get.url, put.url);
+
+        for (url, expires) in [(&get, "X-Amz-Expires=60"), (&put, "X-Amz-Expires=3600")] {
+            assert!(url.contains(&format!("/bucket/{}?", storage_id)), "{}", url);
+            assert!(url.contains(expires), "{}", url);
+            assert!(url.contains("X-Amz-Signature="), "{}", url);
+        }
+        assert!(!get.contains("x-id=PutObject"));
+        assert!(put.contains("x-id=PutObject"));
+
+        // Over the 7 day maximum.
+        let result = s3_presign_get(&aws, &storage_id, Duration::from_secs(8 * 24 * 60 * 60)).await;
+        assert!(matches!(result, Err { .. }));
+        assert_eq!(connection.requests().len(), 0);
+
+        // Signed as a header, which has to be sent with the URL.
+        let mut aws = test_aws(TestConnection::<&str>::new(vec![]));
+        aws.set_request_payer();
+        let payer = vec![("x-amz-request-payer".to_owned(), "requester".to_owned())];
+        let get = s3_presign_get(&aws, &storage_id, Duration::from_secs(60))
+            .await
+            .expect("failed to presign get");
+        assert_eq!(get.headers, payer);
+        assert!(get.url.contains("x-amz-request-payer"), "{}", get.url);
+        let put = s3_presign_put(&aws, &storage_id, Duration::from_secs(60))
+            .await
+            .expect("failed to presign put");
+        assert_eq!(put.headers, payer);
+    }
+
+    #[tokio::test]
+    async fn upload_object_headers() {
+        let file = tempfile::NamedTempFile::new().expect("failed to create temp file");
+        std::fs::write(file.path(), b"data").expect("failed to write temp file");
+        let connection = TestConnection::new(vec![
+            canned_response(
+                200, &[],
+                "<InitiateMultipartUploadResult><UploadId>upload</UploadId></InitiateMultipartUploadResult>",
+            ),
+            canned_response(200, &[], ""),
+            canned_response(
+                200, &[],
+                "<CompleteMultipartUploadResult><Key>key</Key></CompleteMultipartUploadResult>",
+            ),
+            canned_response(200, &[], ""),
+        ]);
+        let mut aws = test_aws(DrainingConnection(connection.clone()));
+        aws.set_object_headers("max-age=86400", "attachment");
+
+        s3_upload_stream(&aws, &mut &b"data"[..])
+            .await
+            .expect("upload failed");
+        aws.set_force_single_put();
+        s3_upload_file(&aws, file.path())
+            .await
+            .expect("upload failed");
+
+        let requests = connection.requests();
+        for request in [&requests[0], &requests[3]] {
+            let headers = request.actual.headers();
+            assert_eq!(headers["cache-control"], "max-age=86400");
+            assert_eq!(headers["content-disposition"], "attachment");
+        }
+        assert!(!requests[1].actual.headers().contains_key("cache-control"));
+    }
+
+    #[tokio::test]
+    async fn rewrap() {
+        init();
+        let previous_key = MasterKey::new().expect("failed to create key");
+        let previous_keys = || HashKeys::new(&previous_key, 0).expect("failed to derive keys");
+        let previous_fingerprint = key_fingerprint(&previous_keys());
+        let object = || {
+            canned_response(
+                200,
+                &[
+                    ("Content-Length", "4"),
+                    ("x-amz-meta-key-fingerprint", previous_fingerprint.as_str()),
+                ],
+                "data",
+            )
+        };
+        let upload_started = || {
+            canned_response(
+            200, &[],
+                "<InitiateMultipartUploadResult><UploadId>upload</UploadId></InitiateMultipartUploadResult>",
+            )
+        };
+
+        let mut hash = ChunkedHash::keyed(previous_keys().get(HashKind::File));
+        hash.update(&b"data"[..]);
+        let previous_hash = FileHash::from_bytes(hash.finalize());
+
+        let connection = TestConnection::new(vec![
+            object(),
+            upload_started(),
+            canned_response(200, &[], ""),
+            canned_response(
+                200,
+                &[],
+                "<CompleteMultipartUploadResult><Key>key</Key></CompleteMultipartUploadResult>",
+            ),
+        ]);
+        let mut aws = test_aws(DrainingConnection(connection.clone()));
+        aws.add_previous_key(previous_keys());
+        let storage_id = StorageId::generate();
+
+        let UploadReceipt {
+            storage_id: new_id,
+            hash: new_hash,
+            ..
+        } = s3_rewrap(&aws, &storage_id, &previous_hash, &FileSize { size: 4 })
+            .await
+            .expect("rewrap failed");
+        assert_ne!(new_id, storage_id);
+
+        let mut hash = ChunkedHash::keyed(aws.hash_key(HashKind::File));
+        hash.update(&b"data"[..]);
+        assert_eq!(new_hash, FileHash::from_bytes(hash.finalize()));
+
+        let requests = connection.requests();
+        assert_eq!(
+            requests[1].actual.headers()["x-amz-meta-key-fingerprint"],
+            aws.key_fingerprint().as_str()
+        );
+        assert_eq!(requests[2].actual.body().bytes(), Some(&b"data"[..]));
+
+        // Data not matching the expected hash is never committed.
+        let connection = TestConnection::new(vec![
+            object(),
+            upload_started(),
+            canned_response(204, &[], ""),
+        ]);
+        let aws = test_aws(connection.clone());
+
+        let result = s3_rewrap(&aws, &storage_id, &previous_hash, &FileSize { size: 4 }).await;
+        assert!(matches!(result, Err { .. }));
+
+        let requests = connection.requests();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[2].actual.method(), http::Method::DELETE);
+
+        // Streamed source doesn't keep its slot from the parts, even at the lowest limit with
+        // another transfer holding the other one.
+        let connection = TestConnection::new(vec![
+            object(),
+            upload_started(),
+            canned_response(200, &[], ""),
+            canned_response(
+                200,
+                &[],
+                "<CompleteMultipartUploadResult><Key>key</Key></CompleteMultipartUploadResult>",
+            ),
+        ]);
+        let limit = Arc::new(AdaptiveLimit::new(2, 2));
+        let _other = limit.acquire().await;
+        let mut aws = test_aws(Throttled::new(DrainingConnection(connection), limit));
+        aws.add_previous_key(previous_keys());
+
+        tokio::time::timeout(
+            Duration::from_secs(10),
+            s3_rewrap(&aws, &storage_id, &previous_hash, &FileSize { size: 4 }),
+        )
+        .await
+        .expect("rewrap stuck")
+        .expect("rewrap failed");
+    }
+
+    // Keys partitioned by upload day.
+    struct DayNamer;
+
+    impl KeyNamer for DayNamer {
+        fn key_for(&self, storage_id: &StorageId, meta: &UploadMeta) -> String {
+            let day = meta
+                .uploaded
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .expect("clock before epoch")
+                .as_secs()
+                / (24 * 60 * 60);
+
+            format!("{}/{}", day, storage_id.as_str())
+        }
+
+        fn storage_id(&self, key: &str) -> Option<StorageId> {
+            StorageId::parse(key.split_once('/')?.1).ok()
+        }
+    }
+
+    #[tokio::test]
+    async fn key_namer() {
+        let connection = TestConnection::new(vec![
+            canned_response(
+                200, &[],
+                "<InitiateMultipartUploadResult><UploadId>upload</UploadId></InitiateMultipartUploadResult>",
+            ),
+            canned_response(200, &[], ""),
+            canned_response(
+                200, &[],
+                "<CompleteMultipartUploadResult><Key>key</Key></CompleteMultipartUploadResult>",
+            ),
+        ]);
+        let mut aws = test_aws(connection.clone());
+        aws.set_key_namer(DayNamer);
+
+        let UploadReceipt {
+            storage_id,
+            object_key,
+            ..
+        } = s3_upload_stream(&aws, &mut &b"data"[..])
+            .await
+            .expect("upload failed");
+
+        // New key is chosen once, every request of the upload uses it and the receipt has it.
+        let key = DayNamer.key_for(
+            &storage_id,
+            &UploadMeta {
+                uploaded: SystemTime::now(),
+            },
+        );
+        let requests = connection.requests();
+        for request in requests.iter() {
+            assert_eq!(request.actual.uri().path(), format!("/bucket/{}", key));
+        }
+        assert_eq!(object_key, Some(key));
+
+        // Provider that didn't upload the object uses the recorded key.
+        let connection = TestConnection::new(vec![canned_response(204, &[], "")]);
+        let mut aws = test_aws(connection.clone());
+        aws.set_key_namer(DayNamer);
+
+        let storage_id =
+            StorageId::parse("67e55044-10b1-426f-9247-bb680e5fe0c8").expect("invalid id");
+        aws.add_object_keys(&BTreeMap::from([(
+            storage_id.clone(),
+            "19000/67e55044-10b1-426f-9247-bb680e5fe0c8".to_owned(),
+        )]));
+        s3_delete_object(&aws, &storage_id)
+            .await
+            .expect("delete failed");
+        assert_eq!(
+            connection.requests()[0].actual.uri().path(),
+            "/bucket/19000/67e55044-10b1-426f-9247-bb680e5fe0c8"
+        );
+
+        // Unrecorded keys are not looked for in the bucket.
+        let result = s3_delete_object(&aws, &StorageId::generate()).await;
+        assert!(matches!(result, Err { .. }));
+        assert_eq!(connection.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn key_namer_existing() {
+        let connection = TestConnection::new(vec![canned_response(200, &[], "")]);
+        let mut aws = test_aws(connection.clone());
+        aws.set_key_namer(DayNamer);
+        let from = StorageId::parse("67e55044-10b1-426f-9247-bb680e5fe0c8").expect("invalid id");
+        let to = StorageId::parse("4b8a4a2e-3c3e-4c5f-9a37-2a6f7e1b9d10").expect("invalid id");
+        aws.add_object_keys(&BTreeMap::from([
+            (
+                from.clone(),
+                "19000/67e55044-10b1-426f-9247-bb680e5fe0c8".to_owned(),
+            ),
+            (
+                to.clone(),
+                "19001/4b8a4a2e-3c3e-4c5f-9a37-2a6f7e1b9d10".to_owned(),
+            ),
+        ]));
+
+        // Target exists under the key it was stored with, not the one a new object would get.
+        let result = s3_copy(&aws, &from, &to).await;
+        assert!(matches!(
+            result.unwrap_err().downcast_ref::<CloudError>(),
+            Some(CloudError::AlreadyExists(key)) if key == "19001/4b8a4a2e-3c3e-4c5f-9a37-2a6f7e1b9d10"
+        ));
+        let requests = connection.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(
+            requests[0].actual.uri().path(),
+            "/bucket/19001/4b8a4a2e-3c3e-4c5f-9a37-2a6f7e1b9d10"
+        );
+
+        // Failed attempt left the known key alone.
+        assert_eq!(
+            aws.object_key(&to).expect("key not found"),
+            "19001/4b8a4a2e-3c3e-4c5f-9a37-2a6f7e1b9d10"
+        );
+    }
+
+    // Reads streamed request bodies before answering, like a server would. TestConnection
+    // leaves them unread.
+    #[derive(Clone)]
+    struct DrainingConnection(TestConnection<&'static str>);
+
+    impl tower::Service<http::Request<SdkBody>> for DrainingConnection {
+        type Response = http::Response<SdkBody>;
+        type Error = ConnectorError;
+        type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, request: http::Request<SdkBody>) -> Self::Future {
+            let mut connection = self.0.clone();
+
+            Box::pin(async move {
+                let (parts, body) = request.into_parts();
+                let body = hyper::body::to_bytes(body)
+                    .await
+                    .map_err(|e| ConnectorError::other(e, None))?;
+
+                connection
+                    .call(http::Request::from_parts(parts, SdkBody::from(body)))
+                    .await
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn upload_single_put() {
+        let file = tempfile::NamedTempFile::new().expect("failed to create temp file");
+        std::fs::write(file.path(), b"data").expect("failed to write temp file");
+        let connection = TestConnection::new(vec![canned_response(200, &[], "")]);
+        let mut aws = test_aws(DrainingConnection(connection.clone()));
+        aws.set_force_single_put();
+
+        let mut hash = ChunkedHash::keyed(aws.hash_key(HashKind::File));
+        hash.update(&b"data"[..]);
+
+        let UploadReceipt {
+            size,
+            hash: uploaded_hash,
+            ..
+        } = s3_upload_file(&aws, file.path())
+            .await
+            .expect("upload failed");
+        assert_eq!(size.size, 4);
+        assert_eq!(uploaded_hash, FileHash::from_bytes(hash.finalize()));
+
+        let requests = connection.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].actual.method(), "PUT");
+        assert_eq!(requests[0].actual.headers()["content-length"], "4");
+        assert_eq!(requests[0].actual.uri().query(), Some("x-id=PutObject"));
+
+        let result = s3_upload_stream(&aws, &mut &b"data"[..]).await;
+        assert!(matches!(result, Err { .. }));
+    }
+
+    #[tokio::test]
+    async fn request_payer() {
+        let upload = || {
+            vec![
+                canned_response(
+                    200, &[],
+                    "<InitiateMultipartUploadResult><UploadId>upload</UploadId></InitiateMultipartUploadResult>",
+                ),
+                canned_response(200, &[], ""),
+                canned_response(
+                    200, &[],
+                    "<CompleteMultipartUploadResult><Key>key</Key></CompleteMultipartUploadResult>",
+                ),
+            ]
+        };
+
+        let connection = TestConnection::new(upload());
+        let mut aws = test_aws(connection.clone());
+        aws.set_request_payer();
+        s3_upload_stream(&aws, &mut &b"data"[..])
+            .await
+            .expect("upload failed");
+        let requests = connection.requests();
+        assert_eq!(requests.len(), 3);
+        assert!(requests
+            .iter()
+            .all(|request| request.actual.headers()["x-amz-request-payer"] == "requester"));
+        drop(requests);
+
+        // Owner's own buckets don't need it.
+        let connection = TestConnection::new(upload());
+        let aws = test_aws(connection.clone());
+        s3_upload_stream(&aws, &mut &b"data"[..])
+            .await
+            .expect("upload failed");
+        assert!(connection
+            .requests()
+            .iter()
+            .all(|request| !request.actual.headers().contains_key("x-amz-request-payer")));
+    }
+
+    #[tokio::test]
+    async fn upload_receipt() {
+        let complete = canned_response(
+            200,
+            &[("x-amz-version-id", "v1")],
+            "<CompleteMultipartUploadResult><ETag>\"tag\"</ETag></CompleteMultipartUploadResult>",
+        );
+        let connection = TestConnection::new(vec![
+            canned_response(
+                200, &[],
+                "<InitiateMultipartUploadResult><UploadId>upload</UploadId></InitiateMultipartUploadResult>",
+            ),
+            canned_response(200, &[], ""),
+            complete,
+        ]);
+        let aws = test_aws(connection);
+
+        let receipt = s3_upload_stream(&aws, &mut &b"data"[..])
+            .await
+            .expect("upload failed");
+        assert_eq!(receipt.size.size, 4);
+        assert_eq!(receipt.etag.as_deref(), Some("\"tag\""));
+        assert_eq!(receipt.version_id.as_deref(), Some("v1"));
+        assert_eq!((receipt.part_count, receipt.part_size), (1, CHUNK_SIZE));
+
+        // Single put has them as response headers, unversioned bucket returns no version.
+        let put = canned_response(200, &[("etag", "\"single\"")], "");
+        let file = tempfile::NamedTempFile::new().expect("failed to create temp file");
+        std::fs::write(file.path(), b"data").expect("failed to write temp file");
+        let connection = TestConnection::new(vec![put]);
+        let mut aws = test_aws(DrainingConnection(connection));
+        aws.set_force_single_put();
+
+        let receipt = s3_upload_file(&aws, file.path())
+            .await
+            .expect("upload failed");
+        assert_eq!(receipt.etag.as_deref(), Some("\"single\""));
+        assert_eq!(receipt.version_id, None);
+        assert_eq!((receipt.part_count, receipt.part_size), (1, 4));
+    }
+
+    #[tokio::test]
+    async fn upload_events() {
+        let file = tempfile::NamedTempFile::new().expect("failed to create temp file");
+        std::fs::write(file.path(), b"data").expect("failed to write temp file");
+        let connection = TestConnection::new(vec![
+            canned_response(
+                200, &[],
+                "<InitiateMultipartUploadResult><UploadId>upload</UploadId></InitiateMultipartUploadResult>",
+            ),
+            canned_response(200, &[], ""),
+            canned_response(
+                200, &[],
+                "<CompleteMultipartUploadResult><Key>key</Key></CompleteMultipartUploadResult>",
+            ),
+        ]);
+        let aws = test_aws(connection);
+
+        let (upload, events) = aws.upload_file_with_events(file.path());
+        upload.await.expect("upload failed");
+
+        let events: Vec<_> = events.collect().await;
+        assert_eq!(
+            events,
+            [
+                TransferEvent::PartStarted { part: 1 },
+                TransferEvent::PartCompleted { part: 1, bytes: 4 },
+                TransferEvent::Completed,
+            ]
+        );
+    }
 }
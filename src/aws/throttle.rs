@@ -0,0 +1,356 @@
+use aws_smithy_http::body::SdkBody;
+use futures::stream;
+use hyper::body::HttpBody;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use tokio::sync::Notify;
+use tracing::trace;
+
+// Concurrent requests allowed, adjusted AIMD style: halved when S3 answers with SlowDown, raised
+// by one after as many successful requests as the limit. Only the first SlowDown of requests
+// started under the same limit counts, so a burst of them from one overload halves it once.
+pub(crate) struct AdaptiveLimit {
+    min: usize,
+    max: usize,
+    state: Mutex<LimitState>,
+    released: Notify,
+}
+
+struct LimitState {
+    limit: usize,
+    in_flight: usize,
+    successes: usize,
+    // Advanced on each decrease.
+    generation: u64,
+}
+
+impl AdaptiveLimit {
+    // Starts at the upper bound, nothing waits until S3 pushes back.
+    pub(crate) fn new(min: usize, max: usize) -> AdaptiveLimit {
+        AdaptiveLimit {
+            min,
+            max,
+            state: Mutex::new(LimitState {
+                limit: max,
+                in_flight: 0,
+                successes: 0,
+                generation: 0,
+            }),
+            released: Notify::new(),
+        }
+    }
+
+    pub(crate) async fn acquire(self: &Arc<Self>) -> Permit {
+        loop {
+            // Created before checking, so a release in between still wakes it.
+            let released = self.released.notified();
+
+            if let Some(permit) = self.try_acquire() {
+                return permit;
+            }
+
+            released.await;
+        }
+    }
+
+    fn try_acquire(self: &Arc<Self>) -> Option<Permit> {
+        let mut state = self.state.lock().expect("poisoned lock");
+
+        if state.in_flight >= state.limit {
+            return None;
+        }
+
+        state.in_flight += 1;
+
+        Some(Permit {
+            limit: self.clone(),
+            generation: state.generation,
+        })
+    }
+}
+
+#[cfg(test)]
+impl AdaptiveLimit {
+    fn limit(&self) -> usize {
+        self.state.lock().expect("poisoned lock").limit
+    }
+}
+
+// Slot for one request, released on drop. Requests that failed otherwise don't change the limit.
+pub(crate) struct Permit {
+    limit: Arc<AdaptiveLimit>,
+    generation: u64,
+}
+
+impl Permit {
+    pub(crate) fn succeeded(&self) {
+        let mut state = self.limit.state.lock().expect("poisoned lock");
+        state.successes += 1;
+
+        if state.successes >= state.limit && state.limit < self.limit.max {
+            state.limit += 1;
+            state.successes = 0;
+        }
+    }
+
+    // Cut to half of what was actually running, a limit far above the load would take several
+    // halvings to have any effect.
+    pub(crate) fn throttled(&self) {
+        let mut state = self.limit.state.lock().expect("poisoned lock");
+
+        if state.generation == self.generation {
+            state.limit = (state.limit.min(state.in_flight) / 2).max(self.limit.min);
+            state.successes = 0;
+            state.generation += 1;
+            trace!(limit = state.limit, "throttled, reducing concurrency");
+        }
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.limit.state.lock().expect("poisoned lock").in_flight -= 1;
+        self.limit.released.notify_waiters();
+    }
+}
+
+// Connection passing each request through an AdaptiveLimit. It sits below the SDK, so SDK
+// retries wait for a slot too and their SlowDown responses count. This limits parts, ranges
+// and files alike, each of them is a request. Slot is held until the response body is read to
+// the end or dropped, so ranged downloads take one for as long as they run. Whole or open ended
+// GETs release it as headers arrive: their bodies are read at the pace of the consumer, which
+// may need slots of its own meanwhile (rewrap uploads parts of the object it streams).
+#[derive(Clone)]
+pub(crate) struct Throttled<C> {
+    inner: C,
+    limit: Arc<AdaptiveLimit>,
+}
+
+impl<C> Throttled<C> {
+    pub(crate) fn new(inner: C, limit: Arc<AdaptiveLimit>) -> Throttled<C> {
+        Throttled { inner, limit }
+    }
+}
+
+impl<C, B> tower::Service<http::Request<B>> for Throttled<C>
+where
+    C: tower::Service<http::Request<B>, Response = http::Response<SdkBody>>
+        + Clone
+        + Send
+        + 'static,
+    C::Future: Send,
+    B: Send + 'static,
+{
+    type Response = C::Response;
+    type Error = C::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: http::Request<B>) -> Self::Future {
+        // Connection polled ready is the one to call, the clone stays for the next request.
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let limit = self.limit.clone();
+        let streamed = is_streamed(&request);
+
+        Box::pin(async move {
+            let permit = limit.acquire().await;
+            let result = inner.call(request).await;
+
+            // S3 sends SlowDown as 503, other 5xx are failures unrelated to load.
+            match &result {
+                Ok(response) if response.status() == http::StatusCode::SERVICE_UNAVAILABLE => {
+                    permit.throttled()
+                }
+                Ok(response) if !response.status().is_server_error() => permit.succeeded(),
+                _ => (),
+            }
+
+            if streamed {
+                return result;
+            }
+
+            result.map(|response| {
+                let (parts, body) = response.into_parts();
+                http::Response::from_parts(parts, hold_permit(body, permit))
+            })
+        })
+    }
+}
+
+// GET of the whole object or all of it from an offset, as sequential downloads and rewrap do.
+fn is_streamed<B>(request: &http::Request<B>) -> bool {
+    if request.method() != http::Method::GET {
+        return false;
+    }
+
+    match request.headers().get(http::header::RANGE) {
+        Some(range) => range.as_bytes().ends_with(b"-"),
+        None => true,
+    }
+}
+
+// Body keeping the permit until it ends, or is dropped unfinished. Empty bodies release it
+// right away.
+fn hold_permit(body: SdkBody, permit: Permit) -> SdkBody {
+    if body.is_end_stream() {
+        return body;
+    }
+
+    let body = stream::unfold((body, permit), |(mut body, permit)| async move {
+        let data = body.data().await?;
+        Some((data, (body, permit)))
+    });
+
+    SdkBody::from(hyper::Body::wrap_stream(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::aws::throttle::{AdaptiveLimit, Throttled};
+    use aws_sdk_s3::{Credentials, Region, RetryConfig};
+    use aws_smithy_client::test_connection::TestConnection;
+    use aws_smithy_http::body::SdkBody;
+    use hyper::body::HttpBody;
+    use std::sync::Arc;
+    use tower::Service;
+
+    #[tokio::test]
+    async fn adaptive_limit() {
+        let limit = Arc::new(AdaptiveLimit::new(2, 8));
+        let mut permits = Vec::new();
+        for _ in 0..6 {
+            permits.push(limit.acquire().await);
+        }
+
+        // Halved from what was running, the rest of the same burst doesn't count.
+        permits.pop().expect("no permit").throttled();
+        assert_eq!(limit.limit(), 3);
+        permits.pop().expect("no permit").throttled();
+        assert_eq!(limit.limit(), 3);
+        assert!(limit.try_acquire().is_none());
+
+        // Never below the lower bound.
+        permits.truncate(2);
+        limit.acquire().await.throttled();
+        assert_eq!(limit.limit(), 2);
+
+        // Waiter gets the slot once one is released.
+        let waiter = tokio::spawn({
+            let limit = limit.clone();
+            async move { limit.acquire().await.succeeded() }
+        });
+        tokio::task::yield_now().await;
+        assert!(!waiter.is_finished());
+        permits.pop().expect("no permit").succeeded();
+        waiter.await.expect("waiter failed");
+        assert_eq!(limit.limit(), 3);
+
+        // Grows back one at a time, up to the upper bound.
+        permits.clear();
+        for _ in 0..100 {
+            limit.acquire().await.succeeded();
+        }
+        assert_eq!(limit.limit(), 8);
+    }
+
+    #[tokio::test]
+    async fn throttled_connection() {
+        let response = |status| {
+            (
+                http::Request::builder()
+                    .body(SdkBody::empty())
+                    .expect("failed to build request"),
+                http::Response::builder()
+                    .status(status)
+                    .body("")
+                    .expect("failed to build response"),
+            )
+        };
+        let connection = TestConnection::new(vec![response(503), response(500), response(200)]);
+        let limit = Arc::new(AdaptiveLimit::new(1, 4));
+        let config = aws_sdk_s3::Config::builder()
+            .region(Region::new("us-east-1"))
+            .credentials_provider(Credentials::new("keyid", "secret", None, None, "test"))
+            .retry_config(RetryConfig::disabled())
+            .build();
+        let client =
+            aws_sdk_s3::Client::from_conf_conn(config, Throttled::new(connection, limit.clone()));
+        let head = || client.head_bucket().bucket("bucket").send();
+
+        assert!(matches!(head().await, Err { .. }));
+        assert_eq!(limit.limit(), 1);
+        assert!(matches!(head().await, Err { .. }));
+        assert_eq!(limit.limit(), 1);
+        head().await.expect("request failed");
+        assert_eq!(limit.limit(), 2);
+    }
+
+    #[tokio::test]
+    async fn body_holds_permit() {
+        let exchange = |body| {
+            (
+                http::Request::builder()
+                    .body(SdkBody::empty())
+                    .expect("failed to build request"),
+                http::Response::builder()
+                    .status(200)
+                    .body(body)
+                    .expect("failed to build response"),
+            )
+        };
+        let connection = TestConnection::new(vec![
+            exchange("data"),
+            exchange(""),
+            exchange("data"),
+            exchange("data"),
+        ]);
+        let limit = Arc::new(AdaptiveLimit::new(1, 1));
+        let mut throttled = Throttled::new(connection, limit.clone());
+        let request = |range| {
+            http::Request::builder()
+                .header("Range", range)
+                .body(SdkBody::empty())
+                .expect("failed to build request")
+        };
+
+        // Slot stays taken while the range is streamed.
+        let mut body = throttled
+            .call(request("bytes=0-3"))
+            .await
+            .expect("request failed")
+            .into_body();
+        assert!(limit.try_acquire().is_none());
+        while let Some(data) = body.data().await {
+            data.expect("failed to read body");
+        }
+        assert!(limit.try_acquire().is_some());
+
+        let _empty = throttled
+            .call(request("bytes=4-7"))
+            .await
+            .expect("request failed");
+        assert!(limit.try_acquire().is_some());
+
+        // Not for open ended ones, their reader may be waiting for a slot itself.
+        let _open = throttled
+            .call(request("bytes=4-"))
+            .await
+            .expect("request failed");
+        assert!(limit.try_acquire().is_some());
+        let _whole = throttled
+            .call(
+                http::Request::builder()
+                    .body(SdkBody::empty())
+                    .expect("failed to build request"),
+            )
+            .await
+            .expect("request failed");
+        assert!(limit.try_acquire().is_some());
+    }
+}
@@ -0,0 +1,382 @@
+use crate::azure::Azure;
+use crate::crypto::hash::{ChunkedHash, HashKind};
+use crate::error::{write_error, CloudError};
+use crate::provider::{
+    fill_buffer, try_join_buffered, DownloadTarget, FileHash, FileSize, OverwritePolicy, StorageId,
+    UploadReceipt, VerifyResult, READ_SIZE,
+};
+use anyhow::{anyhow, Context, Result};
+use azure_core::StatusCode;
+use azure_storage_blobs::blob::{BlobBlockType, BlockList};
+use azure_storage_blobs::prelude::BlobClient;
+use bytes::BytesMut;
+use futures::stream::{self, BoxStream, StreamExt, TryStreamExt};
+use tokio::fs::{remove_file, File};
+use tokio::io::{AsyncRead, AsyncWriteExt};
+use tracing::{error, instrument, trace, Span};
+
+// Same part size as S3 uploads, Azure allows up to 4000MiB per block and 50000 blocks.
+const CHUNK_SIZE: usize = 100 * 1024 * 1024;
+
+// Block ids must have the same length within a blob.
+fn block_id(blocknum: u32) -> String {
+    format!("{:08}", blocknum)
+}
+
+#[instrument(skip(azure))]
+pub async fn blob_connect_check(azure: &Azure) -> Result<()> {
+    let container_client = azure.container_client();
+    let result = container_client.get_properties().await;
+    let container = container_client.container_name().to_owned();
+
+    match result {
+        Ok(_) => Ok(()),
+        Err(e) => match e.as_http_error().map(|e| e.status()) {
+            Some(StatusCode::NotFound) => Err(CloudError::BucketNotFound(container).into()),
+            Some(StatusCode::Forbidden) => Err(CloudError::AccessDenied(container).into()),
+            _ => Err(e.into()),
+        },
+    }
+}
+
+#[instrument(skip(azure))]
+pub async fn blob_upload_file(azure: &Azure, path: &std::path::Path) -> Result<UploadReceipt> {
+    let mut file = File::open(path)
+        .await
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+    file.set_max_buf_size(READ_SIZE);
+
+    blob_upload_stream(azure, &mut file).await
+}
+
+#[instrument(skip(azure, source), fields(storage_id, hash))]
+pub async fn blob_upload_stream(
+    azure: &Azure,
+    source: &mut (dyn AsyncRead + Unpin + Send),
+) -> Result<UploadReceipt> {
+    let storage_id = StorageId::generate();
+    Span::current().record("storage_id", storage_id.as_str());
+
+    trace!("uploading file");
+
+    let blob_client = azure.blob_client(&storage_id);
+    let mut filesize = 0;
+    let mut hash = ChunkedHash::keyed(azure.hash_key(HashKind::File));
+    let mut block_list = BlockList::default();
+
+    // Staged blocks are not visible until committed and expire on their own,
+    // so there is nothing to clean up if upload fails.
+    for blocknum in 1.. {
+        let mut buffer = BytesMut::with_capacity(CHUNK_SIZE);
+        let more = fill_buffer(source, &mut buffer).await?;
+
+        if buffer.is_empty() {
+            trace!("eof reached");
+            break;
+        }
+
+        let chunk = buffer.freeze();
+
+        trace!(
+            block = blocknum,
+            block_offset = filesize,
+            block_len = chunk.len(),
+            "uploading block"
+        );
+        filesize += chunk.len();
+        hash.update(chunk.to_owned());
+
+        let block_id = block_id(blocknum);
+        blob_client.put_block(block_id.to_owned(), chunk).await?;
+        block_list
+            .blocks
+            .push(BlobBlockType::new_uncommitted(block_id));
+
+        if !more {
+            trace!("eof reached");
+            break;
+        }
+    }
+
+    let block_count = block_list.blocks.len() as u32;
+    let committed = blob_client.put_block_list(block_list).await?;
+
+    let hash = FileHash::from_bytes(hash.finalize());
+    Span::current().record("hash", hash.to_string().as_str());
+
+    // Version id isn't reported by the SDK.
+    Ok(UploadReceipt {
+        storage_id,
+        size: FileSize {
+            size: filesize as u64,
+        },
+        hash,
+        etag: Some(committed.etag),
+        version_id: None,
+        part_count: block_count,
+        part_size: CHUNK_SIZE,
+        object_key: None,
+    })
+}
+
+#[instrument(
+    skip(azure, storage_id, expected_hash, expected_size),
+    fields(storage_id = storage_id.as_str(), hash)
+)]
+pub async fn blob_download_file(
+    azure: &Azure,
+    storage_id: StorageId,
+    expected_hash: &FileHash,
+    expected_size: &FileSize,
+    path: &std::path::Path,
+    overwrite: OverwritePolicy,
+) -> Result<()> {
+    trace!("downloading file");
+
+    let target = DownloadTarget::prepare(path, overwrite).await?;
+    let result = blob_download_file_impl(
+        azure,
+        &storage_id,
+        expected_hash,
+        expected_size,
+        target.path(),
+    )
+    .await;
+
+    if let Err(e) = result {
+        // Cleanup failed downloads
+        trace!(error = ?e, "download failed");
+
+        if let Err(error) = remove_file(target.path()).await {
+            error!(?error, "error deleting partial download");
+        }
+
+        return Err(e);
+    }
+
+    target.commit().await
+}
+
+async fn blob_download_file_impl(
+    azure: &Azure,
+    storage_id: &StorageId,
+    expected_hash: &FileHash,
+    expected_size: &FileSize,
+    path: &std::path::Path,
+) -> Result<()> {
+    let mut file = File::create(path)
+        .await
+        .with_context(|| format!("Failed to create {}", path.display()))?;
+    let mut hash = ChunkedHash::keyed(azure.hash_key(HashKind::File));
+    let mut size = 0;
+
+    let mut pages = get_blob(&azure.blob_client(storage_id));
+
+    while let Some(page) = pages.next().await {
+        let mut body = page?.data;
+
+        while let Some(bytes) = body.try_next().await? {
+            trace!(size = bytes.len(), "received body chunk");
+            hash.update(bytes.clone());
+            file.write_all(&bytes)
+                .await
+                .map_err(|e| write_error(e, size))?;
+            size += bytes.len() as u64;
+        }
+    }
+
+    trace!("eof reached");
+    file.flush().await.map_err(|e| write_error(e, size))?;
+
+    if size < expected_size.size {
+        return Err(CloudError::Truncated {
+            expected: expected_size.size,
+            actual: size,
+        }
+        .into());
+    }
+
+    if size != expected_size.size {
+        return Err(anyhow!(
+            "File size mismatch: expected {}, got {}",
+            expected_size.size,
+            size,
+        ));
+    }
+
+    let actual_hash = FileHash::from_bytes(hash.finalize());
+    Span::current().record("hash", actual_hash.to_string().as_str());
+
+    if actual_hash != *expected_hash {
+        return Err(anyhow!(
+            "File hash mismatch: expected {}, got {}",
+            expected_hash,
+            actual_hash,
+        ));
+    }
+
+    Ok(())
+}
+
+#[instrument(skip(azure, storage_id), fields(storage_id = storage_id.as_str()))]
+pub async fn blob_download_part(
+    azure: &Azure,
+    storage_id: &StorageId,
+    offset: u64,
+    len: u64,
+    path: &std::path::Path,
+    overwrite: OverwritePolicy,
+) -> Result<()> {
+    let target = DownloadTarget::prepare(path, overwrite).await?;
+    let path = target.path();
+    let mut file = File::create(path)
+        .await
+        .with_context(|| format!("Failed to create {}", path.display()))?;
+    let mut received = 0;
+
+    if len > 0 {
+        let mut pages = azure
+            .blob_client(storage_id)
+            .get()
+            .range(offset..offset + len)
+            .chunk_size(CHUNK_SIZE as u64)
+            .into_stream();
+
+        while let Some(page) = pages.next().await {
+            let mut body = page?.data;
+
+            while let Some(bytes) = body.try_next().await? {
+                file.write_all(&bytes)
+                    .await
+                    .map_err(|e| write_error(e, received))?;
+                received += bytes.len() as u64;
+            }
+        }
+    }
+
+    file.flush().await.map_err(|e| write_error(e, received))?;
+
+    if received != len {
+        return Err(CloudError::Truncated {
+            expected: len,
+            actual: received,
+        }
+        .into());
+    }
+
+    target.commit().await
+}
+
+// Blob is fetched in CHUNK_SIZE ranged requests, one page each.
+fn get_blob(
+    blob_client: &BlobClient,
+) -> azure_core::Pageable<azure_storage_blobs::blob::operations::GetBlobResponse, azure_core::Error>
+{
+    blob_client
+        .get()
+        .chunk_size(CHUNK_SIZE as u64)
+        .into_stream()
+}
+
+#[instrument(skip(azure, manifest))]
+pub async fn blob_verify_all(
+    azure: &Azure,
+    manifest: &[(StorageId, FileHash, FileSize)],
+    concurrency: usize,
+) -> Result<Vec<(StorageId, Result<VerifyResult>)>> {
+    if concurrency == 0 {
+        return Err(anyhow!("Verification concurrency must be positive"));
+    }
+
+    trace!(files = manifest.len(), "verifying files");
+
+    let checks = manifest
+        .iter()
+        .map(|(storage_id, expected_hash, expected_size)| async move {
+            let result = blob_verify_file(azure, storage_id, expected_hash, expected_size).await;
+            Ok((storage_id.to_owned(), result))
+        })
+        .collect();
+
+    try_join_buffered(checks, concurrency).await
+}
+
+// Same as download, but received data is only hashed.
+#[instrument(
+    skip(azure, storage_id, expected_hash, expected_size),
+    fields(storage_id = storage_id.as_str(), hash)
+)]
+async fn blob_verify_file(
+    azure: &Azure,
+    storage_id: &StorageId,
+    expected_hash: &FileHash,
+    expected_size: &FileSize,
+) -> Result<VerifyResult> {
+    trace!("verifying file");
+
+    let mut hash = ChunkedHash::keyed(azure.hash_key(HashKind::File));
+    let mut size = 0;
+    let mut pages = get_blob(&azure.blob_client(storage_id));
+
+    while let Some(page) = pages.next().await {
+        let mut body = match page {
+            Ok(page) => page.data,
+            Err(e) if e.as_http_error().map(|e| e.status()) == Some(StatusCode::NotFound) => {
+                return Ok(VerifyResult::Missing);
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        while let Some(bytes) = body.try_next().await? {
+            size += bytes.len() as u64;
+            hash.update(bytes);
+        }
+    }
+
+    if size != expected_size.size {
+        return Ok(VerifyResult::SizeMismatch {
+            expected: *expected_size,
+            actual: FileSize { size },
+        });
+    }
+
+    let actual_hash = FileHash::from_bytes(hash.finalize());
+    Span::current().record("hash", actual_hash.to_string().as_str());
+
+    if actual_hash != *expected_hash {
+        return Ok(VerifyResult::HashMismatch {
+            expected: expected_hash.to_owned(),
+            actual: actual_hash,
+        });
+    }
+
+    Ok(VerifyResult::Ok)
+}
+
+#[instrument(skip(azure, storage_id), fields(storage_id = storage_id.as_str()))]
+pub async fn blob_delete_file(azure: &Azure, storage_id: &StorageId) -> Result<()> {
+    azure.blob_client(storage_id).delete().await?;
+
+    Ok(())
+}
+
+// Blobs with foreign names are skipped.
+pub fn blob_list_files_stream(azure: &Azure) -> BoxStream<'_, Result<StorageId>> {
+    azure
+        .container_client()
+        .list_blobs()
+        .into_stream()
+        .map_err(anyhow::Error::from)
+        .map_ok(|page| {
+            let ids: Vec<_> = page
+                .blobs
+                .blobs()
+                .filter_map(|blob| StorageId::parse(&blob.name).ok())
+                .map(Ok)
+                .collect();
+
+            stream::iter(ids)
+        })
+        .try_flatten()
+        .boxed()
+}
@@ -0,0 +1,5 @@
+mod blob;
+mod provider;
+
+pub use provider::create_azure_config;
+pub use provider::Azure;
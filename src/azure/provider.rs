@@ -0,0 +1,250 @@
+use crate::azure::blob::{
+    blob_connect_check, blob_delete_file, blob_download_file, blob_download_part,
+    blob_list_files_stream, blob_upload_file, blob_upload_stream, blob_verify_all,
+};
+use crate::config::{
+    config_master_key, env_master_key, hash_check, open_config, seal_config, verify_hash_check,
+    EnvMasterKey, SealedConfig,
+};
+use crate::crypto::hash::{random_salt, ChunkedHash, HashKey, HashKeys, HashKind};
+use crate::crypto::master_key::MasterKey;
+use crate::provider::*;
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use azure_storage::StorageCredentials;
+use azure_storage_blobs::prelude::{BlobClient, BlobServiceClient, ContainerClient};
+use futures::stream::BoxStream;
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncRead;
+use tracing::instrument;
+
+#[derive(Clone, Default, Eq, PartialEq, Hash, Serialize, Deserialize)]
+struct AzureConfig {
+    account: String,
+    container: String,
+    // Exactly one of shared key or SAS token must be set.
+    access_key: Option<String>,
+    sas_token: Option<String>,
+    master_key: String,
+    // Key store entry "service/account" holding the master key, which is then left empty.
+    #[serde(default)]
+    master_key_entry: Option<String>,
+    // Mixed into hash keys, random for new configs. Zero in old configs, matching their hashes.
+    #[serde(default, with = "crate::config::pickle_u64")]
+    hash_salt: u64,
+    // Hex hash of fixed input with file hash key, recorded at creation.
+    #[serde(default)]
+    hash_check: Option<String>,
+    #[serde(default)]
+    overwrite: OverwritePolicy,
+}
+
+impl std::fmt::Debug for AzureConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AzureConfig")
+            .field("account", &self.account)
+            .field("container", &self.container)
+            .field("access_key", &"*****")
+            .field("sas_token", &"*****")
+            .field("master_key", &"*****")
+            .field("master_key_entry", &self.master_key_entry)
+            .field("hash_salt", &self.hash_salt)
+            .field("hash_check", &self.hash_check)
+            .field("overwrite", &self.overwrite)
+            .finish()
+    }
+}
+
+impl SealedConfig for AzureConfig {
+    fn master_key(&self) -> &str {
+        &self.master_key
+    }
+
+    fn key_entry(&self) -> Option<&str> {
+        self.master_key_entry.as_deref()
+    }
+
+    fn set_master_key(&mut self, master_key: String) {
+        self.master_key = master_key;
+    }
+}
+
+#[instrument]
+pub fn create_azure_config() -> Result<CloudProviderConfig> {
+    // TODO build config in smart way
+    crate::crypto::init();
+    let (master_key, master_key_entry) = match env_master_key()? {
+        EnvMasterKey::Key(key) => (key, None),
+        EnvMasterKey::Entry(name) => (String::new(), Some(name)),
+    };
+
+    let mut config = AzureConfig {
+        account: std::env::var("AZURE_STORAGE_ACCOUNT")?,
+        container: std::env::var("AZURE_CONTAINER")?,
+        access_key: std::env::var("AZURE_STORAGE_KEY").ok(),
+        sas_token: std::env::var("AZURE_STORAGE_SAS_TOKEN").ok(),
+        master_key,
+        master_key_entry,
+        hash_salt: random_salt(),
+        hash_check: None,
+        overwrite: OverwritePolicy::default(),
+    };
+    let hash_keys = HashKeys::new(&config_master_key(&config)?, config.hash_salt)?;
+    config.hash_check = Some(hash_check(&hash_keys));
+
+    seal_config(&config)
+}
+
+#[derive(Debug)]
+pub struct Azure {
+    container_client: ContainerClient,
+    master_key: MasterKey,
+    hash_keys: HashKeys,
+    overwrite: OverwritePolicy,
+}
+
+impl Azure {
+    pub(crate) fn blob_client(&self, storage_id: &StorageId) -> BlobClient {
+        self.container_client.blob_client(storage_id.as_str())
+    }
+
+    pub(crate) fn container_client(&self) -> &ContainerClient {
+        &self.container_client
+    }
+
+    pub(crate) fn hash_key(&self, kind: HashKind) -> &HashKey {
+        self.hash_keys.get(kind)
+    }
+
+    // Master key for deriving purpose-specific subkeys.
+    pub fn master_key(&self) -> &MasterKey {
+        &self.master_key
+    }
+}
+
+#[async_trait]
+impl CloudProviderFactory for Azure {
+    async fn load_from_config(config: CloudProviderConfig) -> Result<Self> {
+        azure_load_from_config(config)
+    }
+}
+
+#[async_trait]
+impl CloudProvider for Azure {
+    async fn connect_check(&self) -> Result<()> {
+        blob_connect_check(self).await
+    }
+
+    async fn upload_file(&self, path: &std::path::Path) -> Result<UploadReceipt> {
+        blob_upload_file(self, path).await
+    }
+
+    async fn upload_stream(
+        &self,
+        source: &mut (dyn AsyncRead + Unpin + Send),
+    ) -> Result<UploadReceipt> {
+        blob_upload_stream(self, source).await
+    }
+
+    async fn download_file(
+        &self,
+        storage_id: StorageId,
+        expected_hash: &FileHash,
+        expected_size: &FileSize,
+        path: &std::path::Path,
+    ) -> Result<()> {
+        blob_download_file(
+            self,
+            storage_id,
+            expected_hash,
+            expected_size,
+            path,
+            self.overwrite,
+        )
+        .await
+    }
+
+    async fn download_range(
+        &self,
+        storage_id: &StorageId,
+        offset: u64,
+        len: u64,
+        path: &std::path::Path,
+    ) -> Result<()> {
+        blob_download_part(self, storage_id, offset, len, path, self.overwrite).await
+    }
+
+    async fn verify_all(
+        &self,
+        manifest: &[(StorageId, FileHash, FileSize)],
+        concurrency: usize,
+    ) -> Result<Vec<(StorageId, Result<VerifyResult>)>> {
+        blob_verify_all(self, manifest, concurrency).await
+    }
+
+    async fn delete_file(&self, storage_id: &StorageId) -> Result<()> {
+        blob_delete_file(self, storage_id).await
+    }
+
+    fn segment_hasher(&self) -> ChunkedHash {
+        ChunkedHash::keyed(self.hash_key(HashKind::Manifest))
+    }
+
+    fn list_files_stream(&self) -> BoxStream<'_, Result<StorageId>> {
+        blob_list_files_stream(self)
+    }
+}
+
+// Serialized config holds the credentials and master key.
+#[instrument(skip(config))]
+fn azure_load_from_config(config: CloudProviderConfig) -> Result<Azure> {
+    crate::crypto::init();
+
+    let azure_config: AzureConfig = open_config(&config)?;
+
+    let credentials = match (azure_config.access_key, azure_config.sas_token) {
+        (Some(key), None) => StorageCredentials::access_key(azure_config.account.clone(), key),
+        (None, Some(token)) => StorageCredentials::sas_token(token)?,
+        _ => {
+            return Err(anyhow!(
+                "Exactly one of Azure access key and SAS token must be set"
+            ))
+        }
+    };
+
+    let container_client = BlobServiceClient::new(azure_config.account, credentials)
+        .container_client(azure_config.container);
+
+    let master_key = MasterKey::from(&azure_config.master_key)?;
+    let hash_keys = HashKeys::new(&master_key, azure_config.hash_salt)?;
+    verify_hash_check(&hash_keys, azure_config.hash_check.as_deref())?;
+
+    Ok(Azure {
+        container_client,
+        master_key,
+        hash_keys,
+        overwrite: azure_config.overwrite,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::azure::provider::AzureConfig;
+    use crate::config::{open_config, seal_config};
+
+    #[test]
+    fn config_roundtrip() {
+        let config = AzureConfig {
+            account: "account".to_owned(),
+            container: "container".to_owned(),
+            sas_token: Some("sv=2022-11-02&sig=x".to_owned()),
+            master_key: "ce747155fe6b9557a083f95b51e7b0d0e4112950686110927b77a2ed589e8c0e"
+                .to_owned(),
+            ..Default::default()
+        };
+        let sealed = seal_config(&config).expect("failed to seal config");
+        let opened: AzureConfig = open_config(&sealed).expect("failed to open config");
+
+        assert_eq!(config, opened);
+    }
+}
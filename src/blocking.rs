@@ -0,0 +1,110 @@
+use crate::provider::*;
+use anyhow::Result;
+use tokio::runtime::{Builder, Runtime};
+
+// Runs provider calls to completion on an internal runtime, for callers without one.
+// Like other blocking wrappers, must not be used from within an async context: the calls panic.
+#[derive(Debug)]
+pub struct BlockingProvider<P> {
+    provider: P,
+    runtime: Runtime,
+}
+
+impl<P: CloudProviderFactory> BlockingProvider<P> {
+    pub fn load_from_config(config: CloudProviderConfig) -> Result<BlockingProvider<P>> {
+        let runtime = runtime()?;
+        let provider = runtime.block_on(P::load_from_config(config))?;
+
+        Ok(BlockingProvider { provider, runtime })
+    }
+}
+
+impl<P: CloudProvider> BlockingProvider<P> {
+    pub fn new(provider: P) -> Result<BlockingProvider<P>> {
+        Ok(BlockingProvider {
+            provider,
+            runtime: runtime()?,
+        })
+    }
+
+    pub fn connect_check(&self) -> Result<()> {
+        self.runtime.block_on(self.provider.connect_check())
+    }
+
+    pub fn upload_file(&self, path: &std::path::Path) -> Result<UploadReceipt> {
+        self.runtime.block_on(self.provider.upload_file(path))
+    }
+
+    pub fn download_file(
+        &self,
+        storage_id: StorageId,
+        expected_hash: &FileHash,
+        expected_size: &FileSize,
+        path: &std::path::Path,
+    ) -> Result<()> {
+        self.runtime.block_on(self.provider.download_file(
+            storage_id,
+            expected_hash,
+            expected_size,
+            path,
+        ))
+    }
+
+    pub fn verify_all(
+        &self,
+        manifest: &[(StorageId, FileHash, FileSize)],
+        concurrency: usize,
+    ) -> Result<Vec<(StorageId, Result<VerifyResult>)>> {
+        self.runtime
+            .block_on(self.provider.verify_all(manifest, concurrency))
+    }
+
+    pub fn list_files(&self) -> Result<Vec<StorageId>> {
+        self.runtime.block_on(self.provider.list_files())
+    }
+
+    pub fn provider(&self) -> &P {
+        &self.provider
+    }
+}
+
+fn runtime() -> Result<Runtime> {
+    Ok(Builder::new_current_thread().enable_all().build()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::blocking::BlockingProvider;
+    use crate::crypto::init;
+    use crate::crypto::master_key::MasterKey;
+    use crate::provider::UploadReceipt;
+    use crate::testing::MockProvider;
+
+    #[test]
+    fn upload_download() {
+        init();
+        let master_key = MasterKey::new().expect("failed to create master key");
+        let provider = MockProvider::new(&master_key).expect("failed to create provider");
+        let provider = BlockingProvider::new(provider).expect("failed to create runtime");
+
+        let dir = tempfile::tempdir().expect("failed to create temp dir");
+        let source = dir.path().join("source");
+        let target = dir.path().join("target");
+        std::fs::write(&source, b"This is test message").expect("failed to write file");
+
+        let UploadReceipt {
+            storage_id: id,
+            size,
+            hash,
+            ..
+        } = provider.upload_file(&source).expect("upload failed");
+        provider
+            .download_file(id.clone(), &hash, &size, &target)
+            .expect("download failed");
+        assert_eq!(
+            std::fs::read(&target).expect("failed to read file"),
+            b"This is test message"
+        );
+        assert_eq!(provider.list_files().expect("list failed"), [id]);
+    }
+}
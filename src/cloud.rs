@@ -1,16 +1,1798 @@
-use crate::provider::CloudProvider;
-use anyhow::Result;
+use crate::provider::{CloudProvider, FileHash, FileSize, StorageId, UploadReceipt};
+use anyhow::{anyhow, Result};
+use bytes::BytesMut;
+use futures::stream::{self, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::ErrorKind;
+use std::path::{Component, Path, PathBuf};
+use std::time::SystemTime;
+use tar::{EntryType, Header};
+use tokio::fs::{create_dir_all, metadata, read_dir, remove_file, symlink_metadata, File};
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::{info, instrument, trace, warn};
 
-pub async fn run(provider: &impl CloudProvider) -> Result<()> {
-    let (id, size, hash) = provider
-        .upload_file(std::path::Path::new("/tmp/JetBrainsMono-2.242.zip"))
-        .await?;
-    println!("upload {:?} {:?} {:?}", id, size, hash);
+// Backed up files keyed by path relative to backup root.
+// Serialized as a flat list of entries, for consumption by other tools.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(from = "ManifestDocument", into = "ManifestDocument")]
+pub struct BackupManifest {
+    pub files: BTreeMap<PathBuf, (StorageId, FileHash, FileSize)>,
+    // Modification times at upload of stored and packed files, recorded by `sync_dir` for
+    // change detection.
+    pub mtimes: BTreeMap<PathBuf, SystemTime>,
+    // Files stored in shared pack objects by `Packer`, not in `files`.
+    pub packed: BTreeMap<PathBuf, PackedFile>,
+    // Keys returned in upload receipts, by storage id, for providers whose keys can't be derived
+    // from the id. Passed back to the provider before objects are accessed.
+    pub object_keys: BTreeMap<StorageId, String>,
+}
+
+// Place of a file in its pack object.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct PackedFile {
+    pub pack_id: StorageId,
+    pub offset: u64,
+    pub size: FileSize,
+    // Provider's segment hash, as archive entries.
+    pub hash: FileHash,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ManifestDocument {
+    files: Vec<ManifestEntry>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    packed: Vec<PackedEntry>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    object_keys: BTreeMap<StorageId, String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PackedEntry {
+    path: PathBuf,
+    #[serde(flatten)]
+    file: PackedFile,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mtime: Option<SystemTime>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ManifestEntry {
+    path: PathBuf,
+    storage_id: StorageId,
+    size: FileSize,
+    hash: FileHash,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mtime: Option<SystemTime>,
+}
+
+impl BackupManifest {
+    pub fn record_object_key(&mut self, receipt: &UploadReceipt) {
+        if let Some(key) = &receipt.object_key {
+            self.object_keys
+                .insert(receipt.storage_id.to_owned(), key.to_owned());
+        }
+    }
+
+    // Keys of objects no longer referenced by any file.
+    fn prune_object_keys(&mut self) {
+        let referenced: BTreeSet<_> = self
+            .files
+            .values()
+            .map(|(storage_id, _, _)| storage_id)
+            .chain(self.packed.values().map(|file| &file.pack_id))
+            .collect();
+
+        self.object_keys
+            .retain(|storage_id, _| referenced.contains(storage_id));
+    }
+}
+
+impl From<ManifestDocument> for BackupManifest {
+    fn from(document: ManifestDocument) -> BackupManifest {
+        let mut manifest = BackupManifest::default();
+
+        for e in document.files {
+            if let Some(mtime) = e.mtime {
+                manifest.mtimes.insert(e.path.clone(), mtime);
+            }
+            manifest
+                .files
+                .insert(e.path, (e.storage_id, e.hash, e.size));
+        }
+        for e in document.packed {
+            if let Some(mtime) = e.mtime {
+                manifest.mtimes.insert(e.path.clone(), mtime);
+            }
+            manifest.packed.insert(e.path, e.file);
+        }
+        manifest.object_keys = document.object_keys;
+
+        manifest
+    }
+}
+
+impl From<BackupManifest> for ManifestDocument {
+    fn from(manifest: BackupManifest) -> ManifestDocument {
+        let mut mtimes = manifest.mtimes;
+
+        ManifestDocument {
+            files: manifest
+                .files
+                .into_iter()
+                .map(|(path, (storage_id, hash, size))| ManifestEntry {
+                    mtime: mtimes.remove(&path),
+                    path,
+                    storage_id,
+                    size,
+                    hash,
+                })
+                .collect(),
+            packed: manifest
+                .packed
+                .into_iter()
+                .map(|(path, file)| PackedEntry {
+                    mtime: mtimes.remove(&path),
+                    path,
+                    file,
+                })
+                .collect(),
+            object_keys: manifest.object_keys,
+        }
+    }
+}
+
+// Removed when dropped, so staging files don't outlive failed operations either.
+pub(crate) struct TempFile {
+    pub(crate) path: PathBuf,
+}
+
+impl TempFile {
+    pub(crate) fn new(dir: &Path, name: &str) -> TempFile {
+        TempFile {
+            path: dir.join(format!("private-cloud-{}-{}", name, StorageId::generate())),
+        }
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if let Err(error) = std::fs::remove_file(&self.path) {
+            if error.kind() != std::io::ErrorKind::NotFound {
+                warn!(?error, path = ?self.path, "error deleting temp file");
+            }
+        }
+    }
+}
+
+const TEST_FILE_SIZE: usize = 1024 * 1024;
+
+// Round trip of a generated file, staged in provider's temp dir.
+pub async fn run(provider: &(impl CloudProvider + ?Sized)) -> Result<()> {
+    let temp_dir = provider.temp_dir();
+    let source = TempFile::new(&temp_dir, "source");
+    let target = TempFile::new(&temp_dir, "target");
+    let data: Vec<u8> = (0..TEST_FILE_SIZE).map(|i| (i % 251) as u8).collect();
+    tokio::fs::write(&source.path, &data).await?;
+
+    let UploadReceipt {
+        storage_id: id,
+        size,
+        hash,
+        ..
+    } = provider.upload_file(&source.path).await?;
+    println!("upload {} {} {}", id, size, hash);
 
     provider
-        .download_file(id, &hash, &size, std::path::Path::new("/tmp/downloaded"))
+        .download_file(id, &hash, &size, &target.path)
         .await?;
+
+    if tokio::fs::read(&target.path).await? != data {
+        return Err(anyhow!("Downloaded file differs from uploaded"));
+    }
     println!("download complete");
 
     Ok(())
 }
+
+pub const DEFAULT_BACKUP_CONCURRENCY: usize = 4;
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct BackupOptions {
+    // Only log planned uploads, don't touch the provider.
+    pub dry_run: bool,
+    // Files uploaded at once.
+    pub concurrency: usize,
+    // Files smaller than this go into shared packs, see `Packer`. Only used by `backup_dir`.
+    pub pack_below: Option<u64>,
+}
+
+impl Default for BackupOptions {
+    fn default() -> Self {
+        BackupOptions {
+            dry_run: false,
+            concurrency: DEFAULT_BACKUP_CONCURRENCY,
+            pack_below: None,
+        }
+    }
+}
+
+// Files that backup would upload, with paths relative to backup root.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct BackupPlan {
+    pub files: Vec<(PathBuf, FileSize)>,
+}
+
+impl BackupPlan {
+    pub fn total_size(&self) -> u64 {
+        self.files.iter().map(|(_, size)| size.size).sum()
+    }
+}
+
+// Find regular files under `root` to back up.
+// Only relative paths are preserved: symlinks and special files are skipped, empty directories
+// and permission bits are not recorded. Unreadable files and directories are skipped with warning.
+#[instrument]
+pub async fn plan_backup(root: &Path) -> Result<BackupPlan> {
+    let mut plan = BackupPlan::default();
+
+    for relative_path in list_files(root).await? {
+        let path = root.join(&relative_path);
+
+        // Check readability upfront: network errors may also have io::Error as their cause.
+        let metadata = match File::open(&path).await {
+            Ok(file) => file.metadata().await?,
+            Err(error) => {
+                warn!(?path, %error, "skipping unreadable file");
+                continue;
+            }
+        };
+
+        plan.files.push((
+            relative_path,
+            FileSize {
+                size: metadata.len(),
+            },
+        ));
+    }
+
+    Ok(plan)
+}
+
+// Upload every file found by `plan_backup`, one object per file or packed if small enough,
+// and add them to `manifest`. Files already in `manifest` are skipped, so passing the manifest
+// of a failed run resumes it.
+// Upload failures don't stop other files, the first one is returned after all are attempted.
+#[instrument(skip(provider, manifest))]
+pub async fn backup_dir(
+    provider: &(impl CloudProvider + ?Sized),
+    root: &Path,
+    options: &BackupOptions,
+    manifest: &mut BackupManifest,
+) -> Result<()> {
+    if options.concurrency == 0 {
+        return Err(anyhow!("Backup concurrency must be positive"));
+    }
+    check_packing(provider, options)?;
+    provider.add_object_keys(&manifest.object_keys);
+
+    let mut plan = plan_backup(root).await?;
+    plan.files.retain(|(relative_path, _)| {
+        !manifest.files.contains_key(relative_path) && !manifest.packed.contains_key(relative_path)
+    });
+
+    if options.dry_run {
+        for (relative_path, size) in &plan.files {
+            info!(?relative_path, size = size.size, "would upload file");
+        }
+
+        info!(
+            files = plan.files.len(),
+            total_size = plan.total_size(),
+            "dry run complete"
+        );
+
+        return Ok(());
+    }
+
+    let (small, large): (Vec<_>, Vec<_>) = plan
+        .files
+        .into_iter()
+        .partition(|(_, size)| options.pack_below.is_some_and(|limit| size.size < limit));
+    let small = small
+        .into_iter()
+        .map(|(relative_path, _)| (relative_path, None))
+        .collect();
+    let large = large
+        .into_iter()
+        .map(|(relative_path, _)| (relative_path, None))
+        .collect();
+
+    let packed = pack_files(provider, root, small, manifest).await;
+    let uploaded = upload_files(provider, root, large, options.concurrency, manifest).await;
+
+    packed.and(uploaded)
+}
+
+// Part size of S3 and Azure uploads.
+pub const DEFAULT_PACK_SIZE: usize = 100 * 1024 * 1024;
+
+// Packed files are read whole and looked up by offsets into stored data, so the threshold is
+// capped at pack size and the provider must store data uncompressed. Packs are uploaded as
+// streams, which content addressed providers can't take.
+fn check_packing(provider: &(impl CloudProvider + ?Sized), options: &BackupOptions) -> Result<()> {
+    match options.pack_below {
+        Some(limit) if limit > DEFAULT_PACK_SIZE as u64 => Err(anyhow!(
+            "Pack threshold must be at most {} bytes",
+            DEFAULT_PACK_SIZE
+        )),
+        Some(_) if provider.compression().is_some() => Err(anyhow!(
+            "Packing needs uncompressed storage for ranged reads of packed files"
+        )),
+        Some(_) if provider.content_addressed() => Err(anyhow!(
+            "Packing needs stream uploads, which content hash ids don't support"
+        )),
+        _ => Ok(()),
+    }
+}
+
+// Small files appended into shared pack objects, each recorded in the manifest with its offset
+// for ranged downloads. Far fewer requests than an object per file, and unlike an archive, the
+// files can be replaced one by one. Offsets are into stored data, restore needs uncompressed
+// objects. A pack is kept in memory until uploaded, once it reaches `pack_size`.
+pub struct Packer<'a, P: CloudProvider + ?Sized> {
+    provider: &'a P,
+    pack_size: usize,
+    buffer: Vec<u8>,
+    pending: Vec<(PathBuf, Option<SystemTime>, u64, FileSize, FileHash)>,
+}
+
+impl<'a, P: CloudProvider + ?Sized> Packer<'a, P> {
+    pub fn new(provider: &'a P, pack_size: usize) -> Packer<'a, P> {
+        Packer {
+            provider,
+            pack_size,
+            buffer: Vec::new(),
+            pending: Vec::new(),
+        }
+    }
+
+    // Files are added to `manifest` once their pack is uploaded, with modification time if
+    // given.
+    pub async fn add(
+        &mut self,
+        relative_path: PathBuf,
+        mtime: Option<SystemTime>,
+        data: &[u8],
+        manifest: &mut BackupManifest,
+    ) -> Result<()> {
+        let mut hash = self.provider.segment_hasher();
+        hash.update(data);
+
+        self.pending.push((
+            relative_path,
+            mtime,
+            self.buffer.len() as u64,
+            FileSize {
+                size: data.len() as u64,
+            },
+            FileHash::from_bytes(hash.finalize()),
+        ));
+        self.buffer.extend_from_slice(data);
+
+        if self.buffer.len() >= self.pack_size {
+            self.flush(manifest).await?;
+        }
+
+        Ok(())
+    }
+
+    // Upload pending files as a pack, if there are any. They stay pending if the upload fails.
+    pub async fn flush(&mut self, manifest: &mut BackupManifest) -> Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+
+        let receipt = self.provider.upload_stream(&mut &self.buffer[..]).await?;
+        manifest.record_object_key(&receipt);
+        let storage_id = receipt.storage_id;
+        trace!(
+            storage_id = storage_id.as_str(),
+            files = self.pending.len(),
+            size = self.buffer.len(),
+            "pack uploaded"
+        );
+
+        for (relative_path, mtime, offset, size, hash) in self.pending.drain(..) {
+            match mtime {
+                Some(mtime) => manifest.mtimes.insert(relative_path.clone(), mtime),
+                None => manifest.mtimes.remove(&relative_path),
+            };
+            // Moved into the pack, if it was stored on its own.
+            manifest.files.remove(&relative_path);
+            manifest.packed.insert(
+                relative_path,
+                PackedFile {
+                    pack_id: storage_id.clone(),
+                    offset,
+                    size,
+                    hash,
+                },
+            );
+        }
+        self.buffer.clear();
+
+        Ok(())
+    }
+}
+
+// Pack files under `root` into `manifest`, with modification time if given. Unreadable files
+// don't stop other files, a failed pack upload does, packs uploaded before it stay in `manifest`.
+async fn pack_files(
+    provider: &(impl CloudProvider + ?Sized),
+    root: &Path,
+    files: Vec<(PathBuf, Option<SystemTime>)>,
+    manifest: &mut BackupManifest,
+) -> Result<()> {
+    let mut packer = Packer::new(provider, DEFAULT_PACK_SIZE);
+    let mut failed = 0;
+    let mut first_error = None;
+
+    for (relative_path, mtime) in files {
+        match tokio::fs::read(root.join(&relative_path)).await {
+            Ok(data) => packer.add(relative_path, mtime, &data, manifest).await?,
+            Err(error) => {
+                warn!(?relative_path, %error, "file read failed");
+                failed += 1;
+                first_error.get_or_insert(error);
+            }
+        }
+    }
+
+    packer.flush(manifest).await?;
+
+    match first_error {
+        None => Ok(()),
+        Some(e) => Err(anyhow::Error::from(e).context(format!("{} files failed to pack", failed))),
+    }
+}
+
+// Upload files under `root` into `manifest`, with modification time if given.
+// Upload failures don't stop other files, the first one is returned after all are attempted.
+async fn upload_files(
+    provider: &(impl CloudProvider + ?Sized),
+    root: &Path,
+    files: Vec<(PathBuf, Option<SystemTime>)>,
+    concurrency: usize,
+    manifest: &mut BackupManifest,
+) -> Result<()> {
+    let uploads = files.into_iter().map(|(relative_path, mtime)| async move {
+        let result = provider
+            .upload_file_outcome(&root.join(&relative_path))
+            .await;
+        (relative_path, mtime, result)
+    });
+    let mut results = stream::iter(uploads).buffer_unordered(concurrency);
+    let mut uploaded = 0;
+    let mut skipped = 0;
+    let mut failed = 0;
+    let mut first_error = None;
+
+    while let Some((relative_path, mtime, result)) = results.next().await {
+        match result {
+            Ok(outcome) => {
+                let was_skipped = outcome.is_skipped();
+
+                if was_skipped {
+                    skipped += 1;
+                } else {
+                    uploaded += 1;
+                }
+
+                let receipt = outcome.into_receipt();
+                manifest.record_object_key(&receipt);
+                let UploadReceipt {
+                    storage_id,
+                    size,
+                    hash,
+                    ..
+                } = receipt;
+                trace!(
+                    ?relative_path,
+                    storage_id = storage_id.as_str(),
+                    skipped = was_skipped,
+                    "file uploaded"
+                );
+                match mtime {
+                    Some(mtime) => manifest.mtimes.insert(relative_path.clone(), mtime),
+                    None => manifest.mtimes.remove(&relative_path),
+                };
+                // Moved out of its pack, if it was packed.
+                manifest.packed.remove(&relative_path);
+                manifest
+                    .files
+                    .insert(relative_path, (storage_id, hash, size));
+            }
+            Err(error) => {
+                warn!(?relative_path, %error, "file upload failed");
+                failed += 1;
+                first_error.get_or_insert(error);
+            }
+        }
+    }
+
+    // Skipped files had identical content stored already, by this run or an earlier one.
+    info!(uploaded, skipped, failed, "uploads finished");
+
+    match first_error {
+        None => Ok(()),
+        Some(e) => Err(e.context(format!("{} files failed to upload", failed))),
+    }
+}
+
+// Bring `manifest` of an earlier run up to date with `root`: files with changed size or
+// modification time, or without recorded time, are uploaded under fresh ids, objects of files
+// removed locally are deleted. Superseded objects of changed files are deleted as well.
+// Changed files smaller than `pack_below` are packed, as by `backup_dir`. Packs are deleted once
+// none of their files is left in the manifest.
+// Files that can't be read are left as backed up before, they are not treated as removed.
+// Failed deletes keep their manifest entries, so the next run retries them.
+#[instrument(skip(provider, manifest))]
+pub async fn sync_dir(
+    provider: &(impl CloudProvider + ?Sized),
+    root: &Path,
+    options: &BackupOptions,
+    manifest: &mut BackupManifest,
+) -> Result<()> {
+    if options.concurrency == 0 {
+        return Err(anyhow!("Backup concurrency must be positive"));
+    }
+    check_packing(provider, options)?;
+    provider.add_object_keys(&manifest.object_keys);
+
+    let mut changed = vec![];
+    let mut present = BTreeMap::new();
+
+    for relative_path in list_files(root).await? {
+        let path = root.join(&relative_path);
+
+        let metadata = match File::open(&path).await {
+            Ok(file) => file.metadata().await?,
+            Err(error) => {
+                warn!(?path, %error, "skipping unreadable file");
+                continue;
+            }
+        };
+        let size = FileSize {
+            size: metadata.len(),
+        };
+        let mtime = metadata.modified()?;
+
+        let recorded = match manifest.files.get(&relative_path) {
+            Some((_, _, recorded)) => Some(recorded),
+            None => manifest.packed.get(&relative_path).map(|file| &file.size),
+        };
+        let unchanged =
+            recorded == Some(&size) && manifest.mtimes.get(&relative_path) == Some(&mtime);
+
+        if !unchanged {
+            changed.push((relative_path.clone(), Some(mtime)));
+        }
+        present.insert(relative_path, size);
+    }
+
+    let mut removed = vec![];
+
+    for relative_path in manifest.files.keys().chain(manifest.packed.keys()) {
+        if present.contains_key(relative_path) {
+            continue;
+        }
+
+        // Absent from listing may also mean unreadable directory, only confirmed removals count.
+        match symlink_metadata(root.join(relative_path)).await {
+            Ok(m) if m.is_file() => {
+                warn!(?relative_path, "keeping unreadable file");
+            }
+            Err(error) if error.kind() != ErrorKind::NotFound => {
+                warn!(?relative_path, %error, "keeping unreadable file");
+            }
+            _ => removed.push(relative_path.clone()),
+        }
+    }
+
+    if options.dry_run {
+        for (relative_path, _) in &changed {
+            info!(
+                ?relative_path,
+                size = present[relative_path].size,
+                "would upload file"
+            );
+        }
+        for relative_path in &removed {
+            info!(?relative_path, "would delete file");
+        }
+
+        info!(
+            uploads = changed.len(),
+            deletes = removed.len(),
+            "dry run complete"
+        );
+
+        return Ok(());
+    }
+
+    let superseded: Vec<_> = changed
+        .iter()
+        .filter_map(|(relative_path, _)| {
+            let (storage_id, _, _) = manifest.files.get(relative_path)?;
+            Some(storage_id.clone())
+        })
+        .collect();
+
+    let packs: BTreeSet<_> = manifest
+        .packed
+        .values()
+        .map(|file| file.pack_id.clone())
+        .collect();
+    let (small, large): (Vec<_>, Vec<_>) = changed.into_iter().partition(|(relative_path, _)| {
+        options
+            .pack_below
+            .is_some_and(|limit| present[relative_path].size < limit)
+    });
+    let packed = pack_files(provider, root, small, manifest).await;
+    let uploaded = upload_files(provider, root, large, options.concurrency, manifest).await;
+    let uploaded = packed.and(uploaded);
+
+    let (removed_packed, removed): (Vec<_>, Vec<_>) = removed
+        .into_iter()
+        .partition(|relative_path| manifest.packed.contains_key(relative_path));
+
+    // Files with the same content share an object when ids are content hashes. Objects are only
+    // deleted once no file staying in the manifest references them.
+    let removed_set: BTreeSet<_> = removed.iter().collect();
+    let referenced: BTreeSet<_> = manifest
+        .files
+        .iter()
+        .filter(|(relative_path, _)| !removed_set.contains(relative_path))
+        .map(|(_, (storage_id, _, _))| storage_id.clone())
+        .collect();
+
+    // Removed files sharing an object go with its one delete.
+    let mut unreferenced: BTreeMap<StorageId, Vec<PathBuf>> = BTreeMap::new();
+
+    for relative_path in removed {
+        let (storage_id, _, _) = &manifest.files[&relative_path];
+
+        if referenced.contains(storage_id) {
+            trace!(?relative_path, "removed file shares a kept object");
+            manifest.files.remove(&relative_path);
+            manifest.mtimes.remove(&relative_path);
+        } else {
+            unreferenced
+                .entry(storage_id.clone())
+                .or_default()
+                .push(relative_path);
+        }
+    }
+
+    // Only objects replaced in manifest, failed uploads still reference the old ones.
+    let superseded: BTreeSet<_> = superseded
+        .into_iter()
+        .filter(|storage_id| {
+            !referenced.contains(storage_id) && !unreferenced.contains_key(storage_id)
+        })
+        .collect();
+
+    for storage_id in superseded {
+        if let Err(error) = provider.delete_file(&storage_id).await {
+            warn!(storage_id = storage_id.as_str(), %error, "error deleting superseded file");
+        }
+    }
+
+    for relative_path in removed_packed {
+        trace!(?relative_path, "packed file removed");
+        manifest.packed.remove(&relative_path);
+        manifest.mtimes.remove(&relative_path);
+    }
+
+    let remaining: BTreeSet<_> = manifest
+        .packed
+        .values()
+        .map(|file| file.pack_id.clone())
+        .collect();
+
+    // Unlike files, emptied packs have no manifest entry left to retry the delete with.
+    for pack_id in packs.difference(&remaining) {
+        if let Err(error) = provider.delete_file(pack_id).await {
+            warn!(pack_id = pack_id.as_str(), %error, "error deleting emptied pack");
+        }
+    }
+
+    let mut failed = 0;
+    let mut first_error = None;
+
+    let removed_ids: Vec<_> = unreferenced.keys().cloned().collect();
+    let deleted = provider.delete_files(&removed_ids).await?;
+
+    for (relative_paths, (_, result)) in unreferenced.into_values().zip(deleted) {
+        match result {
+            Ok(()) => {
+                for relative_path in relative_paths {
+                    trace!(?relative_path, "file deleted");
+                    manifest.files.remove(&relative_path);
+                    manifest.mtimes.remove(&relative_path);
+                }
+            }
+            Err(error) => {
+                for relative_path in &relative_paths {
+                    warn!(?relative_path, %error, "file delete failed");
+                }
+                failed += relative_paths.len();
+                first_error.get_or_insert(error);
+            }
+        }
+    }
+
+    manifest.prune_object_keys();
+    uploaded?;
+
+    match first_error {
+        None => Ok(()),
+        Some(e) => Err(e.context(format!("{} files failed to delete", failed))),
+    }
+}
+
+// Directory stored as a single tar object, with offsets of file data for extracting files
+// without downloading the whole archive. Fewer requests than a backup of many small files,
+// but files can't be replaced or deleted one by one.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ArchiveIndex {
+    pub storage_id: StorageId,
+    pub size: FileSize,
+    pub hash: FileHash,
+    pub entries: BTreeMap<PathBuf, ArchiveEntry>,
+    // As recorded in manifests, for providers whose keys can't be derived from the id.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub object_key: Option<String>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ArchiveEntry {
+    // Start of file data in the archive, past its tar header.
+    pub offset: u64,
+    pub size: FileSize,
+    // Provider's segment hash, keyed unless the provider has no hash keys.
+    pub hash: FileHash,
+}
+
+const TAR_BLOCK_SIZE: u64 = 512;
+// Between archive writer and upload, which collates parts on its own.
+const ARCHIVE_PIPE_SIZE: usize = 1024 * 1024;
+
+// Stream files found by `plan_backup` into one tar object. Offsets are into stored data,
+// extraction needs an uncompressed object, and the archive is uploaded as a stream, which
+// content addressed providers can't take. An archive that fails while being written is
+// deleted, rather than left as a valid but incomplete object.
+#[instrument(skip(provider))]
+pub async fn backup_archive(
+    provider: &(impl CloudProvider + ?Sized),
+    root: &Path,
+) -> Result<ArchiveIndex> {
+    if provider.compression().is_some() {
+        return Err(anyhow!(
+            "Archives need uncompressed storage for extracting single files"
+        ));
+    }
+    if provider.content_addressed() {
+        return Err(anyhow!(
+            "Archives need stream uploads, which content hash ids don't support"
+        ));
+    }
+
+    let plan = plan_backup(root).await?;
+    let (reader, writer) = tokio::io::duplex(ARCHIVE_PIPE_SIZE);
+
+    // Reader is dropped with a failed upload, so the writer gets an error instead of blocking.
+    let upload = async move {
+        let mut reader = reader;
+        provider.upload_stream(&mut reader).await
+    };
+    let (written, uploaded) = tokio::join!(write_archive(provider, root, &plan, writer), upload);
+    let UploadReceipt {
+        storage_id,
+        size,
+        hash,
+        object_key,
+        ..
+    } = uploaded?;
+
+    match written {
+        Ok(entries) => Ok(ArchiveIndex {
+            storage_id,
+            size,
+            hash,
+            entries,
+            object_key,
+        }),
+        Err(e) => {
+            if let Err(error) = provider.delete_file(&storage_id).await {
+                warn!(?error, "error deleting incomplete archive");
+            }
+
+            Err(e)
+        }
+    }
+}
+
+async fn write_archive(
+    provider: &(impl CloudProvider + ?Sized),
+    root: &Path,
+    plan: &BackupPlan,
+    mut writer: impl AsyncWrite + Unpin,
+) -> Result<BTreeMap<PathBuf, ArchiveEntry>> {
+    let mut entries = BTreeMap::new();
+    let mut offset = 0;
+
+    for (relative_path, _) in &plan.files {
+        let path = root.join(relative_path);
+        let file = match File::open(&path).await {
+            Ok(file) => file,
+            Err(error) => {
+                warn!(?path, %error, "skipping unreadable file");
+                continue;
+            }
+        };
+        let metadata = file.metadata().await?;
+
+        let mut header = Header::new_ustar();
+        if let Err(error) = header.set_path(relative_path) {
+            warn!(?path, %error, "skipping file with path unfit for tar");
+            continue;
+        }
+        header.set_entry_type(EntryType::Regular);
+        header.set_size(metadata.len());
+        header.set_mode(0o644);
+        header.set_mtime(
+            metadata
+                .modified()?
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+        );
+        header.set_cksum();
+
+        writer.write_all(header.as_bytes()).await?;
+        offset += TAR_BLOCK_SIZE;
+
+        // Header has the size already, a file changed since must not shift later entries.
+        let mut data = file.take(metadata.len());
+        let mut hash = provider.segment_hasher();
+        let mut copied = 0;
+
+        loop {
+            let mut buffer = BytesMut::with_capacity(ARCHIVE_PIPE_SIZE);
+            if data.read_buf(&mut buffer).await? == 0 {
+                break;
+            }
+
+            copied += buffer.len() as u64;
+            writer.write_all(&buffer).await?;
+            hash.update(buffer);
+        }
+
+        if copied != metadata.len() {
+            return Err(anyhow!("{:?} shrank during backup", path));
+        }
+
+        let padding = (TAR_BLOCK_SIZE - copied % TAR_BLOCK_SIZE) % TAR_BLOCK_SIZE;
+        writer.write_all(&vec![0; padding as usize]).await?;
+
+        trace!(?relative_path, offset, size = copied, "file archived");
+        entries.insert(
+            relative_path.to_owned(),
+            ArchiveEntry {
+                offset,
+                size: FileSize { size: copied },
+                hash: FileHash::from_bytes(hash.finalize()),
+            },
+        );
+        offset += copied + padding;
+    }
+
+    // End of archive marker.
+    writer.write_all(&[0; 2 * TAR_BLOCK_SIZE as usize]).await?;
+    writer.shutdown().await?;
+
+    Ok(entries)
+}
+
+// Extract one archived file to `dest` with a ranged download, checking entry hash.
+#[instrument(skip(provider, index))]
+pub async fn extract_entry(
+    provider: &(impl CloudProvider + ?Sized),
+    index: &ArchiveIndex,
+    relative_path: &Path,
+    dest: &Path,
+) -> Result<()> {
+    let entry = index
+        .entries
+        .get(relative_path)
+        .ok_or_else(|| anyhow!("No {:?} in archive", relative_path))?;
+
+    if let Some(key) = &index.object_key {
+        provider.add_object_keys(&BTreeMap::from([(
+            index.storage_id.to_owned(),
+            key.to_owned(),
+        )]));
+    }
+
+    download_segment(
+        provider,
+        &index.storage_id,
+        entry.offset,
+        &entry.size,
+        &entry.hash,
+        dest,
+    )
+    .await
+}
+
+// Ranged download of a file stored within another object, checking its segment hash.
+async fn download_segment(
+    provider: &(impl CloudProvider + ?Sized),
+    storage_id: &StorageId,
+    offset: u64,
+    size: &FileSize,
+    expected_hash: &FileHash,
+    dest: &Path,
+) -> Result<()> {
+    provider
+        .download_range(storage_id, offset, size.size, dest)
+        .await?;
+
+    let mut file = File::open(dest).await?;
+    let mut hash = provider.segment_hasher();
+
+    loop {
+        let mut buffer = BytesMut::with_capacity(ARCHIVE_PIPE_SIZE);
+        if file.read_buf(&mut buffer).await? == 0 {
+            break;
+        }
+
+        hash.update(buffer);
+    }
+
+    let actual_hash = FileHash::from_bytes(hash.finalize());
+
+    if actual_hash != *expected_hash {
+        remove_file(dest).await?;
+
+        return Err(anyhow!(
+            "File hash mismatch: expected {}, got {}",
+            expected_hash,
+            actual_hash,
+        ));
+    }
+
+    Ok(())
+}
+
+// Download single file to `dest`, which may be a file path or an existing directory.
+// In a directory, the file is named after `original_name` (usually manifest path)
+// or the storage id if there is no name. Returns the path written.
+#[instrument(skip(provider, expected_hash, expected_size))]
+pub async fn download_to(
+    provider: &(impl CloudProvider + ?Sized),
+    storage_id: StorageId,
+    expected_hash: &FileHash,
+    expected_size: &FileSize,
+    dest: &Path,
+    original_name: Option<&Path>,
+) -> Result<PathBuf> {
+    let path = if metadata(dest).await.is_ok_and(|m| m.is_dir()) {
+        let file_name = match original_name {
+            Some(name) => name
+                .file_name()
+                .ok_or_else(|| anyhow!("No file name in {:?} to download into directory", name))?,
+            None => storage_id.as_str().as_ref(),
+        };
+
+        dest.join(file_name)
+    } else {
+        dest.to_owned()
+    };
+
+    provider
+        .download_file(storage_id, expected_hash, expected_size, &path)
+        .await?;
+
+    Ok(path)
+}
+
+// Download all manifest files into `dest`, recreating directory structure.
+#[instrument(skip(provider, manifest))]
+pub async fn restore_dir(
+    provider: &(impl CloudProvider + ?Sized),
+    manifest: &BackupManifest,
+    dest: &Path,
+) -> Result<()> {
+    provider.add_object_keys(&manifest.object_keys);
+
+    for (relative_path, (storage_id, hash, size)) in &manifest.files {
+        let path = restore_path(dest, relative_path).await?;
+
+        trace!(
+            ?relative_path,
+            storage_id = storage_id.as_str(),
+            "restoring file"
+        );
+        provider
+            .download_file(storage_id.to_owned(), hash, size, &path)
+            .await?;
+    }
+
+    for (relative_path, file) in &manifest.packed {
+        let path = restore_path(dest, relative_path).await?;
+
+        trace!(
+            ?relative_path,
+            pack_id = file.pack_id.as_str(),
+            offset = file.offset,
+            "restoring packed file"
+        );
+        download_segment(
+            provider,
+            &file.pack_id,
+            file.offset,
+            &file.size,
+            &file.hash,
+            &path,
+        )
+        .await?;
+    }
+
+    Ok(())
+}
+
+// Path of a manifest file under `dest`, with parent directories created.
+async fn restore_path(dest: &Path, relative_path: &Path) -> Result<PathBuf> {
+    // Manifest is external input, don't let it write outside of destination.
+    if !relative_path
+        .components()
+        .all(|c| matches!(c, Component::Normal(_)))
+    {
+        return Err(anyhow!("Invalid path in manifest: {:?}", relative_path));
+    }
+
+    let path = dest.join(relative_path);
+
+    if let Some(parent) = path.parent() {
+        create_dir_all(parent).await?;
+    }
+
+    Ok(path)
+}
+
+// Recursively list regular files under `root`, as sorted relative paths.
+async fn list_files(root: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    let mut dirs = vec![PathBuf::new()];
+
+    while let Some(dir) = dirs.pop() {
+        let mut entries = match read_dir(root.join(&dir)).await {
+            Ok(entries) => entries,
+            Err(error) if !dir.as_os_str().is_empty() => {
+                warn!(?dir, %error, "skipping unreadable directory");
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        while let Some(entry) = entries.next_entry().await? {
+            let relative_path = dir.join(entry.file_name());
+            // Doesn't follow symlinks
+            let file_type = entry.file_type().await?;
+
+            if file_type.is_dir() {
+                dirs.push(relative_path);
+            } else if file_type.is_file() {
+                files.push(relative_path);
+            } else {
+                warn!(path = ?relative_path, "skipping non-regular file");
+            }
+        }
+    }
+
+    files.sort();
+
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cloud::{
+        backup_archive, backup_dir, download_to, extract_entry, plan_backup, restore_dir, run,
+        sync_dir, BackupManifest, BackupOptions, Packer, DEFAULT_PACK_SIZE,
+    };
+    use crate::crypto::init;
+    use crate::crypto::master_key::MasterKey;
+    use crate::provider::{
+        CloudProvider, Compression, FileHash, FileSize, StorageId, UploadReceipt,
+    };
+    use crate::testing::{mock_object_key, Failure, MockProvider};
+    use std::collections::BTreeMap;
+    use std::path::Path;
+    use std::path::PathBuf;
+
+    fn provider() -> MockProvider {
+        init();
+        let master_key = MasterKey::new().expect("failed to create master key");
+
+        MockProvider::new(&master_key).expect("failed to create provider")
+    }
+
+    #[tokio::test]
+    async fn backup_and_restore() {
+        let provider = provider();
+        let source = tempfile::tempdir().expect("failed to create temp dir");
+        let dest = tempfile::tempdir().expect("failed to create temp dir");
+
+        std::fs::create_dir_all(source.path().join("a/b")).expect("failed to create dir");
+        std::fs::create_dir_all(source.path().join("empty")).expect("failed to create dir");
+        std::fs::write(source.path().join("top"), b"top file").expect("failed to write file");
+        std::fs::write(source.path().join("a/b/nested"), b"nested file")
+            .expect("failed to write file");
+        #[cfg(unix)]
+        std::os::unix::fs::symlink("top", source.path().join("link"))
+            .expect("failed to create symlink");
+
+        let mut manifest = BackupManifest::default();
+        backup_dir(
+            &provider,
+            source.path(),
+            &BackupOptions::default(),
+            &mut manifest,
+        )
+        .await
+        .expect("backup failed");
+        assert_eq!(
+            manifest.files.keys().collect::<Vec<_>>(),
+            [&PathBuf::from("a/b/nested"), &PathBuf::from("top")]
+        );
+
+        restore_dir(&provider, &manifest, dest.path())
+            .await
+            .expect("restore failed");
+        assert_eq!(
+            std::fs::read(dest.path().join("top")).expect("failed to read file"),
+            b"top file"
+        );
+        assert_eq!(
+            std::fs::read(dest.path().join("a/b/nested")).expect("failed to read file"),
+            b"nested file"
+        );
+    }
+
+    #[tokio::test]
+    async fn boxed_providers() {
+        let providers: Vec<Box<dyn CloudProvider>> =
+            vec![Box::new(provider()), Box::new(provider())];
+        let source = tempfile::tempdir().expect("failed to create temp dir");
+        std::fs::write(source.path().join("file"), b"data").expect("failed to write file");
+
+        for provider in &providers {
+            run(provider.as_ref()).await.expect("run failed");
+
+            let dest = tempfile::tempdir().expect("failed to create temp dir");
+            let mut manifest = BackupManifest::default();
+            backup_dir(
+                provider.as_ref(),
+                source.path(),
+                &BackupOptions::default(),
+                &mut manifest,
+            )
+            .await
+            .expect("backup failed");
+            restore_dir(provider.as_ref(), &manifest, dest.path())
+                .await
+                .expect("restore failed");
+            assert_eq!(
+                std::fs::read(dest.path().join("file")).expect("failed to read file"),
+                b"data"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn restore_rejects_escaping_paths() {
+        let provider = provider();
+        let source = tempfile::tempdir().expect("failed to create temp dir");
+        let dest = tempfile::tempdir().expect("failed to create temp dir");
+        std::fs::write(source.path().join("file"), b"data").expect("failed to write file");
+
+        let mut manifest = BackupManifest::default();
+        backup_dir(
+            &provider,
+            source.path(),
+            &BackupOptions::default(),
+            &mut manifest,
+        )
+        .await
+        .expect("backup failed");
+        let entry = manifest.files.remove(&PathBuf::from("file")).unwrap();
+        manifest.files.insert(PathBuf::from("../escaped"), entry);
+
+        let result = restore_dir(&provider, &manifest, dest.path()).await;
+        assert!(matches!(result, Err { .. }));
+    }
+
+    #[test]
+    fn manifest_json() {
+        let mut manifest = BackupManifest::default();
+        manifest.files.insert(
+            PathBuf::from("a/file"),
+            (
+                StorageId::parse("67e55044-10b1-426f-9247-bb680e5fe0c8").expect("valid id"),
+                FileHash::from_bytes([0xab; 32]),
+                FileSize { size: 42 },
+            ),
+        );
+
+        let json = serde_json::to_value(&manifest).expect("failed to serialize manifest");
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "files": [{
+                    "path": "a/file",
+                    "storage_id": "67e55044-10b1-426f-9247-bb680e5fe0c8",
+                    "size": 42,
+                    "hash": "ab".repeat(32),
+                }]
+            })
+        );
+
+        let parsed: BackupManifest =
+            serde_json::from_value(json.clone()).expect("failed to parse manifest");
+        assert_eq!(parsed, manifest);
+
+        let mut invalid = json;
+        invalid["files"][0]["storage_id"] = "../escaped".into();
+        assert!(matches!(
+            serde_json::from_value::<BackupManifest>(invalid),
+            Err { .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn run_cleans_temp_dir() {
+        let mut provider = provider();
+        let temp_dir = tempfile::tempdir().expect("failed to create temp dir");
+        provider.set_temp_dir(temp_dir.path().to_owned());
+
+        run(&provider).await.expect("run failed");
+        assert_eq!(provider.object_count(), 1);
+        assert_eq!(
+            std::fs::read_dir(temp_dir.path())
+                .expect("failed to read dir")
+                .count(),
+            0
+        );
+
+        // Second run, download is damaged.
+        provider.fail_call(4, Failure::Corruption);
+        assert!(matches!(run(&provider).await, Err { .. }));
+        assert_eq!(
+            std::fs::read_dir(temp_dir.path())
+                .expect("failed to read dir")
+                .count(),
+            0
+        );
+    }
+
+    #[tokio::test]
+    async fn dry_run() {
+        let provider = provider();
+        let source = tempfile::tempdir().expect("failed to create temp dir");
+        std::fs::create_dir_all(source.path().join("dir")).expect("failed to create dir");
+        std::fs::write(source.path().join("dir/file1"), b"12345").expect("failed to write file");
+        std::fs::write(source.path().join("file2"), b"123").expect("failed to write file");
+
+        let plan = plan_backup(source.path()).await.expect("plan failed");
+        assert_eq!(plan.files.len(), 2);
+        assert_eq!(plan.total_size(), 8);
+
+        let options = BackupOptions {
+            dry_run: true,
+            ..Default::default()
+        };
+        let mut manifest = BackupManifest::default();
+        backup_dir(&provider, source.path(), &options, &mut manifest)
+            .await
+            .expect("backup failed");
+        assert!(manifest.files.is_empty());
+        assert_eq!(provider.calls(), 0);
+    }
+
+    #[tokio::test]
+    async fn download_into_directory() {
+        let provider = provider();
+        let dir = tempfile::tempdir().expect("failed to create temp dir");
+        let source = dir.path().join("source");
+        std::fs::write(&source, b"data").expect("failed to write file");
+        let UploadReceipt {
+            storage_id: id,
+            size,
+            hash,
+            ..
+        } = provider.upload_file(&source).await.expect("upload failed");
+
+        let named = Path::new("docs/report.txt");
+        let path = download_to(&provider, id.clone(), &hash, &size, dir.path(), Some(named))
+            .await
+            .expect("download failed");
+        assert_eq!(path, dir.path().join("report.txt"));
+        assert_eq!(std::fs::read(&path).expect("failed to read file"), b"data");
+
+        let path = download_to(&provider, id.clone(), &hash, &size, dir.path(), None)
+            .await
+            .expect("download failed");
+        assert_eq!(path, dir.path().join(id.as_str()));
+
+        let file = dir.path().join("file");
+        let path = download_to(&provider, id.clone(), &hash, &size, &file, Some(named))
+            .await
+            .expect("download failed");
+        assert_eq!(path, file);
+
+        let result = download_to(
+            &provider,
+            id,
+            &hash,
+            &size,
+            dir.path(),
+            Some(Path::new("..")),
+        )
+        .await;
+        assert!(matches!(result, Err { .. }));
+    }
+
+    #[tokio::test]
+    async fn backup_resumes_after_failure() {
+        let provider = provider();
+        let source = tempfile::tempdir().expect("failed to create temp dir");
+        for name in ["a", "b", "c"] {
+            std::fs::write(source.path().join(name), name).expect("failed to write file");
+        }
+
+        let options = BackupOptions {
+            concurrency: 1,
+            ..Default::default()
+        };
+        let mut manifest = BackupManifest::default();
+
+        provider.fail_call(2, Failure::Network);
+        let result = backup_dir(&provider, source.path(), &options, &mut manifest).await;
+        assert!(matches!(result, Err { .. }));
+        assert_eq!(manifest.files.len(), 2);
+
+        backup_dir(&provider, source.path(), &options, &mut manifest)
+            .await
+            .expect("backup failed");
+        assert_eq!(manifest.files.len(), 3);
+        assert_eq!(provider.calls(), 4);
+    }
+
+    #[tokio::test]
+    async fn sync() {
+        let provider = provider();
+        let source = tempfile::tempdir().expect("failed to create temp dir");
+        for name in ["kept", "changed", "removed"] {
+            std::fs::write(source.path().join(name), name).expect("failed to write file");
+        }
+
+        let options = BackupOptions::default();
+        let mut manifest = BackupManifest::default();
+        sync_dir(&provider, source.path(), &options, &mut manifest)
+            .await
+            .expect("sync failed");
+        assert_eq!(manifest.files.len(), 3);
+        assert_eq!(manifest.mtimes.len(), 3);
+        assert_eq!(provider.calls(), 3);
+
+        let previous = manifest.clone();
+        std::fs::write(source.path().join("changed"), "changed again")
+            .expect("failed to write file");
+        std::fs::remove_file(source.path().join("removed")).expect("failed to remove file");
+        std::fs::write(source.path().join("added"), "added").expect("failed to write file");
+
+        sync_dir(&provider, source.path(), &options, &mut manifest)
+            .await
+            .expect("sync failed");
+        assert_eq!(
+            manifest.files.keys().collect::<Vec<_>>(),
+            [
+                &PathBuf::from("added"),
+                &PathBuf::from("changed"),
+                &PathBuf::from("kept")
+            ]
+        );
+        assert_eq!(
+            manifest.files[Path::new("kept")],
+            previous.files[Path::new("kept")]
+        );
+        assert_ne!(
+            manifest.files[Path::new("changed")].0,
+            previous.files[Path::new("changed")].0
+        );
+        // Two uploads, superseded and removed objects deleted.
+        assert_eq!(provider.calls(), 7);
+        assert_eq!(provider.object_count(), 3);
+
+        let json = serde_json::to_string(&manifest).expect("failed to serialize manifest");
+        let parsed: BackupManifest = serde_json::from_str(&json).expect("failed to parse manifest");
+        assert_eq!(parsed, manifest);
+    }
+
+    #[tokio::test]
+    async fn sync_keeps_failed_deletes() {
+        let provider = provider();
+        let source = tempfile::tempdir().expect("failed to create temp dir");
+        std::fs::write(source.path().join("file"), b"data").expect("failed to write file");
+
+        let options = BackupOptions::default();
+        let mut manifest = BackupManifest::default();
+        sync_dir(&provider, source.path(), &options, &mut manifest)
+            .await
+            .expect("sync failed");
+        std::fs::remove_file(source.path().join("file")).expect("failed to remove file");
+
+        provider.fail_call(2, Failure::Network);
+        let result = sync_dir(&provider, source.path(), &options, &mut manifest).await;
+        assert!(matches!(result, Err { .. }));
+        assert_eq!(manifest.files.len(), 1);
+
+        sync_dir(&provider, source.path(), &options, &mut manifest)
+            .await
+            .expect("sync failed");
+        assert!(manifest.files.is_empty());
+        assert_eq!(provider.object_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn sync_shared_objects() {
+        let mut provider = provider();
+        provider.set_content_ids();
+        let source = tempfile::tempdir().expect("failed to create temp dir");
+        let dest = tempfile::tempdir().expect("failed to create temp dir");
+        for name in ["removed", "changed", "kept"] {
+            std::fs::write(source.path().join(name), b"data").expect("failed to write file");
+        }
+
+        let options = BackupOptions::default();
+        let mut manifest = BackupManifest::default();
+        sync_dir(&provider, source.path(), &options, &mut manifest)
+            .await
+            .expect("sync failed");
+        assert_eq!(manifest.files.len(), 3);
+        assert_eq!(provider.object_count(), 1);
+
+        std::fs::remove_file(source.path().join("removed")).expect("failed to remove file");
+        std::fs::write(source.path().join("changed"), b"changed").expect("failed to write file");
+
+        // Object is still referenced by "kept", neither removal nor replacement deletes it.
+        sync_dir(&provider, source.path(), &options, &mut manifest)
+            .await
+            .expect("sync failed");
+        assert_eq!(manifest.files.len(), 2);
+        assert_eq!(provider.object_count(), 2);
+
+        restore_dir(&provider, &manifest, dest.path())
+            .await
+            .expect("restore failed");
+        assert_eq!(
+            std::fs::read(dest.path().join("kept")).expect("failed to read file"),
+            b"data"
+        );
+        assert_eq!(
+            std::fs::read(dest.path().join("changed")).expect("failed to read file"),
+            b"changed"
+        );
+    }
+
+    #[tokio::test]
+    async fn archive_compressed() {
+        let mut provider = provider();
+        provider.set_compression(Compression::Zstd);
+        let source = tempfile::tempdir().expect("failed to create temp dir");
+        std::fs::write(source.path().join("file"), b"data").expect("failed to write file");
+
+        let result = backup_archive(&provider, source.path()).await;
+        assert!(matches!(result, Err { .. }));
+        assert_eq!(provider.calls(), 0);
+
+        let mut provider = self::provider();
+        provider.set_content_ids();
+        let result = backup_archive(&provider, source.path()).await;
+        assert!(matches!(result, Err { .. }));
+        assert_eq!(provider.calls(), 0);
+    }
+
+    #[tokio::test]
+    async fn archive() {
+        let provider = provider();
+        let source = tempfile::tempdir().expect("failed to create temp dir");
+        let dest = tempfile::tempdir().expect("failed to create temp dir");
+        std::fs::create_dir_all(source.path().join("dir")).expect("failed to create dir");
+        std::fs::write(source.path().join("dir/nested"), b"nested file")
+            .expect("failed to write file");
+        std::fs::write(source.path().join("empty"), b"").expect("failed to write file");
+        std::fs::write(source.path().join("large"), vec![7; 5000]).expect("failed to write file");
+
+        let index = backup_archive(&provider, source.path())
+            .await
+            .expect("backup failed");
+        assert_eq!(index.entries.len(), 3);
+        assert_eq!(provider.object_count(), 1);
+
+        // Stored object is a regular tar archive.
+        let data = provider
+            .object(&index.storage_id)
+            .expect("archive not stored");
+        let mut archive = tar::Archive::new(&data[..]);
+        let paths: Vec<_> = archive
+            .entries()
+            .expect("failed to read archive")
+            .map(|entry| {
+                entry
+                    .expect("bad entry")
+                    .path()
+                    .expect("bad path")
+                    .into_owned()
+            })
+            .collect();
+        assert_eq!(paths, index.entries.keys().cloned().collect::<Vec<_>>());
+
+        for (relative_path, expected) in [
+            ("dir/nested", b"nested file".to_vec()),
+            ("empty", vec![]),
+            ("large", vec![7; 5000]),
+        ] {
+            let target = dest.path().join("target");
+            extract_entry(&provider, &index, Path::new(relative_path), &target)
+                .await
+                .expect("extract failed");
+            assert_eq!(
+                std::fs::read(&target).expect("failed to read file"),
+                expected
+            );
+        }
+
+        provider.fail_call(5, Failure::Corruption);
+        let target = dest.path().join("corrupted");
+        let result = extract_entry(&provider, &index, Path::new("large"), &target).await;
+        assert!(matches!(result, Err { .. }));
+        assert!(!target.exists());
+    }
+
+    #[tokio::test]
+    async fn packer() {
+        let provider = provider();
+        let dest = tempfile::tempdir().expect("failed to create temp dir");
+        let mut manifest = BackupManifest::default();
+        let mut packer = Packer::new(&provider, 10);
+
+        packer
+            .add(PathBuf::from("a"), None, b"first", &mut manifest)
+            .await
+            .expect("add failed");
+        assert!(manifest.packed.is_empty());
+        assert_eq!(provider.object_count(), 0);
+
+        // Pack reached its size, the next file starts another one.
+        packer
+            .add(PathBuf::from("dir/b"), None, b"second", &mut manifest)
+            .await
+            .expect("add failed");
+        packer
+            .add(PathBuf::from("c"), None, b"third", &mut manifest)
+            .await
+            .expect("add failed");
+        packer.flush(&mut manifest).await.expect("flush failed");
+        assert_eq!(provider.object_count(), 2);
+
+        let first = &manifest.packed[Path::new("a")];
+        let second = &manifest.packed[Path::new("dir/b")];
+        assert_eq!(first.pack_id, second.pack_id);
+        assert_eq!((first.offset, second.offset), (0, 5));
+        assert_ne!(manifest.packed[Path::new("c")].pack_id, first.pack_id);
+        assert_eq!(
+            provider.object(&first.pack_id).expect("pack not stored"),
+            &b"firstsecond"[..]
+        );
+
+        // Hashes are keyed, the same file hashes differently under another master key.
+        let other = self::provider();
+        let mut hash = other.segment_hasher();
+        hash.update(&b"first"[..]);
+        assert_ne!(first.hash, FileHash::from_bytes(hash.finalize()));
+
+        restore_dir(&provider, &manifest, dest.path())
+            .await
+            .expect("restore failed");
+        for (relative_path, expected) in [("a", "first"), ("dir/b", "second"), ("c", "third")] {
+            assert_eq!(
+                std::fs::read(dest.path().join(relative_path)).expect("failed to read file"),
+                expected.as_bytes()
+            );
+        }
+
+        let json = serde_json::to_string(&manifest).expect("failed to serialize manifest");
+        let parsed: BackupManifest = serde_json::from_str(&json).expect("failed to parse manifest");
+        assert_eq!(parsed, manifest);
+
+        provider.fail_call(6, Failure::Corruption);
+        let result = restore_dir(&provider, &manifest, dest.path()).await;
+        assert!(matches!(result, Err { .. }));
+        assert!(!dest.path().join("a").exists());
+    }
+
+    #[tokio::test]
+    async fn sync_packed() {
+        let provider = provider();
+        let source = tempfile::tempdir().expect("failed to create temp dir");
+        for name in ["kept", "removed"] {
+            std::fs::write(source.path().join(name), name).expect("failed to write file");
+        }
+        std::fs::write(source.path().join("large"), vec![7; 200]).expect("failed to write file");
+
+        let options = BackupOptions {
+            pack_below: Some(100),
+            ..BackupOptions::default()
+        };
+        let mut manifest = BackupManifest::default();
+        backup_dir(&provider, source.path(), &options, &mut manifest)
+            .await
+            .expect("backup failed");
+        assert_eq!(
+            manifest.files.keys().collect::<Vec<_>>(),
+            [&PathBuf::from("large")]
+        );
+        assert_eq!(
+            manifest.packed.keys().collect::<Vec<_>>(),
+            [&PathBuf::from("kept"), &PathBuf::from("removed")]
+        );
+        assert_eq!(provider.object_count(), 2);
+
+        // Without recorded times everything is stored again, small files into a new pack. The
+        // old one goes with nothing left in it.
+        std::fs::remove_file(source.path().join("removed")).expect("failed to remove file");
+        sync_dir(&provider, source.path(), &options, &mut manifest)
+            .await
+            .expect("sync failed");
+        assert_eq!(
+            manifest.packed.keys().collect::<Vec<_>>(),
+            [&PathBuf::from("kept")]
+        );
+        assert_eq!(
+            manifest.files.keys().collect::<Vec<_>>(),
+            [&PathBuf::from("large")]
+        );
+        assert_eq!(provider.object_count(), 2);
+
+        // Unchanged packed file stays where it is, times survive the manifest round trip.
+        let json = serde_json::to_string(&manifest).expect("failed to serialize manifest");
+        let mut manifest: BackupManifest =
+            serde_json::from_str(&json).expect("failed to parse manifest");
+        let pack_id = manifest.packed[Path::new("kept")].pack_id.clone();
+        sync_dir(&provider, source.path(), &options, &mut manifest)
+            .await
+            .expect("sync failed");
+        assert_eq!(manifest.packed[Path::new("kept")].pack_id, pack_id);
+        assert_eq!(provider.object_count(), 2);
+
+        // Grown past the threshold, it moves out of the pack, and the large file into one.
+        std::fs::write(source.path().join("kept"), vec![1; 150]).expect("failed to write file");
+        std::fs::write(source.path().join("large"), "small").expect("failed to write file");
+        sync_dir(&provider, source.path(), &options, &mut manifest)
+            .await
+            .expect("sync failed");
+        assert_eq!(
+            manifest.files.keys().collect::<Vec<_>>(),
+            [&PathBuf::from("kept")]
+        );
+        assert_eq!(
+            manifest.packed.keys().collect::<Vec<_>>(),
+            [&PathBuf::from("large")]
+        );
+        assert_eq!(provider.object_count(), 2);
+    }
+
+    #[tokio::test]
+    async fn object_keys() {
+        let mut provider = provider();
+        provider.set_object_keys();
+        let source = tempfile::tempdir().expect("failed to create temp dir");
+        let dest = tempfile::tempdir().expect("failed to create temp dir");
+        std::fs::write(source.path().join("small"), "small").expect("failed to write file");
+        std::fs::write(source.path().join("large"), vec![7; 200]).expect("failed to write file");
+
+        let options = BackupOptions {
+            pack_below: Some(100),
+            ..BackupOptions::default()
+        };
+        let mut manifest = BackupManifest::default();
+        backup_dir(&provider, source.path(), &options, &mut manifest)
+            .await
+            .expect("backup failed");
+
+        // Keys of the file and the pack are recorded, and survive the manifest round trip.
+        let (storage_id, _, _) = manifest.files[Path::new("large")].clone();
+        let pack_id = manifest.packed[Path::new("small")].pack_id.clone();
+        let expected = BTreeMap::from([
+            (storage_id.clone(), mock_object_key(&storage_id)),
+            (pack_id.clone(), mock_object_key(&pack_id)),
+        ]);
+        assert_eq!(manifest.object_keys, expected);
+
+        let json = serde_json::to_string(&manifest).expect("failed to serialize manifest");
+        let mut manifest: BackupManifest =
+            serde_json::from_str(&json).expect("failed to parse manifest");
+        assert_eq!(manifest.object_keys, expected);
+
+        // Passed back before objects are accessed.
+        restore_dir(&provider, &manifest, dest.path())
+            .await
+            .expect("restore failed");
+        assert_eq!(provider.added_keys(), expected);
+
+        // Keys of deleted objects go with them.
+        std::fs::remove_file(source.path().join("large")).expect("failed to remove file");
+        sync_dir(&provider, source.path(), &options, &mut manifest)
+            .await
+            .expect("sync failed");
+        assert!(!manifest.object_keys.contains_key(&storage_id));
+        assert_eq!(manifest.object_keys.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn packing_options() {
+        let mut provider = provider();
+        let source = tempfile::tempdir().expect("failed to create temp dir");
+        std::fs::write(source.path().join("file"), "data").expect("failed to write file");
+        let mut manifest = BackupManifest::default();
+
+        let too_large = BackupOptions {
+            pack_below: Some(DEFAULT_PACK_SIZE as u64 + 1),
+            ..BackupOptions::default()
+        };
+        let result = backup_dir(&provider, source.path(), &too_large, &mut manifest).await;
+        assert!(matches!(result, Err { .. }));
+
+        // Rejected before anything is stored.
+        provider.set_compression(Compression::Zstd);
+        let options = BackupOptions {
+            pack_below: Some(100),
+            ..BackupOptions::default()
+        };
+        let result = backup_dir(&provider, source.path(), &options, &mut manifest).await;
+        assert!(matches!(result, Err { .. }));
+        let result = sync_dir(&provider, source.path(), &options, &mut manifest).await;
+        assert!(matches!(result, Err { .. }));
+        assert_eq!(provider.object_count(), 0);
+
+        let mut content_addressed = self::provider();
+        content_addressed.set_content_ids();
+        let result = backup_dir(&content_addressed, source.path(), &options, &mut manifest).await;
+        assert!(matches!(result, Err { .. }));
+        assert_eq!(content_addressed.object_count(), 0);
+
+        backup_dir(
+            &provider,
+            source.path(),
+            &BackupOptions::default(),
+            &mut manifest,
+        )
+        .await
+        .expect("backup failed");
+        assert_eq!(provider.object_count(), 1);
+    }
+}
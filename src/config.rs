@@ -0,0 +1,277 @@
+use crate::crypto::auth::{AuthKey, AUTH_TAG_SIZE};
+use crate::crypto::hash::{ChunkedHash, HashKeys, HashKind};
+use crate::crypto::master_key::MasterKey;
+use crate::crypto::passphrase::{PassphraseCost, PassphraseKey, SALT_SIZE};
+use crate::error::CloudError;
+use crate::provider::CloudProviderConfig;
+use anyhow::{anyhow, Result};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+// Where new configs get the master key. MASTER_KEY_KEYRING names a key store entry as
+// "service/account", recorded in the config in place of the key; plain MASTER_KEY remains for
+// headless servers without a secret store.
+pub(crate) enum EnvMasterKey {
+    Key(String),
+    Entry(String),
+}
+
+pub(crate) fn env_master_key() -> Result<EnvMasterKey> {
+    if let Ok(name) = std::env::var("MASTER_KEY_KEYRING") {
+        return Ok(EnvMasterKey::Entry(name));
+    }
+
+    match std::env::var("MASTER_KEY") {
+        Ok(key) => Ok(EnvMasterKey::Key(key)),
+        Err(_) => Err(anyhow!("MASTER_KEY is not set")),
+    }
+}
+
+// Hex master key held by a key store entry named as "service/account".
+fn load_entry_key(name: &str) -> Result<String> {
+    #[cfg(feature = "keyring")]
+    {
+        let (service, account) = crate::crypto::key_store::parse_entry_name(name)?;
+        crate::crypto::key_store::load_master_key(service, account)
+    }
+
+    #[cfg(not(feature = "keyring"))]
+    Err(anyhow!(
+        "Master key is in key store entry {:?}, key store needs the keyring feature",
+        name
+    ))
+}
+
+// Pickle decodes only signed 64-bit integers, so u64 config fields are stored as i64 of the
+// same bits. Values below 2^63 are stored unchanged, as they were before.
+pub(crate) mod pickle_u64 {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub(crate) fn serialize<S: Serializer>(value: &u64, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_i64(*value as i64)
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+        Ok(i64::deserialize(deserializer)? as u64)
+    }
+}
+
+// Provider config that carries the master key it is authenticated with, or names the key store
+// entry holding it. Keys from the store are never serialized, they are filled in on opening.
+pub(crate) trait SealedConfig: Serialize + DeserializeOwned {
+    fn master_key(&self) -> &str;
+
+    fn key_entry(&self) -> Option<&str>;
+
+    fn set_master_key(&mut self, master_key: String);
+}
+
+// Master key of a config, from the key store if the config names an entry.
+pub(crate) fn config_master_key(config: &impl SealedConfig) -> Result<MasterKey> {
+    match config.key_entry() {
+        Some(name) => MasterKey::from(&load_entry_key(name)?),
+        None => MasterKey::from(config.master_key()),
+    }
+}
+
+// Serialized config is followed by MAC keyed from the master key. The master key itself is
+// stored in the same blob, so the MAC can't stop anyone able to read the config from forging
+// it. It detects corruption and blind edits of the non-key fields (e.g. bucket name).
+pub(crate) fn seal_config(config: &impl SealedConfig) -> Result<CloudProviderConfig> {
+    crate::crypto::init();
+
+    let mut writer = BytesMut::with_capacity(1024).writer();
+    serde_pickle::to_writer(&mut writer, config, serde_pickle::SerOptions::new())?;
+    let mut data = writer.into_inner();
+
+    let master_key = config_master_key(config)?;
+    let config_auth_key = AuthKey::new(&master_key, 1, "config")?;
+    let tag = config_auth_key.sign(&data);
+    data.put_slice(&tag);
+
+    Ok(CloudProviderConfig {
+        data: data.freeze(),
+    })
+}
+
+pub(crate) fn open_config<T: SealedConfig>(config: &CloudProviderConfig) -> Result<T> {
+    if config.data.len() < AUTH_TAG_SIZE {
+        return Err(anyhow!("Config is too short"));
+    }
+
+    let (payload, tag) = config.data.split_at(config.data.len() - AUTH_TAG_SIZE);
+    let mut provider_config: T =
+        serde_pickle::from_reader(payload.reader(), serde_pickle::DeOptions::new())?;
+
+    if let Some(name) = provider_config.key_entry() {
+        let master_key = load_entry_key(name)?;
+        provider_config.set_master_key(master_key);
+    }
+
+    let master_key = MasterKey::from(provider_config.master_key())?;
+    let config_auth_key = AuthKey::new(&master_key, 1, "config")?;
+    config_auth_key
+        .verify(payload, tag)
+        .map_err(|_| anyhow!("Config authentication failed: config is corrupted or modified"))?;
+
+    Ok(provider_config)
+}
+
+// Config file contents: the sealed config encrypted with a passphrase key, as JSON with hex
+// fields. Everything is encrypted, the other fields only describe how to derive the key.
+#[derive(Serialize, Deserialize)]
+struct EncryptedConfig {
+    version: u32,
+    salt: String,
+    opslimit: u64,
+    memlimit: usize,
+    data: String,
+}
+
+const ENCRYPTED_CONFIG_VERSION: u32 = 1;
+
+impl CloudProviderConfig {
+    // Text for a config file holding credentials and master key, safe to keep in dotfiles or
+    // a repo as long as the passphrase is strong.
+    pub fn encrypt(&self, passphrase: &str) -> Result<String> {
+        encrypt_config(self, passphrase, PassphraseCost::default())
+    }
+
+    pub fn decrypt(text: &str, passphrase: &str) -> Result<CloudProviderConfig> {
+        crate::crypto::init();
+
+        let file: EncryptedConfig = serde_json::from_str(text)?;
+
+        if file.version != ENCRYPTED_CONFIG_VERSION {
+            return Err(anyhow!("Unsupported config file version {}", file.version));
+        }
+
+        let salt: [u8; SALT_SIZE] = hex::decode(&file.salt)?
+            .try_into()
+            .map_err(|_| anyhow!("Invalid config file salt size"))?;
+        let cost = PassphraseCost {
+            opslimit: file.opslimit,
+            memlimit: file.memlimit,
+        };
+        cost.check()?;
+        let key = PassphraseKey::derive(passphrase, &salt, cost)?;
+        let data = key.open(&hex::decode(&file.data)?)?;
+
+        // Decrypted config holds the master key, its memory is wiped once the last clone of
+        // the config is dropped, normally after the provider has loaded it.
+        Ok(CloudProviderConfig {
+            data: Bytes::from_owner(data),
+        })
+    }
+}
+
+fn encrypt_config(
+    config: &CloudProviderConfig,
+    passphrase: &str,
+    cost: PassphraseCost,
+) -> Result<String> {
+    crate::crypto::init();
+
+    let salt = crate::crypto::passphrase::random_salt();
+    let key = PassphraseKey::derive(passphrase, &salt, cost)?;
+    let file = EncryptedConfig {
+        version: ENCRYPTED_CONFIG_VERSION,
+        salt: hex::encode(salt),
+        opslimit: cost.opslimit,
+        memlimit: cost.memlimit,
+        data: hex::encode(key.seal(&config.data)),
+    };
+
+    Ok(serde_json::to_string_pretty(&file)?)
+}
+
+// Known input hashed with the file hash key when config is created. Rechecked on load to tell
+// a wrong master key or changed hash keying from corrupted objects.
+const HASH_CHECK_INPUT: &[u8] = b"private-cloud hash check";
+
+pub(crate) fn hash_check(hash_keys: &HashKeys) -> String {
+    let mut hash = ChunkedHash::keyed(hash_keys.get(HashKind::File));
+    hash.update(HASH_CHECK_INPUT);
+
+    hex::encode(hash.finalize())
+}
+
+// Identifies hash keys in object metadata. Truncated, it only has to tell apart the few keys
+// of one config.
+#[cfg(feature = "aws")]
+pub(crate) fn key_fingerprint(hash_keys: &HashKeys) -> String {
+    let mut fingerprint = hash_check(hash_keys);
+    fingerprint.truncate(16);
+
+    fingerprint
+}
+
+// Configs created before the check was recorded have nothing to compare against.
+pub(crate) fn verify_hash_check(hash_keys: &HashKeys, expected: Option<&str>) -> Result<()> {
+    match expected {
+        Some(expected) if hash_check(hash_keys) != expected => Err(CloudError::KeyMismatch.into()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{encrypt_config, hash_check, verify_hash_check};
+    use crate::crypto::hash::HashKeys;
+    use crate::crypto::init;
+    use crate::crypto::master_key::MasterKey;
+    use crate::crypto::passphrase::PassphraseCost;
+    use crate::error::CloudError;
+    use crate::provider::CloudProviderConfig;
+
+    #[test]
+    fn encrypted_config() {
+        let config = CloudProviderConfig {
+            data: "secret config data".into(),
+        };
+        let cost = PassphraseCost {
+            opslimit: 1,
+            memlimit: 8192,
+        };
+
+        let text = encrypt_config(&config, "passphrase", cost).expect("failed to encrypt");
+        assert!(!text.contains(&hex::encode("secret config data")));
+
+        let decrypted =
+            CloudProviderConfig::decrypt(&text, "passphrase").expect("failed to decrypt");
+        assert_eq!(decrypted.data, config.data);
+
+        let result = CloudProviderConfig::decrypt(&text, "wrong passphrase");
+        assert!(matches!(result, Err { .. }));
+        let result = CloudProviderConfig::decrypt(
+            &text.replace("\"version\": 1", "\"version\": 2"),
+            "passphrase",
+        );
+        assert!(matches!(result, Err { .. }));
+        let result = CloudProviderConfig::decrypt(
+            &text.replace("\"memlimit\": 8192", "\"memlimit\": 1099511627776"),
+            "passphrase",
+        );
+        assert!(matches!(result, Err { .. }));
+    }
+
+    #[test]
+    fn hash_check_mismatch() {
+        init();
+        let keys = HashKeys::new(&MasterKey::new().expect("failed to create master key"), 0)
+            .expect("failed to create hash keys");
+        let other_keys = HashKeys::new(&MasterKey::new().expect("failed to create master key"), 0)
+            .expect("failed to create hash keys");
+        let check = hash_check(&keys);
+
+        assert!(verify_hash_check(&keys, Some(&check)).is_ok());
+        assert!(verify_hash_check(&keys, None).is_ok());
+
+        let result = verify_hash_check(&other_keys, Some(&check));
+        assert!(matches!(
+            result.unwrap_err().downcast_ref::<CloudError>(),
+            Some(CloudError::KeyMismatch)
+        ));
+    }
+}
@@ -0,0 +1,98 @@
+use crate::crypto::master_key::MasterKey;
+use crate::crypto::secure_memory::SecureMemory;
+use anyhow::{anyhow, Result};
+use libsodium_sys::{crypto_auth, crypto_auth_BYTES, crypto_auth_KEYBYTES, crypto_auth_verify};
+
+pub const AUTH_TAG_SIZE: usize = crypto_auth_BYTES as usize;
+const AUTH_KEY_SIZE: usize = crypto_auth_KEYBYTES as usize;
+
+pub struct AuthKey {
+    data: SecureMemory,
+}
+
+impl std::fmt::Debug for AuthKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AuthKey").field("data", &"*****").finish()
+    }
+}
+
+impl AuthKey {
+    pub fn new(master_key: &MasterKey, keyid: u64, context: &str) -> Result<AuthKey> {
+        let data = master_key.subkey(AUTH_KEY_SIZE, keyid, context)?;
+
+        Ok(AuthKey { data })
+    }
+
+    pub fn sign(&self, message: &[u8]) -> [u8; AUTH_TAG_SIZE] {
+        let mut tag = [0; AUTH_TAG_SIZE];
+
+        unsafe {
+            crypto_auth(
+                tag.as_mut_ptr(),
+                message.as_ptr(),
+                message.len() as u64,
+                self.data.as_ptr(),
+            );
+        }
+
+        tag
+    }
+
+    pub fn verify(&self, message: &[u8], tag: &[u8]) -> Result<()> {
+        if tag.len() != AUTH_TAG_SIZE {
+            return Err(anyhow!("Invalid authentication tag size"));
+        }
+
+        let result = unsafe {
+            crypto_auth_verify(
+                tag.as_ptr(),
+                message.as_ptr(),
+                message.len() as u64,
+                self.data.as_ptr(),
+            )
+        };
+
+        if result != 0 {
+            return Err(anyhow!("Authentication tag mismatch"));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::crypto::auth::AuthKey;
+    use crate::crypto::init;
+    use crate::crypto::master_key::MasterKey;
+
+    #[test]
+    fn sign_and_verify() {
+        init();
+        let master_key = MasterKey::new().expect("failed to create master key");
+        let key = AuthKey::new(&master_key, 1, "ctx").expect("failed to create auth key");
+        let message = b"This is test message";
+
+        let tag = key.sign(message);
+
+        assert!(matches!(key.verify(message, &tag), Ok { .. }));
+    }
+
+    #[test]
+    fn verify_tampered() {
+        init();
+        let master_key = MasterKey::new().expect("failed to create master key");
+        let key = AuthKey::new(&master_key, 1, "ctx").expect("failed to create auth key");
+        let other_key = AuthKey::new(&master_key, 2, "ctx").expect("failed to create auth key");
+        let message = b"This is test message";
+
+        let tag = key.sign(message);
+
+        assert!(matches!(
+            key.verify(b"This is test massage", &tag),
+            Err { .. }
+        ));
+        assert!(matches!(key.verify(message, &tag[1..]), Err { .. }));
+        assert!(matches!(other_key.verify(message, &tag), Err { .. }));
+    }
+}
@@ -1,15 +1,24 @@
 use crate::crypto::master_key::MasterKey;
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use bytes::Buf;
 use libsodium_sys::{
-    crypto_generichash_BYTES, crypto_generichash_KEYBYTES, crypto_generichash_final,
-    crypto_generichash_init, crypto_generichash_state, crypto_generichash_update,
+    crypto_generichash, crypto_generichash_BYTES, crypto_generichash_KEYBYTES,
+    crypto_generichash_blake2b_PERSONALBYTES, crypto_generichash_blake2b_salt_personal,
+    crypto_generichash_final, crypto_generichash_init, crypto_generichash_state,
+    crypto_generichash_update, crypto_hash_sha256_BYTES, crypto_hash_sha256_final,
+    crypto_hash_sha256_init, crypto_hash_sha256_state, crypto_hash_sha256_update, randombytes_buf,
+    sodium_memcmp, sodium_memzero,
 };
+use serde::{Deserialize, Serialize};
+use std::io::Read;
+use std::pin::Pin;
+use std::task::{Context, Poll};
 
-const HASH_SIZE: usize = crypto_generichash_BYTES as usize;
+pub const HASH_SIZE: usize = crypto_generichash_BYTES as usize;
 const HASH_KEY_SIZE: usize = crypto_generichash_KEYBYTES as usize;
 
 // Not using protected memory for this key: it is for hash value randomization, not for security.
+// Still zeroed on drop, it is derived from the master key.
 pub struct HashKey {
     opaque: [u8; HASH_KEY_SIZE],
 }
@@ -24,17 +33,144 @@ impl std::fmt::Debug for HashKey {
 
 impl HashKey {
     pub fn new(master_key: &MasterKey, keyid: u64, context: &str) -> Result<HashKey> {
-        let mut key = [0; HASH_KEY_SIZE];
+        // Derived in place, a local array would leave a copy on the stack.
+        let mut key = HashKey {
+            opaque: [0; HASH_KEY_SIZE],
+        };
 
-        master_key.derive_subkey(&mut key, keyid, context)?;
+        master_key.derive_subkey(&mut key.opaque, keyid, context)?;
 
-        Ok(HashKey { opaque: key })
+        Ok(key)
     }
+
+    // Subkey for one use of this key. Personalization keeps it apart from every plain keyed
+    // hash, whatever data was hashed. Needs only this key, not the master key, so holders of a
+    // hash key can derive it.
+    fn subkey(&self, personal: &[u8; PERSONAL_SIZE]) -> HashKey {
+        let mut key = HashKey {
+            opaque: [0; HASH_KEY_SIZE],
+        };
+
+        unsafe {
+            crypto_generichash_blake2b_salt_personal(
+                key.opaque.as_mut_ptr(),
+                key.opaque.len(),
+                std::ptr::null(),
+                0,
+                self.opaque.as_ptr(),
+                self.opaque.len(),
+                std::ptr::null(),
+                personal.as_ptr(),
+            );
+        }
+
+        key
+    }
+}
+
+const PERSONAL_SIZE: usize = crypto_generichash_blake2b_PERSONALBYTES as usize;
+
+impl Drop for HashKey {
+    fn drop(&mut self) {
+        // Plain writes before deallocation may be optimized out, sodium_memzero is not.
+        unsafe {
+            sodium_memzero(self.opaque.as_mut_ptr() as *mut _, self.opaque.len());
+        }
+    }
+}
+
+// Kinds of hashed objects. Each gets its own key, so bytes hashed as one kind can't be passed
+// off as another.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum HashKind {
+    File,
+    Manifest,
+    Chunk,
+}
+
+impl HashKind {
+    // KDF contexts are 8 bytes, longer names would be truncated.
+    fn context(self) -> &'static str {
+        match self {
+            HashKind::File => "filehash",
+            HashKind::Manifest => "mnfshash",
+            HashKind::Chunk => "chnkhash",
+        }
+    }
+}
+
+// Random per-bucket salt, so buckets sharing a master key have unlinkable hashes.
+// Top bit is clear: configs are pickled, which decodes only signed 64-bit integers.
+pub fn random_salt() -> u64 {
+    let mut salt = [0; 8];
+    unsafe { randombytes_buf(salt.as_mut_ptr() as *mut _, salt.len()) };
+
+    u64::from_le_bytes(salt) >> 1
+}
+
+// Hash keys for all object kinds, derived up front.
+#[derive(Debug)]
+pub struct HashKeys {
+    file: HashKey,
+    manifest: HashKey,
+    chunk: HashKey,
+}
+
+impl HashKeys {
+    // Salt is mixed into the subkey id. Zero salt gives the keys used before salting.
+    pub fn new(master_key: &MasterKey, salt: u64) -> Result<HashKeys> {
+        let key = |kind: HashKind| HashKey::new(master_key, 1 ^ salt, kind.context());
+
+        Ok(HashKeys {
+            file: key(HashKind::File)?,
+            manifest: key(HashKind::Manifest)?,
+            chunk: key(HashKind::Chunk)?,
+        })
+    }
+
+    pub fn get(&self, kind: HashKind) -> &HashKey {
+        match kind {
+            HashKind::File => &self.file,
+            HashKind::Manifest => &self.manifest,
+            HashKind::Chunk => &self.chunk,
+        }
+    }
+}
+
+// Constant time comparison, hashes are keyed and checked against untrusted data.
+pub fn hashes_equal(a: &[u8; HASH_SIZE], b: &[u8; HASH_SIZE]) -> bool {
+    unsafe { sodium_memcmp(a.as_ptr() as *const _, b.as_ptr() as *const _, HASH_SIZE) == 0 }
+}
+
+// Both produce HASH_SIZE bytes.
+const _: () = assert!(crypto_hash_sha256_BYTES as usize == HASH_SIZE);
+
+// Blake2b is libsodium generichash, keyed for stored files. Sha256 ignores the key: it is only
+// for matching digests computed elsewhere.
+#[derive(Copy, Debug, Clone, Default, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub enum HashAlgo {
+    #[default]
+    Blake2b,
+    Sha256,
+}
+
+// One per transfer, boxing the larger state would only add an allocation.
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone, Debug)]
+enum HashState {
+    Blake2b(crypto_generichash_state),
+    Sha256(crypto_hash_sha256_state),
 }
 
 #[derive(Clone, Debug)]
 pub struct ChunkedHash {
-    state: crypto_generichash_state,
+    state: HashState,
+}
+
+impl Default for ChunkedHash {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl ChunkedHash {
@@ -46,7 +182,9 @@ impl ChunkedHash {
             crypto_generichash_init(&mut state, std::ptr::null(), 0, HASH_SIZE);
         }
 
-        ChunkedHash { state }
+        ChunkedHash {
+            state: HashState::Blake2b(state),
+        }
     }
 
     pub fn keyed(key: &HashKey) -> ChunkedHash {
@@ -57,7 +195,32 @@ impl ChunkedHash {
             crypto_generichash_init(&mut state, key.opaque.as_ptr(), key.opaque.len(), HASH_SIZE);
         }
 
-        ChunkedHash { state }
+        ChunkedHash {
+            state: HashState::Blake2b(state),
+        }
+    }
+
+    pub fn sha256() -> ChunkedHash {
+        let mut state = crypto_hash_sha256_state {
+            state: [0; 8],
+            count: 0,
+            buf: [0; 64],
+        };
+
+        unsafe {
+            crypto_hash_sha256_init(&mut state);
+        }
+
+        ChunkedHash {
+            state: HashState::Sha256(state),
+        }
+    }
+
+    pub fn with_algo(algo: HashAlgo, key: &HashKey) -> ChunkedHash {
+        match algo {
+            HashAlgo::Blake2b => ChunkedHash::keyed(key),
+            HashAlgo::Sha256 => ChunkedHash::sha256(),
+        }
     }
 
     pub fn update(&mut self, mut data: impl Buf) {
@@ -66,7 +229,14 @@ impl ChunkedHash {
             let chunklen = chunk.len();
 
             unsafe {
-                crypto_generichash_update(&mut self.state, chunk.as_ptr(), chunklen as u64);
+                match &mut self.state {
+                    HashState::Blake2b(state) => {
+                        crypto_generichash_update(state, chunk.as_ptr(), chunklen as u64);
+                    }
+                    HashState::Sha256(state) => {
+                        crypto_hash_sha256_update(state, chunk.as_ptr(), chunklen as u64);
+                    }
+                }
             }
 
             data.advance(chunklen);
@@ -77,16 +247,284 @@ impl ChunkedHash {
         let mut hash = [0; HASH_SIZE];
 
         unsafe {
-            crypto_generichash_final(&mut self.state, hash.as_mut_ptr(), hash.len());
+            match &mut self.state {
+                HashState::Blake2b(state) => {
+                    crypto_generichash_final(state, hash.as_mut_ptr(), hash.len());
+                }
+                HashState::Sha256(state) => {
+                    crypto_hash_sha256_final(state, hash.as_mut_ptr());
+                }
+            }
         }
 
         hash
     }
 }
 
+// Writes are hashed, so the hash can be a sink for io combinators, e.g. in a tee with upload.
+impl std::io::Write for ChunkedHash {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.update(buf);
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+// Never pending, hashing is done inline.
+impl tokio::io::AsyncWrite for ChunkedHash {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        self.get_mut().update(buf);
+
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+// Block and root hashes of data hashed by MerkleHasher. Recorded next to the data, so one block
+// can be checked without reading the others.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct MerkleHash {
+    pub block_size: u64,
+    pub size: u64,
+    #[serde(with = "hex_hashes")]
+    pub blocks: Vec<[u8; HASH_SIZE]>,
+    #[serde(with = "hex_hash")]
+    pub root: [u8; HASH_SIZE],
+}
+
+// Tree hash for data in fixed size blocks. Each block is hashed under its own key, so equal
+// blocks at different positions hash differently. Root is the hash of data size followed by
+// block hashes, under another subkey: no block key or block hash can pass for a root. Blocks
+// hash independently of each other, which spreads the work across cores, unlike a single
+// ChunkedHash. Multipart uploads record one block per part.
+// Not compatible with ChunkedHash: root differs from the plain hash of the same data.
+#[derive(Debug)]
+pub struct MerkleHasher {
+    block_key: HashKey,
+    root_key: HashKey,
+    block_size: usize,
+}
+
+impl MerkleHasher {
+    pub fn new(key: &HashKey, block_size: usize) -> Result<MerkleHasher> {
+        if block_size == 0 {
+            return Err(anyhow!("Block size must be positive"));
+        }
+
+        Ok(MerkleHasher {
+            block_key: key.subkey(b"merkle-block-key"),
+            root_key: key.subkey(b"merkle-root-key\0"),
+            block_size,
+        })
+    }
+
+    // Keys for blocks, in block order.
+    pub fn block_keys(&self) -> impl Iterator<Item = HashKey> + '_ {
+        (0..).map(|index| self.block_key(index))
+    }
+
+    // Keyed hash of the little-endian index.
+    fn block_key(&self, index: u64) -> HashKey {
+        let mut key = HashKey {
+            opaque: [0; HASH_KEY_SIZE],
+        };
+        let index = index.to_le_bytes();
+
+        unsafe {
+            crypto_generichash(
+                key.opaque.as_mut_ptr(),
+                key.opaque.len(),
+                index.as_ptr(),
+                index.len() as u64,
+                self.block_key.opaque.as_ptr(),
+                self.block_key.opaque.len(),
+            );
+        }
+
+        key
+    }
+
+    // For blocks that arrive in pieces.
+    pub fn block_hash(&self, index: u64) -> ChunkedHash {
+        ChunkedHash::keyed(&self.block_key(index))
+    }
+
+    pub fn hash_block(&self, index: u64, data: impl Buf) -> [u8; HASH_SIZE] {
+        let mut hash = self.block_hash(index);
+        hash.update(data);
+
+        hash.finalize()
+    }
+
+    pub fn root(&self, size: u64, blocks: &[[u8; HASH_SIZE]]) -> [u8; HASH_SIZE] {
+        let mut hash = ChunkedHash::keyed(&self.root_key);
+        hash.update(&size.to_le_bytes()[..]);
+
+        for block in blocks {
+            hash.update(&block[..]);
+        }
+
+        hash.finalize()
+    }
+
+    // Block hashes of data of `size` bytes, with their root.
+    pub fn tree(&self, size: u64, blocks: Vec<[u8; HASH_SIZE]>) -> MerkleHash {
+        MerkleHash {
+            block_size: self.block_size as u64,
+            size,
+            root: self.root(size, &blocks),
+            blocks,
+        }
+    }
+
+    pub fn hash(&self, data: &[u8]) -> MerkleHash {
+        let mut blocks = vec![[0; HASH_SIZE]; data.len().div_ceil(self.block_size)];
+        self.hash_blocks(0, data, &mut blocks);
+
+        self.tree(data.len() as u64, blocks)
+    }
+
+    // Reads a batch of blocks per core at a time, memory use doesn't grow with data size.
+    pub fn hash_reader(&self, mut reader: impl Read) -> Result<MerkleHash> {
+        let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+        let mut buffer = vec![0; threads * self.block_size];
+        let mut blocks = Vec::new();
+        let mut size = 0;
+
+        loop {
+            let mut filled = 0;
+
+            while filled < buffer.len() {
+                match reader.read(&mut buffer[filled..]) {
+                    Ok(0) => break,
+                    Ok(n) => filled += n,
+                    Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                    Err(e) => return Err(e.into()),
+                }
+            }
+
+            let first = blocks.len();
+            blocks.resize(first + filled.div_ceil(self.block_size), [0; HASH_SIZE]);
+            self.hash_blocks(first as u64, &buffer[..filled], &mut blocks[first..]);
+            size += filled as u64;
+
+            if filled < buffer.len() {
+                return Ok(self.tree(size, blocks));
+            }
+        }
+    }
+
+    // Blocks starting at number `first` are split evenly between threads, one per core.
+    fn hash_blocks(&self, first: u64, data: &[u8], hashes: &mut [[u8; HASH_SIZE]]) {
+        let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+        let per_thread = hashes.len().div_ceil(threads).max(1);
+
+        std::thread::scope(|scope| {
+            let shards = data.chunks(per_thread * self.block_size);
+
+            for (shard_index, (shard, hashes)) in
+                shards.zip(hashes.chunks_mut(per_thread)).enumerate()
+            {
+                scope.spawn(move || {
+                    let first = first + (shard_index * per_thread) as u64;
+
+                    for (index, (block, hash)) in
+                        (first..).zip(shard.chunks(self.block_size).zip(hashes))
+                    {
+                        *hash = self.hash_block(index, block);
+                    }
+                });
+            }
+        });
+    }
+
+    // Whether recorded block hashes add up to the recorded root, for this block size.
+    pub fn verify_root(&self, recorded: &MerkleHash) -> bool {
+        recorded.block_size == self.block_size as u64
+            && recorded.blocks.len() as u64 == recorded.size.div_ceil(recorded.block_size)
+            && hashes_equal(&self.root(recorded.size, &recorded.blocks), &recorded.root)
+    }
+
+    // Checks one block against recorded hashes, and that the recorded hashes add up to the root.
+    pub fn verify_block(&self, recorded: &MerkleHash, index: u64, data: impl Buf) -> bool {
+        let expected = match recorded.blocks.get(index as usize) {
+            Some(expected) => expected,
+            None => return false,
+        };
+
+        self.verify_root(recorded) && hashes_equal(&self.hash_block(index, data), expected)
+    }
+}
+
+// Hashes in hex, as FileHash is serialized.
+mod hex_hash {
+    use super::HASH_SIZE;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        hash: &[u8; HASH_SIZE],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&hex::encode(hash))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<[u8; HASH_SIZE], D::Error> {
+        let s = String::deserialize(deserializer)?;
+        let mut hash = [0; HASH_SIZE];
+        hex::decode_to_slice(&s, &mut hash).map_err(serde::de::Error::custom)?;
+
+        Ok(hash)
+    }
+}
+
+mod hex_hashes {
+    use super::HASH_SIZE;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        hashes: &[[u8; HASH_SIZE]],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(hashes.iter().map(hex::encode))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<[u8; HASH_SIZE]>, D::Error> {
+        Vec::<String>::deserialize(deserializer)?
+            .iter()
+            .map(|s| {
+                let mut hash = [0; HASH_SIZE];
+                hex::decode_to_slice(s, &mut hash).map_err(serde::de::Error::custom)?;
+
+                Ok(hash)
+            })
+            .collect()
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::crypto::hash::{ChunkedHash, HashKey};
+    use crate::crypto::hash::{
+        random_salt, ChunkedHash, HashAlgo, HashKey, HashKeys, HashKind, MerkleHash, MerkleHasher,
+    };
     use crate::crypto::init;
     use crate::crypto::master_key::MasterKey;
     use bytes::{Buf, Bytes};
@@ -205,4 +643,156 @@ mod tests {
         assert_ne!(result_ctx2, result_context1);
         assert_eq!(result_ctx1, result_ctx1_dup);
     }
+
+    #[test]
+    fn hash_kinds() {
+        init();
+
+        let master_key = MasterKey::new().expect("failed to create master key");
+        let keys = HashKeys::new(&master_key, 0).expect("failed to create hash keys");
+        let file_key = HashKey::new(&master_key, 1, "filehash").expect("failed to create hash key");
+        let data = Bytes::from("This is test message");
+
+        let hash_with = |key: &HashKey| {
+            let mut hash = ChunkedHash::keyed(key);
+            hash.update(data.to_owned());
+            hash.finalize()
+        };
+
+        let file = hash_with(keys.get(HashKind::File));
+        let manifest = hash_with(keys.get(HashKind::Manifest));
+        let chunk = hash_with(keys.get(HashKind::Chunk));
+
+        assert_eq!(file, hash_with(&file_key));
+        assert_ne!(file, manifest);
+        assert_ne!(file, chunk);
+        assert_ne!(manifest, chunk);
+    }
+
+    #[tokio::test]
+    async fn write_traits() {
+        init();
+
+        let data = b"This is test message".repeat(1000);
+        let mut expected = ChunkedHash::new();
+        expected.update(&data[..]);
+        let expected = expected.finalize();
+
+        let mut hash = ChunkedHash::new();
+        std::io::copy(&mut &data[..], &mut hash).expect("failed to copy");
+        assert_eq!(hash.finalize(), expected);
+
+        let mut hash = ChunkedHash::new();
+        tokio::io::copy(&mut &data[..], &mut hash)
+            .await
+            .expect("failed to copy");
+        assert_eq!(hash.finalize(), expected);
+    }
+
+    #[test]
+    fn salted_keys() {
+        init();
+
+        let master_key = MasterKey::new().expect("failed to create master key");
+        let data = Bytes::from("This is test message");
+
+        let hash_with = |salt: u64| {
+            let keys = HashKeys::new(&master_key, salt).expect("failed to create hash keys");
+            let mut hash = ChunkedHash::keyed(keys.get(HashKind::File));
+            hash.update(data.to_owned());
+            hash.finalize()
+        };
+
+        assert_eq!(hash_with(42), hash_with(42));
+        assert_ne!(hash_with(0), hash_with(42));
+        assert_ne!(hash_with(random_salt()), hash_with(random_salt()));
+        assert!((0..100).all(|_| random_salt() <= i64::MAX as u64));
+    }
+
+    #[test]
+    fn sha256() {
+        init();
+
+        let master_key = MasterKey::new().expect("failed to create master key");
+        let key = HashKey::new(&master_key, 1, "ctx").expect("failed to create hash key");
+
+        let mut hash = ChunkedHash::with_algo(HashAlgo::Sha256, &key);
+        hash.update(Bytes::from("This is "));
+        hash.update(Bytes::from("test message"));
+
+        assert_eq!(
+            hex::encode(hash.finalize()),
+            "dddbdc2845c9d80dc288710d9b2cf2d6c4f613d0dc4c048a9ea0e8674c2c5e73"
+        );
+    }
+
+    #[test]
+    fn merkle_hash() {
+        init();
+
+        let master_key = MasterKey::new().expect("failed to create master key");
+        let key = HashKey::new(&master_key, 1, "ctx").expect("failed to create hash key");
+        let hasher = MerkleHasher::new(&key, 1000).expect("failed to create hasher");
+        let data: Vec<u8> = (0..24680).map(|i| i as u8).collect();
+
+        let merkle = hasher.hash(&data);
+        assert_eq!(merkle.size, data.len() as u64);
+        assert_eq!(merkle.blocks.len(), 25);
+
+        // Same as hashing blocks one by one with the iterated keys.
+        for ((block, hash), block_key) in data
+            .chunks(1000)
+            .zip(&merkle.blocks)
+            .zip(hasher.block_keys())
+        {
+            let mut expected = ChunkedHash::keyed(&block_key);
+            expected.update(block);
+            assert_eq!(&expected.finalize(), hash);
+        }
+        assert_eq!(merkle.root, hasher.root(merkle.size, &merkle.blocks));
+        assert_eq!(
+            hasher
+                .hash_reader(&data[..])
+                .expect("failed to hash reader"),
+            merkle
+        );
+        let exact = &data[..8000];
+        assert_eq!(
+            hasher.hash_reader(exact).expect("failed to hash reader"),
+            hasher.hash(exact)
+        );
+
+        // Roots are under a key of their own: the root of empty data, the hash of eight zero
+        // bytes, isn't any block's key or hash.
+        let empty = hasher.hash(&[]);
+        let mut plain = ChunkedHash::keyed(&key);
+        plain.update(&0u64.to_le_bytes()[..]);
+        assert_ne!(empty.root, plain.finalize());
+        assert_ne!(empty.root, hasher.hash_block(0, &0u64.to_le_bytes()[..]));
+        assert_ne!(empty.root, hasher.block_key(0).opaque);
+
+        // Equal blocks at different positions hash differently.
+        let repeated = MerkleHasher::new(&key, 20)
+            .expect("failed to create hasher")
+            .hash(&b"This is test message".repeat(2));
+        assert_ne!(repeated.blocks[0], repeated.blocks[1]);
+
+        let json = serde_json::to_string(&merkle).expect("failed to serialize");
+        let recorded: MerkleHash = serde_json::from_str(&json).expect("failed to deserialize");
+        assert_eq!(recorded, merkle);
+
+        assert!(hasher.verify_block(&recorded, 3, &data[3000..4000]));
+        assert!(hasher.verify_block(&recorded, 24, &data[24000..]));
+        assert!(!hasher.verify_block(&recorded, 4, &data[3000..4000]));
+        assert!(!hasher.verify_block(&recorded, 25, &data[3000..4000]));
+
+        let mut tampered = recorded.clone();
+        tampered.blocks[5] = tampered.blocks[6];
+        assert!(!hasher.verify_block(&tampered, 5, &data[6000..7000]));
+
+        let other = MerkleHasher::new(&key, 999).expect("failed to create hasher");
+        assert!(!other.verify_block(&recorded, 3, &data[3000..4000]));
+        assert_ne!(hasher.hash(&[]).root, hasher.hash(&[0]).root);
+        assert!(matches!(MerkleHasher::new(&key, 0), Err { .. }));
+    }
 }
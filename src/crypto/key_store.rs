@@ -0,0 +1,68 @@
+use crate::crypto::master_key::MasterKey;
+use anyhow::{anyhow, Result};
+use keyring::Entry;
+
+// Master key kept in platform secret store (Secret Service, macOS Keychain, Windows Credential
+// Manager) under service and account name. Stored as hex, same as MASTER_KEY env.
+pub fn load_master_key(service: &str, account: &str) -> Result<String> {
+    crate::crypto::init();
+    let master_key = on_own_thread(|| Entry::new(service, account)?.get_password())?;
+    MasterKey::from(&master_key)?;
+
+    Ok(master_key)
+}
+
+// Invalid keys are rejected before they replace a working one.
+pub fn store_master_key(service: &str, account: &str, master_key: &str) -> Result<()> {
+    crate::crypto::init();
+    MasterKey::from(master_key)?;
+
+    on_own_thread(|| Entry::new(service, account)?.set_password(master_key))
+}
+
+// Secret Service calls on the tokio backend deadlock on a runtime thread, so they get a thread
+// of their own.
+fn on_own_thread<T: Send>(call: impl FnOnce() -> keyring::Result<T> + Send) -> Result<T> {
+    match std::thread::scope(|scope| scope.spawn(call).join()) {
+        Ok(result) => Ok(result?),
+        Err(_) => Err(anyhow!("Key store call panicked")),
+    }
+}
+
+// Entry is named as "service/account", account may contain further slashes.
+pub fn parse_entry_name(name: &str) -> Result<(&str, &str)> {
+    match name.split_once('/') {
+        Some((service, account)) if !service.is_empty() && !account.is_empty() => {
+            Ok((service, account))
+        }
+        _ => Err(anyhow!(
+            "Invalid key store entry {:?}, expected service/account",
+            name
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::crypto::key_store::parse_entry_name;
+
+    #[test]
+    fn entry_name() {
+        assert_eq!(
+            parse_entry_name("private-cloud/backup").ok(),
+            Some(("private-cloud", "backup"))
+        );
+        assert_eq!(
+            parse_entry_name("private-cloud/team/backup").ok(),
+            Some(("private-cloud", "team/backup"))
+        );
+
+        for name in ["", "private-cloud", "/backup", "private-cloud/"] {
+            assert!(
+                matches!(parse_entry_name(name), Err { .. }),
+                "{:?} accepted",
+                name
+            );
+        }
+    }
+}
@@ -2,11 +2,17 @@ use crate::crypto::secure_memory::SecureMemory;
 use anyhow::{anyhow, Result};
 use libc::c_char;
 use libsodium_sys::{
-    crypto_kdf_CONTEXTBYTES, crypto_kdf_KEYBYTES, crypto_kdf_derive_from_key, crypto_kdf_keygen,
+    crypto_kdf_BYTES_MAX, crypto_kdf_BYTES_MIN, crypto_kdf_CONTEXTBYTES, crypto_kdf_KEYBYTES,
+    crypto_kdf_derive_from_key, crypto_kdf_keygen,
 };
+use std::io::{ErrorKind, Read};
 
 const MASTER_KEY_SIZE: usize = crypto_kdf_KEYBYTES as usize;
 const CONTEXT_SIZE: usize = crypto_kdf_CONTEXTBYTES as usize;
+const SUBKEY_MIN_SIZE: usize = crypto_kdf_BYTES_MIN as usize;
+const SUBKEY_MAX_SIZE: usize = crypto_kdf_BYTES_MAX as usize;
+// Hex key with room for surrounding whitespace, such as a trailing newline.
+const MAX_KEY_INPUT: usize = 2 * MASTER_KEY_SIZE + 16;
 
 #[derive(Debug)]
 pub struct MasterKey {
@@ -27,9 +33,7 @@ impl MasterKey {
     pub fn from(hex: &str) -> Result<MasterKey> {
         let bytes = hex::decode(hex)?;
 
-        if bytes.len() != MASTER_KEY_SIZE {
-            return Err(anyhow!("Invalid master key size"));
-        }
+        check_key(&bytes)?;
 
         let mut data = SecureMemory::new(MASTER_KEY_SIZE)?;
 
@@ -38,6 +42,43 @@ impl MasterKey {
         Ok(MasterKey { data })
     }
 
+    // Key delivered through a pipe or file descriptor, as by secrets agents and systemd
+    // credentials. Takes hex with optional surrounding whitespace, or exactly the raw key bytes.
+    // Input is read into secure memory, which is wiped when freed, so no copy is left behind.
+    pub fn from_reader(reader: &mut impl Read) -> Result<MasterKey> {
+        // One byte over the limit, to tell overlong input from input of exactly the limit.
+        let mut buffer = SecureMemory::new(MAX_KEY_INPUT + 1)?;
+        let mut len = 0;
+
+        while len < buffer.len() {
+            match reader.read(&mut buffer.as_mut()[len..]) {
+                Ok(0) => break,
+                Ok(n) => len += n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        if len > MAX_KEY_INPUT {
+            return Err(anyhow!("Master key input too long"));
+        }
+
+        let input = &buffer.as_ref()[..len];
+        let mut data = SecureMemory::new(MASTER_KEY_SIZE)?;
+
+        if len == MASTER_KEY_SIZE {
+            data.as_mut().copy_from_slice(input);
+        } else {
+            // Decode error would name the offending character, which is part of the key.
+            hex::decode_to_slice(input.trim_ascii(), data.as_mut())
+                .map_err(|_| anyhow!("Invalid master key, expected hex or raw bytes"))?;
+        }
+
+        check_key(data.as_ref())?;
+
+        Ok(MasterKey { data })
+    }
+
     pub fn derive_subkey(&self, subkey: &mut [u8], subkey_id: u64, context: &str) -> Result<()> {
         let mut ctx: [c_char; CONTEXT_SIZE] = [0; CONTEXT_SIZE];
 
@@ -61,12 +102,69 @@ impl MasterKey {
 
         Ok(())
     }
+
+    // Derive purpose-specific subkey into protected memory.
+    pub fn subkey(&self, len: usize, id: u64, context: &str) -> Result<SecureMemory> {
+        if !(SUBKEY_MIN_SIZE..=SUBKEY_MAX_SIZE).contains(&len) {
+            return Err(anyhow!("Invalid subkey size"));
+        }
+
+        let mut subkey = SecureMemory::new(len)?;
+
+        self.derive_subkey(subkey.as_mut(), id, context)?;
+
+        Ok(subkey)
+    }
+}
+
+// Copy goes into its own secure allocation, sharing the pointer would free it twice.
+// Clone can't fail, so allocation failure panics like it does for regular heap memory.
+impl Clone for MasterKey {
+    fn clone(&self) -> Self {
+        let mut data =
+            SecureMemory::new(MASTER_KEY_SIZE).expect("Error allocating secure memory for clone");
+
+        data.as_mut().copy_from_slice(self.data.as_ref());
+
+        MasterKey { data }
+    }
+}
+
+fn check_key(key: &[u8]) -> Result<()> {
+    if key.len() != MASTER_KEY_SIZE {
+        return Err(anyhow!("Invalid master key size"));
+    }
+
+    if is_weak_key(key) {
+        return Err(anyhow!(
+            "Master key is too weak: zero, repeating or sequential bytes"
+        ));
+    }
+
+    Ok(())
+}
+
+// Catch placeholder and degenerate keys. Random key has ~30 distinct bytes, so the false positive
+// probability is negligible.
+fn is_weak_key(key: &[u8]) -> bool {
+    const MIN_DISTINCT_BYTES: usize = 8;
+
+    let mut seen = [false; 256];
+    for &b in key {
+        seen[b as usize] = true;
+    }
+    let distinct = seen.iter().filter(|&&s| s).count();
+
+    let step = key[1].wrapping_sub(key[0]);
+    let sequential = key.windows(2).all(|w| w[1].wrapping_sub(w[0]) == step);
+
+    distinct < MIN_DISTINCT_BYTES || sequential
 }
 
 #[cfg(test)]
 mod tests {
     use crate::crypto::init;
-    use crate::crypto::master_key::{MasterKey, MASTER_KEY_SIZE};
+    use crate::crypto::master_key::{MasterKey, MASTER_KEY_SIZE, SUBKEY_MAX_SIZE, SUBKEY_MIN_SIZE};
 
     #[test]
     fn create_and_drop() {
@@ -85,12 +183,64 @@ mod tests {
     #[test]
     fn from_valid_size() {
         init();
-        let bytes = [43; MASTER_KEY_SIZE];
-        let hexdump = hex::encode(bytes);
-        let k = MasterKey::from(&hexdump);
+        let k = MasterKey::from("ce747155fe6b9557a083f95b51e7b0d0e4112950686110927b77a2ed589e8c0e");
         assert!(matches!(k, Ok { .. }));
     }
 
+    #[test]
+    fn from_weak() {
+        init();
+        let zero = [0; MASTER_KEY_SIZE];
+        let same = [43; MASTER_KEY_SIZE];
+        let mut repeating = [0; MASTER_KEY_SIZE];
+        let mut sequential = [0; MASTER_KEY_SIZE];
+
+        for i in 0..MASTER_KEY_SIZE {
+            repeating[i] = b"0123"[i % 4];
+            sequential[i] = (i * 7 + 200) as u8;
+        }
+
+        for bytes in [zero, same, repeating, sequential] {
+            let k = MasterKey::from(&hex::encode(bytes));
+            assert!(matches!(k, Err { .. }), "{:?} accepted", bytes);
+        }
+    }
+
+    #[test]
+    fn from_reader() {
+        init();
+        let hex = "ce747155fe6b9557a083f95b51e7b0d0e4112950686110927b77a2ed589e8c0e";
+        let mut expected = [0; 32];
+        MasterKey::from(hex)
+            .expect("MasterKey::from() failed")
+            .derive_subkey(&mut expected, 1, "foobar")
+            .expect("Key derivation failed");
+
+        let raw = hex::decode(hex).expect("invalid hex");
+        let with_newline = format!("{}\n", hex);
+        for input in [hex.as_bytes(), with_newline.as_bytes(), &raw] {
+            let key = MasterKey::from_reader(&mut &input[..]).expect("from_reader() failed");
+            let mut subkey = [0; 32];
+            key.derive_subkey(&mut subkey, 1, "foobar")
+                .expect("Key derivation failed");
+            assert_eq!(subkey, expected);
+        }
+
+        let too_long = format!("{}{}", hex, hex);
+        let invalid = hex.replace('c', "x");
+        let weak = [0; MASTER_KEY_SIZE];
+        for input in [
+            too_long.as_bytes(),
+            invalid.as_bytes(),
+            &raw[1..],
+            &weak,
+            b"",
+        ] {
+            let k = MasterKey::from_reader(&mut &input[..]);
+            assert!(matches!(k, Err { .. }), "{:?} accepted", input);
+        }
+    }
+
     #[test]
     fn derive() {
         init();
@@ -111,4 +261,55 @@ mod tests {
 
         assert_eq!(subkey1, subkey3);
     }
+
+    #[test]
+    fn subkey() {
+        init();
+        let key = MasterKey::new().expect("MasterKey::new() failed");
+
+        let subkey1 = key.subkey(32, 1, "foobar").expect("Key derivation failed");
+        let subkey2 = key.subkey(32, 2, "foobar").expect("Key derivation failed");
+        assert_ne!(subkey1.as_ref(), subkey2.as_ref());
+
+        let mut raw = [0; 32];
+        key.derive_subkey(&mut raw, 1, "foobar")
+            .expect("Key derivation failed");
+        assert_eq!(subkey1.as_ref(), raw);
+    }
+
+    #[test]
+    fn subkey_invalid_size() {
+        init();
+        let key = MasterKey::new().expect("MasterKey::new() failed");
+
+        assert!(matches!(
+            key.subkey(SUBKEY_MIN_SIZE - 1, 1, "ctx"),
+            Err { .. }
+        ));
+        assert!(matches!(
+            key.subkey(SUBKEY_MAX_SIZE + 1, 1, "ctx"),
+            Err { .. }
+        ));
+        assert!(matches!(key.subkey(SUBKEY_MIN_SIZE, 1, "ctx"), Ok { .. }));
+        assert!(matches!(key.subkey(SUBKEY_MAX_SIZE, 1, "ctx"), Ok { .. }));
+    }
+
+    #[test]
+    fn clone() {
+        init();
+        let key = MasterKey::new().expect("MasterKey::new() failed");
+        let mut expected = [0; 32];
+        key.derive_subkey(&mut expected, 1, "foobar")
+            .expect("Key derivation failed");
+
+        let cloned = key.clone();
+        assert_ne!(cloned.data.as_ptr(), key.data.as_ptr());
+        drop(key);
+
+        let mut subkey = [0; 32];
+        cloned
+            .derive_subkey(&mut subkey, 1, "foobar")
+            .expect("Key derivation failed");
+        assert_eq!(subkey, expected);
+    }
 }
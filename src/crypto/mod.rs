@@ -1,6 +1,10 @@
+pub mod auth;
 pub mod hash;
+#[cfg(feature = "keyring")]
+pub mod key_store;
 pub mod master_key;
+pub mod passphrase;
 pub mod secure_memory;
 mod util;
 
-pub use util::init;
+pub use util::{generichash_implementation, init, version};
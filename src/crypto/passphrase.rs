@@ -0,0 +1,248 @@
+use crate::crypto::secure_memory::SecureMemory;
+use anyhow::{anyhow, Result};
+use libsodium_sys::{
+    crypto_pwhash, crypto_pwhash_ALG_ARGON2ID13, crypto_pwhash_MEMLIMIT_MODERATE,
+    crypto_pwhash_MEMLIMIT_SENSITIVE, crypto_pwhash_OPSLIMIT_MODERATE,
+    crypto_pwhash_OPSLIMIT_SENSITIVE, crypto_pwhash_SALTBYTES, crypto_secretbox_KEYBYTES,
+    crypto_secretbox_MACBYTES, crypto_secretbox_NONCEBYTES, crypto_secretbox_easy,
+    crypto_secretbox_open_easy, randombytes_buf, sodium_memzero,
+};
+
+pub const SALT_SIZE: usize = crypto_pwhash_SALTBYTES as usize;
+const KEY_SIZE: usize = crypto_secretbox_KEYBYTES as usize;
+const NONCE_SIZE: usize = crypto_secretbox_NONCEBYTES as usize;
+const MAC_SIZE: usize = crypto_secretbox_MACBYTES as usize;
+
+// Argon2id cost, recorded with sealed data so it can be raised later without breaking old data.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct PassphraseCost {
+    pub opslimit: u64,
+    pub memlimit: usize,
+}
+
+// Libsodium's "moderate" level: 256MiB and about a second per attempt, for data that may sit
+// where anyone can try passphrases offline.
+impl Default for PassphraseCost {
+    fn default() -> Self {
+        PassphraseCost {
+            opslimit: u64::from(crypto_pwhash_OPSLIMIT_MODERATE),
+            memlimit: crypto_pwhash_MEMLIMIT_MODERATE as usize,
+        }
+    }
+}
+
+impl PassphraseCost {
+    // Cost read from a file is untrusted: without a cap a crafted file makes every derivation
+    // allocate as much as it asks for. Libsodium's "sensitive" level is the most we ever use.
+    pub fn check(&self) -> Result<()> {
+        if self.opslimit > u64::from(crypto_pwhash_OPSLIMIT_SENSITIVE)
+            || self.memlimit > crypto_pwhash_MEMLIMIT_SENSITIVE as usize
+        {
+            return Err(anyhow!(
+                "Passphrase cost {}/{} exceeds the supported maximum",
+                self.opslimit,
+                self.memlimit
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+// Passphrase text, wiped on drop. Copies made by the terminal or environment are out of reach.
+pub struct Passphrase(String);
+
+impl Passphrase {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<String> for Passphrase {
+    fn from(passphrase: String) -> Self {
+        Passphrase(passphrase)
+    }
+}
+
+impl PartialEq for Passphrase {
+    fn eq(&self, other: &Self) -> bool {
+        self.0 == other.0
+    }
+}
+
+impl Drop for Passphrase {
+    fn drop(&mut self) {
+        // Plain writes before deallocation may be optimized out, sodium_memzero is not.
+        unsafe {
+            sodium_memzero(self.0.as_mut_ptr().cast(), self.0.len());
+        }
+    }
+}
+
+impl std::fmt::Debug for Passphrase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Passphrase(*****)")
+    }
+}
+
+pub fn random_salt() -> [u8; SALT_SIZE] {
+    let mut salt = [0; SALT_SIZE];
+
+    unsafe {
+        randombytes_buf(salt.as_mut_ptr().cast(), salt.len());
+    }
+
+    salt
+}
+
+// Secretbox key derived from a passphrase.
+pub struct PassphraseKey {
+    data: SecureMemory,
+}
+
+impl std::fmt::Debug for PassphraseKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PassphraseKey")
+            .field("data", &"*****")
+            .finish()
+    }
+}
+
+impl PassphraseKey {
+    pub fn derive(
+        passphrase: &str,
+        salt: &[u8; SALT_SIZE],
+        cost: PassphraseCost,
+    ) -> Result<PassphraseKey> {
+        if passphrase.is_empty() {
+            return Err(anyhow!("Passphrase must not be empty"));
+        }
+
+        let mut data = SecureMemory::new(KEY_SIZE)?;
+
+        let result = unsafe {
+            crypto_pwhash(
+                data.as_mut_ptr(),
+                KEY_SIZE as u64,
+                passphrase.as_ptr().cast(),
+                passphrase.len() as u64,
+                salt.as_ptr(),
+                cost.opslimit,
+                cost.memlimit,
+                crypto_pwhash_ALG_ARGON2ID13 as i32,
+            )
+        };
+
+        // Fails only for limits out of range or if memory can't be allocated.
+        if result != 0 {
+            return Err(anyhow!("Error deriving key from passphrase"));
+        }
+
+        Ok(PassphraseKey { data })
+    }
+
+    // Random nonce followed by ciphertext with its MAC.
+    pub fn seal(&self, message: &[u8]) -> Vec<u8> {
+        let mut sealed = vec![0; NONCE_SIZE + MAC_SIZE + message.len()];
+        let (nonce, ciphertext) = sealed.split_at_mut(NONCE_SIZE);
+
+        unsafe {
+            randombytes_buf(nonce.as_mut_ptr().cast(), nonce.len());
+            crypto_secretbox_easy(
+                ciphertext.as_mut_ptr(),
+                message.as_ptr(),
+                message.len() as u64,
+                nonce.as_ptr(),
+                self.data.as_ptr(),
+            );
+        }
+
+        sealed
+    }
+
+    // Opened message is in secure memory, wiped when freed like keys.
+    pub fn open(&self, sealed: &[u8]) -> Result<SecureMemory> {
+        if sealed.len() < NONCE_SIZE + MAC_SIZE {
+            return Err(anyhow!("Sealed data is too short"));
+        }
+
+        let (nonce, ciphertext) = sealed.split_at(NONCE_SIZE);
+        let mut message = SecureMemory::new(ciphertext.len() - MAC_SIZE)?;
+
+        let result = unsafe {
+            crypto_secretbox_open_easy(
+                message.as_mut_ptr(),
+                ciphertext.as_ptr(),
+                ciphertext.len() as u64,
+                nonce.as_ptr(),
+                self.data.as_ptr(),
+            )
+        };
+
+        // Wrong passphrase and damaged data look the same.
+        if result != 0 {
+            return Err(anyhow!(
+                "Decryption failed: wrong passphrase or corrupted data"
+            ));
+        }
+
+        Ok(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::crypto::init;
+    use crate::crypto::passphrase::{random_salt, PassphraseCost, PassphraseKey};
+    use libsodium_sys::{crypto_pwhash_MEMLIMIT_SENSITIVE, crypto_pwhash_OPSLIMIT_SENSITIVE};
+
+    // Minimum cost, default one takes 256MiB per derivation.
+    const TEST_COST: PassphraseCost = PassphraseCost {
+        opslimit: 1,
+        memlimit: 8192,
+    };
+
+    #[test]
+    fn seal_and_open() {
+        init();
+        let salt = random_salt();
+        let key =
+            PassphraseKey::derive("correct horse", &salt, TEST_COST).expect("failed to derive key");
+        let message = b"This is test message";
+
+        let sealed = key.seal(message);
+        assert_ne!(key.seal(message), sealed);
+        assert_eq!(key.open(&sealed).expect("failed to open").as_ref(), message);
+
+        let mut damaged = sealed.clone();
+        damaged[30] ^= 1;
+        assert!(matches!(key.open(&damaged), Err { .. }));
+        assert!(matches!(key.open(&sealed[..10]), Err { .. }));
+
+        let other =
+            PassphraseKey::derive("wrong horse", &salt, TEST_COST).expect("failed to derive key");
+        assert!(matches!(other.open(&sealed), Err { .. }));
+        assert!(matches!(
+            PassphraseKey::derive("", &salt, TEST_COST),
+            Err { .. }
+        ));
+    }
+
+    #[test]
+    fn cost_limits() {
+        assert!(TEST_COST.check().is_ok());
+        assert!(PassphraseCost::default().check().is_ok());
+
+        let huge_memory = PassphraseCost {
+            opslimit: 1,
+            memlimit: crypto_pwhash_MEMLIMIT_SENSITIVE as usize + 1,
+        };
+        assert!(matches!(huge_memory.check(), Err { .. }));
+
+        let huge_ops = PassphraseCost {
+            opslimit: u64::from(crypto_pwhash_OPSLIMIT_SENSITIVE) + 1,
+            memlimit: 8192,
+        };
+        assert!(matches!(huge_ops.check(), Err { .. }));
+    }
+}
@@ -6,6 +6,7 @@ use std::ffi::c_void;
 pub struct SecureMemory {
     data: *mut c_void,
     size: usize,
+    freed: bool,
 }
 
 unsafe impl Sync for SecureMemory {}
@@ -13,13 +14,34 @@ unsafe impl Send for SecureMemory {}
 
 impl SecureMemory {
     pub fn new(size: usize) -> Result<SecureMemory> {
+        // sodium_malloc(0) behavior is unspecified; represent empty memory with null pointer.
+        if size == 0 {
+            return Ok(SecureMemory {
+                data: std::ptr::null_mut(),
+                size,
+                freed: false,
+            });
+        }
+
         let data = unsafe { sodium_malloc(size) };
 
         if data.is_null() {
             return Err(anyhow!("Error allocating secure memory"));
         }
 
-        Ok(SecureMemory { data, size })
+        Ok(SecureMemory {
+            data,
+            size,
+            freed: false,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
     }
 
     pub fn as_ptr(&self) -> *const u8 {
@@ -29,12 +51,32 @@ impl SecureMemory {
     pub fn as_mut_ptr(&mut self) -> *mut u8 {
         self.data as *mut u8
     }
+
+    // Wipes and releases memory before drop. Memory is empty afterwards, freeing it again is a
+    // bug caught in debug builds and ignored otherwise.
+    pub fn free(&mut self) {
+        debug_assert!(!self.freed, "SecureMemory double free");
+
+        if self.freed {
+            return;
+        }
+
+        if !self.data.is_null() {
+            unsafe {
+                sodium_free(self.data);
+            }
+        }
+
+        self.data = std::ptr::null_mut();
+        self.size = 0;
+        self.freed = true;
+    }
 }
 
 impl Drop for SecureMemory {
     fn drop(&mut self) {
-        unsafe {
-            sodium_free(self.data);
+        if !self.freed {
+            self.free();
         }
     }
 }
@@ -42,12 +84,20 @@ impl Drop for SecureMemory {
 // There is no guarantee about allocation alignment. u8 is a safe cast target.
 impl AsRef<[u8]> for SecureMemory {
     fn as_ref(&self) -> &[u8] {
+        if self.data.is_null() {
+            return &[];
+        }
+
         unsafe { std::slice::from_raw_parts(self.data as *const u8, self.size) }
     }
 }
 
 impl AsMut<[u8]> for SecureMemory {
     fn as_mut(&mut self) -> &mut [u8] {
+        if self.data.is_null() {
+            return &mut [];
+        }
+
         unsafe { std::slice::from_raw_parts_mut(self.data as *mut u8, self.size) }
     }
 }
@@ -62,10 +112,51 @@ mod tests {
         init();
         let mut m = SecureMemory::new(50).expect("SecureMemory allocation failed");
 
-        let slice = m.as_mut();
-
-        for i in 0..slice.len() {
-            slice[i] = (i % 100) as u8;
+        for (i, byte) in m.as_mut().iter_mut().enumerate() {
+            *byte = (i % 100) as u8;
         }
     }
+
+    #[test]
+    fn len() {
+        init();
+        let m = SecureMemory::new(50).expect("SecureMemory allocation failed");
+
+        assert_eq!(m.len(), 50);
+        assert!(!m.is_empty());
+        assert_eq!(m.as_ref().len(), 50);
+    }
+
+    #[test]
+    fn zero_length() {
+        init();
+        let mut m = SecureMemory::new(0).expect("SecureMemory allocation failed");
+
+        assert_eq!(m.len(), 0);
+        assert!(m.is_empty());
+        assert!(m.as_ref().is_empty());
+        assert!(m.as_mut().is_empty());
+    }
+
+    #[test]
+    fn free() {
+        init();
+        let mut m = SecureMemory::new(50).expect("SecureMemory allocation failed");
+
+        m.free();
+        assert!(m.is_empty());
+        assert!(m.as_ref().is_empty());
+        assert!(m.as_ptr().is_null());
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "SecureMemory double free")]
+    fn double_free() {
+        init();
+        let mut m = SecureMemory::new(50).expect("SecureMemory allocation failed");
+
+        m.free();
+        m.free();
+    }
 }
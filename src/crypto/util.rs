@@ -1,7 +1,50 @@
-use libsodium_sys::sodium_init;
+use libsodium_sys::{
+    sodium_init, sodium_runtime_has_avx2, sodium_runtime_has_ssse3, sodium_version_string,
+};
+use std::ffi::CStr;
+use tracing::info;
 
 pub fn init() {
+    // Zero only for the call that actually initialized the library.
+    if unsafe { sodium_init() } == 0 {
+        info!(
+            version = version(),
+            generichash = generichash_implementation(),
+            "libsodium initialized"
+        );
+    }
+}
+
+// Version of the linked libsodium, which may differ from the one bindings were built for.
+pub fn version() -> &'static str {
+    unsafe { CStr::from_ptr(sodium_version_string()) }
+        .to_str()
+        .unwrap_or("unknown")
+}
+
+// Blake2b implementation picked by libsodium init, from the same CPU feature checks.
+// Builds without optimized implementations use "ref" regardless, this can't tell them apart.
+pub fn generichash_implementation() -> &'static str {
     unsafe {
-        sodium_init();
+        if sodium_runtime_has_avx2() != 0 {
+            "avx2"
+        } else if sodium_runtime_has_ssse3() != 0 {
+            "ssse3"
+        } else {
+            "ref"
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::crypto::util::{generichash_implementation, init, version};
+
+    #[test]
+    fn version_info() {
+        init();
+
+        assert!(version().starts_with(|c: char| c.is_ascii_digit()));
+        assert!(["avx2", "ssse3", "ref"].contains(&generichash_implementation()));
     }
 }
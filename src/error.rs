@@ -0,0 +1,115 @@
+use std::time::Duration;
+use thiserror::Error;
+
+// Failures callers may want to handle specifically. Returned wrapped in anyhow::Error,
+// use `downcast_ref::<CloudError>()` to inspect.
+#[derive(Debug, Error)]
+pub enum CloudError {
+    #[error("Operation timed out after {0:?}")]
+    Timeout(Duration),
+    #[error("Bucket {0:?} not found")]
+    BucketNotFound(String),
+    #[error("Access to bucket {0:?} denied")]
+    AccessDenied(String),
+    #[error("Hash key mismatch, wrong master key?")]
+    KeyMismatch,
+    // Downloaded object has the expected size, but its hash is under a master key that isn't
+    // configured, by the fingerprint recorded on upload. Data is likely fine.
+    #[error(
+        "Object {storage_id} was uploaded under another master key (fingerprint {fingerprint}), \
+        check the master key or add the original one as a previous key"
+    )]
+    WrongKey {
+        storage_id: String,
+        fingerprint: String,
+    },
+    #[error("Upload exceeds size limit of {0} bytes")]
+    SizeLimitExceeded(u64),
+    #[error("Unknown AWS region {0:?}, other services need an endpoint URL")]
+    UnknownRegion(String),
+    #[error("Object {0:?} not found")]
+    ObjectNotFound(String),
+    #[error("Object {0:?} already exists")]
+    AlreadyExists(String),
+    #[error("Download target {0:?} already exists")]
+    TargetExists(std::path::PathBuf),
+    #[error("Object {0:?} is locked")]
+    ObjectLocked(String),
+    // Resumed download found the object replaced since the first attempt.
+    #[error("Object {0:?} changed during download")]
+    ObjectChanged(String),
+    // Data of one part didn't match its hash recorded on upload. Numbered from 1, `offset` is
+    // where the part starts in the stored object.
+    #[error("Part {part} hash mismatch at offset {offset}")]
+    PartCorrupted { part: u32, offset: u64 },
+    #[error("Download truncated: expected {expected} bytes, got {actual}")]
+    Truncated { expected: u64, actual: u64 },
+    #[error("Insufficient disk space: {needed} bytes needed, {available} available")]
+    InsufficientSpace { needed: u64, available: u64 },
+    // Counts object bytes saved before the failed write, compressed objects count stored bytes
+    // rather than the larger decompressed output.
+    #[error("Disk full after writing {written} bytes")]
+    DiskFull {
+        written: u64,
+        #[source]
+        source: std::io::Error,
+    },
+    // Session credentials (SSO, STS) ran out, a wrong secret key is reported as Request instead.
+    #[error("{operation} failed: credentials expired, renew the session and retry")]
+    CredentialsExpired {
+        operation: &'static str,
+        #[source]
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+    #[error("{operation} failed: request time too far from server time, check the system clock")]
+    ClockSkew {
+        operation: &'static str,
+        #[source]
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+    // Storage service rejected the request. Ids are for provider support tickets.
+    #[error(
+        "{operation} failed (request id {}, extended request id {})",
+        .request_id.as_deref().unwrap_or("unknown"),
+        .extended_request_id.as_deref().unwrap_or("unknown")
+    )]
+    Request {
+        operation: &'static str,
+        request_id: Option<String>,
+        extended_request_id: Option<String>,
+        #[source]
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+}
+
+// Saving downloaded data failed. Full disk is reported specifically, so callers can tell the
+// user why instead of showing a generic I/O error.
+#[cfg(any(feature = "aws", feature = "azure"))]
+pub(crate) fn write_error(source: std::io::Error, written: u64) -> anyhow::Error {
+    match source.kind() {
+        std::io::ErrorKind::StorageFull | std::io::ErrorKind::WriteZero => {
+            CloudError::DiskFull { written, source }.into()
+        }
+        _ => source.into(),
+    }
+}
+
+#[cfg(all(test, any(feature = "aws", feature = "azure")))]
+mod tests {
+    use crate::error::{write_error, CloudError};
+    use std::io::{Error, ErrorKind};
+
+    #[test]
+    fn disk_full() {
+        for kind in [ErrorKind::StorageFull, ErrorKind::WriteZero] {
+            let error = write_error(Error::from(kind), 42);
+            assert!(matches!(
+                error.downcast_ref::<CloudError>(),
+                Some(CloudError::DiskFull { written: 42, .. })
+            ));
+        }
+
+        let error = write_error(Error::from(ErrorKind::PermissionDenied), 42);
+        assert!(error.downcast_ref::<CloudError>().is_none());
+    }
+}
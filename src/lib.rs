@@ -1,4 +1,16 @@
+#[cfg(feature = "aws")]
 pub mod aws;
+#[cfg(feature = "azure")]
+pub mod azure;
+#[cfg(feature = "blocking")]
+pub mod blocking;
 pub mod cloud;
-mod crypto;
+#[cfg(any(feature = "aws", feature = "azure"))]
+mod config;
+pub mod crypto;
+pub mod error;
+#[cfg(feature = "aws")]
+pub mod null;
 pub mod provider;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
@@ -1,23 +1,266 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use clap::{Parser, Subcommand, ValueEnum};
 use private_cloud::aws::{create_aws_config, AWS};
-use private_cloud::provider::CloudProvider;
+use private_cloud::cloud::{
+    backup_archive, backup_dir, plan_backup, BackupManifest, BackupOptions,
+    DEFAULT_BACKUP_CONCURRENCY,
+};
+#[cfg(feature = "keyring")]
+use private_cloud::crypto::key_store::{parse_entry_name, store_master_key};
+use private_cloud::crypto::passphrase::Passphrase;
+use private_cloud::provider::{CloudProvider, CloudProviderConfig, CloudProviderFactory, FileSize};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
 use tracing_subscriber::filter::EnvFilter;
 
-async fn run() -> Result<()> {
-    let config = create_aws_config()?;
-    let provider = AWS::load_from_config(config).await?;
-    private_cloud::cloud::run(&provider).await
+#[derive(Parser)]
+#[command(version, about)]
+struct Args {
+    /// Encrypted config file written by create, instead of config from environment.
+    /// Passphrase is taken from PRIVATE_CLOUD_PASSPHRASE or prompted for
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ManifestFormat {
+    /// One line per file
+    Text,
+    /// Single JSON document
+    Json,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Write config from environment (KEYID, SECRETKEY, MASTER_KEY) to an encrypted file
+    Create {
+        /// Config file to write, must not exist
+        path: PathBuf,
+    },
+    /// Save master key from MASTER_KEY in the platform secret store. Create then
+    /// records the entry instead of the key if MASTER_KEY_KEYRING names it
+    #[cfg(feature = "keyring")]
+    StoreKey {
+        /// Key store entry as service/account
+        entry: String,
+    },
+    /// Check that storage is reachable with configured credentials
+    Connect,
+    /// Upload and download test file
+    Run,
+    /// Time upload, download and delete of a tiny object
+    Probe,
+    /// Back up directory tree, one object per file
+    Backup {
+        /// Directory to back up, or - to upload stdin as a single object.
+        /// Stdin uploads can't be resumed, a failed one has to be piped again
+        source: PathBuf,
+        /// Only show what would be uploaded
+        #[arg(long)]
+        dry_run: bool,
+        /// Number of files uploaded at once
+        #[arg(long, default_value_t = DEFAULT_BACKUP_CONCURRENCY)]
+        concurrency: usize,
+        /// How uploaded files are listed on stdout
+        #[arg(long, value_enum, default_value_t = ManifestFormat::Text)]
+        manifest_format: ManifestFormat,
+        /// Store directory as a single tar object, its index is printed as JSON.
+        /// Needs uncompressed storage for extracting single files and random or
+        /// fixed ids
+        #[arg(long, conflicts_with = "concurrency")]
+        archive: bool,
+        /// Pack files smaller than this many bytes into shared objects, fetched
+        /// with ranged reads on restore. Needs uncompressed storage, at most 100MiB
+        #[arg(long, conflicts_with = "archive")]
+        pack_below: Option<u64>,
+    },
+}
+
+const PASSPHRASE_ENV: &str = "PRIVATE_CLOUD_PASSPHRASE";
+
+// New passphrases are prompted for twice, to catch typos that would lock the config away.
+fn passphrase(confirm: bool) -> Result<Passphrase> {
+    if let Ok(passphrase) = std::env::var(PASSPHRASE_ENV) {
+        return Ok(passphrase.into());
+    }
+
+    let passphrase = Passphrase::from(rpassword::prompt_password("Config passphrase: ")?);
+
+    if confirm && Passphrase::from(rpassword::prompt_password("Repeat passphrase: ")?) != passphrase
+    {
+        return Err(anyhow!("Passphrases don't match"));
+    }
+
+    Ok(passphrase)
+}
+
+fn create_config_file(path: &Path) -> Result<()> {
+    let text = create_aws_config()?.encrypt(passphrase(true)?.as_str())?;
+
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .and_then(|mut file| std::io::Write::write_all(&mut file, text.as_bytes()))
+        .map_err(|e| anyhow!("Failed to write {}: {}", path.display(), e))
+}
+
+async fn load_provider(config_file: Option<&Path>) -> Result<AWS> {
+    let config = match config_file {
+        Some(path) => {
+            let text = std::fs::read_to_string(path)
+                .map_err(|e| anyhow!("Failed to read {}: {}", path.display(), e))?;
+            CloudProviderConfig::decrypt(&text, passphrase(false)?.as_str())?
+        }
+        None => create_aws_config()?,
+    };
+
+    AWS::load_from_config(config).await
+}
+
+async fn run(args: Args) -> Result<()> {
+    let config_file = args.config.as_deref();
+
+    match args.command {
+        Command::Create { path } => {
+            create_config_file(&path)?;
+            println!("config written to {}", path.display());
+
+            Ok(())
+        }
+        #[cfg(feature = "keyring")]
+        Command::StoreKey { entry } => {
+            let master_key =
+                std::env::var("MASTER_KEY").map_err(|_| anyhow!("MASTER_KEY is not set"))?;
+            let (service, account) = parse_entry_name(&entry)?;
+            store_master_key(service, account, &master_key)?;
+            println!("master key stored in {}", entry);
+
+            Ok(())
+        }
+        Command::Connect => {
+            load_provider(config_file).await?.connect_check().await?;
+            println!("storage is reachable");
+
+            Ok(())
+        }
+        Command::Run => private_cloud::cloud::run(&load_provider(config_file).await?).await,
+        Command::Probe => {
+            let result = load_provider(config_file).await?.probe().await?;
+            println!(
+                "upload {:?}, download {:?}, delete {:?}",
+                result.upload, result.download, result.delete
+            );
+
+            Ok(())
+        }
+        Command::Backup {
+            source,
+            dry_run,
+            concurrency,
+            manifest_format,
+            archive,
+            pack_below,
+        } => {
+            let stdin = source.as_os_str() == "-";
+
+            if archive && stdin {
+                return Err(anyhow!("Stdin can't be stored as an archive"));
+            }
+
+            if dry_run && stdin {
+                println!("would upload stdin");
+
+                return Ok(());
+            }
+
+            if dry_run {
+                // No provider needed, plan is built from local files only.
+                let plan = plan_backup(&source).await?;
+
+                for (path, size) in &plan.files {
+                    println!("would upload {:?} {}", path, size);
+                }
+                println!(
+                    "{} files, {} total",
+                    plan.files.len(),
+                    FileSize {
+                        size: plan.total_size()
+                    }
+                );
+
+                return Ok(());
+            }
+
+            let provider = load_provider(config_file).await?;
+
+            if archive {
+                let index = backup_archive(&provider, &source).await?;
+                println!("{}", serde_json::to_string_pretty(&index)?);
+
+                return Ok(());
+            }
+
+            let options = BackupOptions {
+                dry_run,
+                concurrency,
+                pack_below,
+            };
+            let mut manifest = BackupManifest::default();
+            let result = if stdin {
+                // Size isn't known upfront, it is recorded as counted while uploading.
+                provider
+                    .upload_stream(&mut tokio::io::stdin())
+                    .await
+                    .map(|receipt| {
+                        manifest.record_object_key(&receipt);
+                        manifest.files.insert(
+                            source.clone(),
+                            (receipt.storage_id, receipt.hash, receipt.size),
+                        );
+                    })
+            } else {
+                backup_dir(&provider, &source, &options, &mut manifest).await
+            };
+
+            // Print whatever was uploaded, even if some files failed.
+            match manifest_format {
+                ManifestFormat::Text => {
+                    for (path, (id, hash, size)) in &manifest.files {
+                        println!("{:?} {} {} {}", path, id, size, hash);
+                    }
+                    for (path, file) in &manifest.packed {
+                        println!(
+                            "{:?} {}+{} {} {}",
+                            path, file.pack_id, file.offset, file.size, file.hash
+                        );
+                    }
+                    for (id, key) in &manifest.object_keys {
+                        println!("{} key {}", id, key);
+                    }
+                }
+                ManifestFormat::Json => println!("{}", serde_json::to_string_pretty(&manifest)?),
+            }
+
+            result
+        }
+    }
 }
 
 #[tokio::main]
-async fn main() {
+async fn main() -> ExitCode {
     tracing_subscriber::fmt()
         .with_env_filter(EnvFilter::from_default_env())
         .compact()
         .init();
 
-    // TODO split create/connect/run modes
-    if let Err(e) = run().await {
-        eprintln!("Fatal error: {:?}", e);
+    match run(Args::parse()).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Fatal error: {:?}", e);
+            ExitCode::FAILURE
+        }
     }
 }
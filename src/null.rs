@@ -0,0 +1,241 @@
+use crate::aws::AWS;
+use crate::crypto::master_key::MasterKey;
+use crate::provider::*;
+use anyhow::Result;
+use async_trait::async_trait;
+use aws_sdk_s3::{Credentials, Region, RetryConfig};
+use aws_smithy_http::body::SdkBody;
+use aws_smithy_http::result::ConnectorError;
+use futures::future::BoxFuture;
+use futures::stream::{self, BoxStream, StreamExt};
+use hyper::body::HttpBody;
+use std::task::{Context, Poll};
+use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+// Provider without storage, for benchmarking local work apart from network latency. Uploads
+// run the S3 pipeline, parts, hashing and compression included, against a connection that
+// reads request bodies and drops them. Downloads write zeros of the expected size and,
+// having nothing to check, skip verification, as does verify_all.
+#[derive(Debug)]
+pub struct NullProvider {
+    aws: AWS,
+}
+
+impl NullProvider {
+    pub fn new(master_key: &MasterKey) -> Result<NullProvider> {
+        let config = aws_sdk_s3::Config::builder()
+            .region(Region::new("us-east-1"))
+            .credentials_provider(Credentials::new("null", "null", None, None, "null"))
+            .retry_config(RetryConfig::disabled())
+            .build();
+        let client = aws_sdk_s3::Client::from_conf_conn(config, DiscardingConnection);
+
+        Ok(NullProvider {
+            aws: AWS::with_client_and_key(client, master_key.clone())?,
+        })
+    }
+
+    // Compress uploads like an S3 provider configured with `compression`.
+    pub fn set_compression(&mut self, compression: Compression) {
+        self.aws.set_compression(compression);
+    }
+}
+
+// Answers S3 requests as a bucket keeping nothing: no object exists, and uploads succeed once
+// their body is read.
+#[derive(Clone)]
+struct DiscardingConnection;
+
+impl tower::Service<http::Request<SdkBody>> for DiscardingConnection {
+    type Response = http::Response<SdkBody>;
+    type Error = ConnectorError;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: http::Request<SdkBody>) -> Self::Future {
+        Box::pin(async move {
+            let (parts, mut body) = request.into_parts();
+
+            while let Some(data) = body.data().await {
+                data.map_err(|e| ConnectorError::other(e, None))?;
+            }
+
+            let query = parts.uri.query().unwrap_or_default();
+            let (status, body) = match parts.method {
+                http::Method::HEAD => (404, ""),
+                http::Method::POST if query.contains("uploads") => (
+                    200,
+                    "<InitiateMultipartUploadResult><UploadId>null</UploadId></InitiateMultipartUploadResult>",
+                ),
+                http::Method::POST => (200, "<CompleteMultipartUploadResult/>"),
+                http::Method::DELETE => (204, ""),
+                _ => (200, ""),
+            };
+
+            http::Response::builder()
+                .status(status)
+                .header("ETag", "\"null\"")
+                .body(SdkBody::from(body))
+                .map_err(|e| ConnectorError::other(e.into(), None))
+        })
+    }
+}
+
+async fn write_zeros(len: u64, path: &std::path::Path) -> Result<()> {
+    let mut file = File::create(path).await?;
+    tokio::io::copy(&mut tokio::io::repeat(0).take(len), &mut file).await?;
+
+    Ok(())
+}
+
+#[async_trait]
+impl CloudProviderFactory for NullProvider {
+    // Config data is hex master key, as for MockProvider.
+    async fn load_from_config(config: CloudProviderConfig) -> Result<Self> {
+        crate::crypto::init();
+
+        let master_key = MasterKey::from(std::str::from_utf8(&config.data)?)?;
+
+        NullProvider::new(&master_key)
+    }
+}
+
+#[async_trait]
+impl CloudProvider for NullProvider {
+    async fn connect_check(&self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn upload_file(&self, path: &std::path::Path) -> Result<UploadReceipt> {
+        self.aws.upload_file(path).await
+    }
+
+    async fn upload_stream(
+        &self,
+        source: &mut (dyn AsyncRead + Unpin + Send),
+    ) -> Result<UploadReceipt> {
+        self.aws.upload_stream(source).await
+    }
+
+    async fn download_file(
+        &self,
+        _storage_id: StorageId,
+        _expected_hash: &FileHash,
+        expected_size: &FileSize,
+        path: &std::path::Path,
+    ) -> Result<()> {
+        write_zeros(expected_size.size, path).await
+    }
+
+    async fn download_range(
+        &self,
+        _storage_id: &StorageId,
+        _offset: u64,
+        len: u64,
+        path: &std::path::Path,
+    ) -> Result<()> {
+        write_zeros(len, path).await
+    }
+
+    async fn verify_all(
+        &self,
+        manifest: &[(StorageId, FileHash, FileSize)],
+        _concurrency: usize,
+    ) -> Result<Vec<(StorageId, Result<VerifyResult>)>> {
+        Ok(manifest
+            .iter()
+            .map(|(storage_id, _, _)| (storage_id.to_owned(), Ok(VerifyResult::Ok)))
+            .collect())
+    }
+
+    async fn delete_file(&self, _storage_id: &StorageId) -> Result<()> {
+        Ok(())
+    }
+
+    fn compression(&self) -> Option<Compression> {
+        self.aws.compression()
+    }
+
+    fn list_files_stream(&self) -> BoxStream<'_, Result<StorageId>> {
+        stream::empty().boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::crypto::init;
+    use crate::crypto::master_key::MasterKey;
+    use crate::null::NullProvider;
+    use crate::provider::{CloudProvider, Compression, FileSize, UploadReceipt};
+    use crate::testing::MockProvider;
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn upload_download() {
+        init();
+        let master_key = MasterKey::new().expect("failed to create master key");
+        let provider = NullProvider::new(&master_key).expect("failed to create provider");
+        let mock = MockProvider::new(&master_key).expect("failed to create provider");
+        let dir = tempfile::tempdir().expect("failed to create temp dir");
+        let target = dir.path().join("target");
+
+        // Hashed the same as by a provider that keeps the data.
+        let UploadReceipt {
+            storage_id: id,
+            size,
+            hash,
+            ..
+        } = provider
+            .upload_stream(&mut &b"This is test message"[..])
+            .await
+            .expect("upload failed");
+        let UploadReceipt {
+            hash: mock_hash, ..
+        } = mock
+            .upload_stream(&mut &b"This is test message"[..])
+            .await
+            .expect("upload failed");
+        assert_eq!(size.size, 20);
+        assert_eq!(hash, mock_hash);
+
+        provider
+            .download_file(id, &hash, &FileSize { size: 5 }, &target)
+            .await
+            .expect("download failed");
+        assert_eq!(std::fs::read(&target).expect("failed to read file"), [0; 5]);
+        assert!(provider.list_files().await.expect("list failed").is_empty());
+    }
+
+    #[tokio::test]
+    async fn upload_pipeline() {
+        init();
+        let master_key = MasterKey::new().expect("failed to create master key");
+        let mut provider = NullProvider::new(&master_key).expect("failed to create provider");
+
+        // Sent in parts like by S3.
+        let UploadReceipt { part_size, .. } = provider
+            .upload_stream(&mut &b"data"[..])
+            .await
+            .expect("upload failed");
+        let UploadReceipt {
+            size, part_count, ..
+        } = provider
+            .upload_stream(&mut tokio::io::repeat(7).take(part_size as u64 + 1))
+            .await
+            .expect("upload failed");
+        assert_eq!(size.size, part_size as u64 + 1);
+        assert_eq!(part_count, 2);
+
+        provider.set_compression(Compression::Zstd);
+        let UploadReceipt { size, .. } = provider
+            .upload_stream(&mut tokio::io::repeat(7).take(1 << 20))
+            .await
+            .expect("upload failed");
+        assert!(size.size < 1 << 20);
+        assert_eq!(provider.compression(), Some(Compression::Zstd));
+    }
+}
@@ -1,36 +1,342 @@
-use anyhow::Result;
+use crate::crypto::hash::{hashes_equal, ChunkedHash, HASH_SIZE};
+use anyhow::{anyhow, Result};
 use async_trait::async_trait;
 use bytes::Bytes;
+use futures::stream::{BoxStream, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::Duration;
+use tokio::io::AsyncRead;
+use uuid::Uuid;
 
-#[derive(Debug, Clone, Default, Eq, PartialEq, Ord, PartialOrd, Hash)]
+// Storage ids are hyphenated lowercase UUIDs, only valid ones can be constructed.
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
 pub struct StorageId {
-    pub id: String,
+    id: String,
 }
 
-#[derive(Copy, Debug, Clone, Default, Eq, PartialEq, Ord, PartialOrd, Hash)]
+impl StorageId {
+    pub fn parse(s: &str) -> Result<StorageId> {
+        let valid = Uuid::try_parse(s).is_ok_and(|uuid| uuid.hyphenated().to_string() == s);
+
+        if !valid {
+            return Err(anyhow!("Invalid storage id: {:?}", s));
+        }
+
+        Ok(StorageId { id: s.to_owned() })
+    }
+
+    // New random id, for providers to name uploaded objects.
+    pub fn generate() -> StorageId {
+        StorageId {
+            id: Uuid::new_v4().hyphenated().to_string(),
+        }
+    }
+
+    // Deterministic stand-in for generate(), for tests asserting exact ids or keys. Same seed
+    // and index always give the same version 4 UUID, different indexes give different ones.
+    pub fn seeded(seed: u64, index: u64) -> StorageId {
+        let mut bytes = [0; 16];
+        bytes[..8].copy_from_slice(&splitmix64(seed, 2 * index).to_le_bytes());
+        bytes[8..].copy_from_slice(&splitmix64(seed, 2 * index + 1).to_le_bytes());
+
+        StorageId {
+            id: uuid::Builder::from_random_bytes(bytes)
+                .into_uuid()
+                .hyphenated()
+                .to_string(),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.id
+    }
+}
+
+impl TryFrom<String> for StorageId {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<StorageId> {
+        StorageId::parse(&s)
+    }
+}
+
+impl From<StorageId> for String {
+    fn from(id: StorageId) -> String {
+        id.id
+    }
+}
+
+// Nth output of SplitMix64 seeded with `seed`. Not for anything secret.
+fn splitmix64(seed: u64, n: u64) -> u64 {
+    let mut z = seed.wrapping_add(n.wrapping_add(1).wrapping_mul(0x9e3779b97f4a7c15));
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+
+    z ^ (z >> 31)
+}
+
+impl std::fmt::Display for StorageId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.id)
+    }
+}
+
+#[derive(
+    Copy, Debug, Clone, Default, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize, Deserialize,
+)]
+#[serde(transparent)]
 pub struct FileSize {
     pub size: u64,
 }
 
-#[derive(Debug, Clone, Default, Eq, PartialEq, Ord, PartialOrd, Hash)]
+impl FileSize {
+    pub fn bytes(&self) -> u64 {
+        self.size
+    }
+}
+
+// Human-readable, in binary units: "512 B", "1.5 GiB".
+impl std::fmt::Display for FileSize {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        const UNITS: [&str; 6] = ["KiB", "MiB", "GiB", "TiB", "PiB", "EiB"];
+
+        if self.size < 1024 {
+            return write!(f, "{} B", self.size);
+        }
+
+        let mut value = self.size as f64 / 1024.0;
+        let mut unit = 0;
+
+        while value >= 1024.0 && unit < UNITS.len() - 1 {
+            value /= 1024.0;
+            unit += 1;
+        }
+
+        write!(f, "{:.1} {}", value, UNITS[unit])
+    }
+}
+
+// Raw hash bytes, hex is only produced for display.
+#[derive(Clone, Default)]
 pub struct FileHash {
-    pub hash: String,
+    hash: [u8; HASH_SIZE],
+}
+
+impl FileHash {
+    pub fn from_bytes(hash: [u8; HASH_SIZE]) -> FileHash {
+        FileHash { hash }
+    }
+
+    pub fn as_bytes(&self) -> &[u8; HASH_SIZE] {
+        &self.hash
+    }
+}
+
+impl PartialEq for FileHash {
+    fn eq(&self, other: &Self) -> bool {
+        hashes_equal(&self.hash, &other.hash)
+    }
+}
+
+impl Eq for FileHash {}
+
+impl std::hash::Hash for FileHash {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.hash.hash(state);
+    }
+}
+
+impl std::fmt::Debug for FileHash {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("FileHash")
+            .field(&hex::encode(self.hash))
+            .finish()
+    }
+}
+
+impl std::fmt::Display for FileHash {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&hex::encode(self.hash))
+    }
 }
 
-#[derive(Debug, Clone, Default, Eq, PartialEq, Ord, PartialOrd, Hash)]
+// Serialized as hex, same as displayed.
+impl Serialize for FileHash {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&hex::encode(self.hash))
+    }
+}
+
+impl<'de> Deserialize<'de> for FileHash {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<FileHash, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        let mut hash = [0; HASH_SIZE];
+        hex::decode_to_slice(&s, &mut hash).map_err(serde::de::Error::custom)?;
+
+        Ok(FileHash { hash })
+    }
+}
+
+// Result of a successful upload, size and hash are of data as stored. ETag and version id are
+// as reported by the storage service, for audit trails and conditional requests. None if the
+// service has none, or nothing was sent because identical content was stored already.
+// Part count and size tell how many requests carried the data, which is most of its cost.
+// All parts but the last have the part size, a single put is one part of the whole size.
+// Both are zero if nothing was sent, or the provider doesn't upload in parts.
+// Object key is set by providers that can't find the object by storage id alone, record it
+// with the id and pass it to add_object_keys of providers that didn't upload it.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct UploadReceipt {
+    pub storage_id: StorageId,
+    pub size: FileSize,
+    pub hash: FileHash,
+    pub etag: Option<String>,
+    pub version_id: Option<String>,
+    pub part_count: u32,
+    pub part_size: usize,
+    pub object_key: Option<String>,
+}
+
+impl UploadReceipt {
+    // Receipt without service identifiers and parts.
+    pub fn new(storage_id: StorageId, size: FileSize, hash: FileHash) -> UploadReceipt {
+        UploadReceipt {
+            storage_id,
+            size,
+            hash,
+            etag: None,
+            version_id: None,
+            part_count: 0,
+            part_size: 0,
+            object_key: None,
+        }
+    }
+}
+
+// Whether an upload sent anything. Only providers checking for identical content first, such
+// as S3 with content hash ids, ever skip an upload. A receipt of a skipped upload describes the
+// object already stored, without service identifiers.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum UploadOutcome {
+    Uploaded(UploadReceipt),
+    Skipped(UploadReceipt),
+}
+
+impl UploadOutcome {
+    pub fn receipt(&self) -> &UploadReceipt {
+        match self {
+            UploadOutcome::Uploaded(receipt) | UploadOutcome::Skipped(receipt) => receipt,
+        }
+    }
+
+    pub fn into_receipt(self) -> UploadReceipt {
+        match self {
+            UploadOutcome::Uploaded(receipt) | UploadOutcome::Skipped(receipt) => receipt,
+        }
+    }
+
+    pub fn is_skipped(&self) -> bool {
+        matches!(self, UploadOutcome::Skipped(_))
+    }
+}
+
+// Stored objects are compressed, so their size and hash differ from the source file.
+#[derive(Copy, Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub enum Compression {
+    Zstd,
+}
+
+// What downloads do with an existing target file, which is left alone by default.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub enum OverwritePolicy {
+    // Return CloudError::TargetExists.
+    #[default]
+    Fail,
+    // Replaced once the download is complete, a failed one leaves it as it was.
+    Overwrite,
+    // Move the existing file to `<name>.<n>`, the first n from 1 not taken, then download. It
+    // stays moved if the download fails.
+    RenameExisting,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub enum VerifyResult {
+    Ok,
+    SizeMismatch {
+        expected: FileSize,
+        actual: FileSize,
+    },
+    HashMismatch {
+        expected: FileHash,
+        actual: FileHash,
+    },
+    // No object stored under the id.
+    Missing,
+}
+
+#[derive(Copy, Debug, Clone, Eq, PartialEq, Hash)]
+pub enum TransferKind {
+    Upload,
+    Download,
+}
+
+// Numbers for a completed transfer, for monitoring. Bytes are as stored, parts count upload
+// parts or download requests, resumed downloads included. complete_retries counts repeated
+// requests completing an upload, always 0 for downloads. Retries made within the SDK, of any
+// request, are not seen here and not counted.
+#[derive(Copy, Debug, Clone, Default, Eq, PartialEq, Hash)]
+pub struct TransferStats {
+    pub bytes: u64,
+    pub duration: Duration,
+    pub parts: u32,
+    pub complete_retries: u32,
+}
+
+// Progress of a single transfer as it happens. Parts are numbered from 1, bytes are as stored.
+#[derive(Copy, Debug, Clone, Eq, PartialEq, Hash)]
+pub enum TransferEvent {
+    PartStarted { part: u32 },
+    PartCompleted { part: u32, bytes: u64 },
+    Completed,
+}
+
+#[derive(Clone, Default, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub struct CloudProviderConfig {
     pub data: Bytes,
 }
 
+// Data holds credentials and master key in the clear, only its size is shown.
+impl std::fmt::Debug for CloudProviderConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CloudProviderConfig")
+            .field("data", &format_args!("{} bytes", self.data.len()))
+            .finish()
+    }
+}
+
+// Object safe, so providers of different kinds can be used as Box<dyn CloudProvider>.
 #[async_trait]
-pub trait CloudProvider {
-    // Initialize from serialized config.
-    async fn load_from_config(config: CloudProviderConfig) -> Result<Self>
-    where
-        Self: Sized;
+pub trait CloudProvider: Send + Sync {
+    // Check that storage is reachable with configured credentials, to report misconfiguration
+    // before any transfer starts.
+    async fn connect_check(&self) -> Result<()>;
+
+    // Send file to cloud, return its ID and metadata, see UploadReceipt.
+    async fn upload_file(&self, path: &std::path::Path) -> Result<UploadReceipt>;
 
-    // Send file to cloud, return its ID and metadata.
-    async fn upload_file(&self, path: &std::path::Path) -> Result<(StorageId, FileSize, FileHash)>;
+    // Same as upload_file, also telling whether the content was stored already and nothing
+    // was sent, for sync reports. Providers without such a check upload everything.
+    async fn upload_file_outcome(&self, path: &std::path::Path) -> Result<UploadOutcome> {
+        Ok(UploadOutcome::Uploaded(self.upload_file(path).await?))
+    }
+
+    // Send data of unknown size (e.g. a pipe) to cloud, return its ID and metadata.
+    async fn upload_stream(
+        &self,
+        source: &mut (dyn AsyncRead + Unpin + Send),
+    ) -> Result<UploadReceipt>;
 
     // Load file from cloud and save locally, check hash, return download size.
     async fn download_file(
@@ -40,4 +346,341 @@ pub trait CloudProvider {
         expected_size: &FileSize,
         path: &std::path::Path,
     ) -> Result<()>;
+
+    // Save `len` stored bytes starting at `offset` to `path`, e.g. one file of an archive.
+    // Data is not verified, the object hash covers all of it.
+    async fn download_range(
+        &self,
+        storage_id: &StorageId,
+        offset: u64,
+        len: u64,
+        path: &std::path::Path,
+    ) -> Result<()>;
+
+    // Check stored files against expected hash and size without saving them locally.
+    // Runs up to `concurrency` checks at once, results are in manifest order. Each file has its
+    // own, an object that can't be read fails only its check.
+    async fn verify_all(
+        &self,
+        manifest: &[(StorageId, FileHash, FileSize)],
+        concurrency: usize,
+    ) -> Result<Vec<(StorageId, Result<VerifyResult>)>>;
+
+    // Remove stored file, e.g. one no longer referenced by any manifest.
+    async fn delete_file(&self, storage_id: &StorageId) -> Result<()>;
+
+    // Remove several stored files, with a result for each: some may fail while the rest are
+    // deleted. Removed one by one unless the provider has batch delete.
+    async fn delete_files(
+        &self,
+        storage_ids: &[StorageId],
+    ) -> Result<Vec<(StorageId, Result<()>)>> {
+        let mut results = Vec::with_capacity(storage_ids.len());
+
+        for storage_id in storage_ids {
+            results.push((storage_id.clone(), self.delete_file(storage_id).await));
+        }
+
+        Ok(results)
+    }
+
+    // Compression applied to stored objects, which then can't be read by range.
+    fn compression(&self) -> Option<Compression> {
+        None
+    }
+
+    // Directory for staging files, system temp dir unless configured.
+    fn temp_dir(&self) -> std::path::PathBuf {
+        std::env::temp_dir()
+    }
+
+    // Ids are derived from stored content, so uploads need a source that can be read twice
+    // and upload_stream fails.
+    fn content_addressed(&self) -> bool {
+        false
+    }
+
+    // Hash for files stored within other objects, e.g. packed files and archive entries,
+    // which providers don't hash on their own. Keyed like object hashes, so the same file
+    // hashes differently under another master key or bucket. Unkeyed for providers without
+    // hash keys.
+    fn segment_hasher(&self) -> ChunkedHash {
+        ChunkedHash::new()
+    }
+
+    // Object keys recorded from upload receipts, by storage id. Ignored by providers that
+    // don't return them.
+    fn add_object_keys(&self, _keys: &BTreeMap<StorageId, String>) {}
+
+    // Ids of all stored files, yielded as listing pages arrive.
+    fn list_files_stream(&self) -> BoxStream<'_, Result<StorageId>>;
+
+    // Ids of all stored files.
+    async fn list_files(&self) -> Result<Vec<StorageId>> {
+        self.list_files_stream().try_collect().await
+    }
+}
+
+// Construction from serialized config, kept apart from CloudProvider so that stays object safe.
+#[async_trait]
+pub trait CloudProviderFactory: CloudProvider + Sized {
+    // Initialize from serialized config.
+    async fn load_from_config(config: CloudProviderConfig) -> Result<Self>;
+}
+
+// Runs `futures` with at most `concurrency` in flight and returns their results in input order,
+// stopping at the first error. Takes them collected: a lazy iterator with a borrowing closure held
+// across an await trips async-trait's Send check.
+#[cfg(any(feature = "aws", feature = "azure"))]
+pub(crate) async fn try_join_buffered<F, T>(futures: Vec<F>, concurrency: usize) -> Result<Vec<T>>
+where
+    F: std::future::Future<Output = Result<T>>,
+{
+    use futures::stream::{self, StreamExt};
+
+    let mut results: Vec<(usize, T)> = stream::iter(futures.into_iter().enumerate())
+        .map(|(index, future)| async move { future.await.map(|result| (index, result)) })
+        .buffer_unordered(concurrency)
+        .try_collect()
+        .await?;
+    results.sort_unstable_by_key(|(index, _)| *index);
+
+    Ok(results.into_iter().map(|(_, result)| result).collect())
+}
+
+// Single file read size for uploads. Tokio reads only up to its internal buffer size per call,
+// larger reads showed no gain on 1GB file, 16KB reads were ~20% slower.
+#[cfg(any(feature = "aws", feature = "azure"))]
+pub(crate) const READ_SIZE: usize = 2 * 1024 * 1024;
+
+// Reads return at most READ_SIZE; collate them until buffer is full. Returns false at EOF.
+// AsyncRead readers with no data yet return Pending, so the first empty read is EOF and the
+// buffer holds whatever came before it.
+#[cfg(any(feature = "aws", feature = "azure"))]
+pub(crate) async fn fill_buffer(
+    reader: &mut (impl AsyncRead + Unpin + ?Sized),
+    buffer: &mut bytes::BytesMut,
+) -> Result<bool> {
+    use tokio::io::AsyncReadExt;
+
+    while buffer.len() < buffer.capacity() {
+        if reader.read_buf(buffer).await? == 0 {
+            return Ok(false);
+        }
+    }
+
+    Ok(true)
+}
+
+// File a download to `path` writes: the path itself, or for an existing file to overwrite, a temp
+// file next to it, renamed over it by `commit`. Temp file is removed if the download fails.
+// Checked before the download rather than by opening with create_new, attempts after an
+// interruption reopen the file the first one created.
+#[cfg(any(feature = "aws", feature = "azure"))]
+pub(crate) struct DownloadTarget<'a> {
+    path: &'a std::path::Path,
+    staged: Option<crate::cloud::TempFile>,
+}
+
+#[cfg(any(feature = "aws", feature = "azure"))]
+impl<'a> DownloadTarget<'a> {
+    pub(crate) async fn prepare(
+        path: &'a std::path::Path,
+        overwrite: OverwritePolicy,
+    ) -> Result<DownloadTarget<'a>> {
+        let target = DownloadTarget { path, staged: None };
+
+        match tokio::fs::symlink_metadata(path).await {
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(target),
+            Err(e) => return Err(e.into()),
+        }
+
+        match overwrite {
+            OverwritePolicy::Fail => {
+                Err(crate::error::CloudError::TargetExists(path.to_owned()).into())
+            }
+            OverwritePolicy::Overwrite => {
+                // Same directory, so the rename doesn't cross filesystems.
+                let dir = match path.parent() {
+                    Some(dir) if !dir.as_os_str().is_empty() => dir,
+                    _ => std::path::Path::new("."),
+                };
+
+                Ok(DownloadTarget {
+                    staged: Some(crate::cloud::TempFile::new(dir, "download")),
+                    ..target
+                })
+            }
+            OverwritePolicy::RenameExisting => {
+                let name = path
+                    .file_name()
+                    .ok_or_else(|| anyhow!("No file name in {:?}", path))?;
+                let mut n = 1;
+
+                loop {
+                    let mut renamed = name.to_owned();
+                    renamed.push(format!(".{}", n));
+                    let renamed = path.with_file_name(renamed);
+
+                    match tokio::fs::symlink_metadata(&renamed).await {
+                        Ok(_) => n += 1,
+                        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                            tokio::fs::rename(path, &renamed).await?;
+                            tracing::trace!(?renamed, "existing file moved aside");
+
+                            return Ok(target);
+                        }
+                        Err(e) => return Err(e.into()),
+                    }
+                }
+            }
+        }
+    }
+
+    pub(crate) fn path(&self) -> &std::path::Path {
+        match &self.staged {
+            Some(staged) => &staged.path,
+            None => self.path,
+        }
+    }
+
+    // Call once the download is complete and verified.
+    pub(crate) async fn commit(self) -> Result<()> {
+        if let Some(staged) = &self.staged {
+            tokio::fs::rename(&staged.path, self.path).await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::crypto::init;
+    #[cfg(any(feature = "aws", feature = "azure"))]
+    use crate::provider::fill_buffer;
+    use crate::provider::{FileHash, FileSize, StorageId};
+
+    #[cfg(any(feature = "aws", feature = "azure"))]
+    #[tokio::test]
+    async fn fill_buffer_eof() {
+        use bytes::BytesMut;
+        use std::collections::VecDeque;
+        use std::pin::Pin;
+        use std::task::{Context, Poll};
+        use tokio::io::{AsyncRead, ReadBuf};
+
+        // Returns scripted pieces one per read, then EOF.
+        struct ScriptedReader {
+            pieces: VecDeque<&'static [u8]>,
+        }
+
+        impl AsyncRead for ScriptedReader {
+            fn poll_read(
+                mut self: Pin<&mut Self>,
+                _cx: &mut Context<'_>,
+                buf: &mut ReadBuf<'_>,
+            ) -> Poll<std::io::Result<()>> {
+                if let Some(piece) = self.pieces.pop_front() {
+                    buf.put_slice(piece);
+                }
+                Poll::Ready(Ok(()))
+            }
+        }
+
+        let mut reader = ScriptedReader {
+            pieces: VecDeque::from([&b"abc"[..], b"def", b"gh", b"ijkl"]),
+        };
+
+        let mut buffer = BytesMut::with_capacity(8);
+        assert!(fill_buffer(&mut reader, &mut buffer)
+            .await
+            .expect("read failed"));
+        assert_eq!(&buffer[..], b"abcdefgh");
+
+        let mut buffer = BytesMut::with_capacity(8);
+        assert!(!fill_buffer(&mut reader, &mut buffer)
+            .await
+            .expect("read failed"));
+        assert_eq!(&buffer[..], b"ijkl");
+
+        let mut buffer = BytesMut::with_capacity(8);
+        assert!(!fill_buffer(&mut reader, &mut buffer)
+            .await
+            .expect("read failed"));
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn storage_id_parse() {
+        let id = StorageId::parse("67e55044-10b1-426f-9247-bb680e5fe0c8").expect("valid id");
+        assert_eq!(id.as_str(), "67e55044-10b1-426f-9247-bb680e5fe0c8");
+        assert_eq!(id.to_string(), "67e55044-10b1-426f-9247-bb680e5fe0c8");
+
+        let generated = StorageId::generate();
+        assert_eq!(StorageId::parse(generated.as_str()).ok(), Some(generated));
+    }
+
+    #[test]
+    fn storage_id_seeded() {
+        let seeded = StorageId::seeded(42, 0);
+        assert_eq!(StorageId::parse(seeded.as_str()).ok(), Some(seeded.clone()));
+        assert_eq!(seeded, StorageId::seeded(42, 0));
+        assert_ne!(seeded, StorageId::seeded(42, 1));
+        assert_ne!(seeded, StorageId::seeded(43, 0));
+        assert_eq!(&seeded.as_str()[14..15], "4");
+    }
+
+    #[test]
+    fn storage_id_parse_invalid() {
+        for s in [
+            "",
+            "not-a-uuid",
+            "67e5504410b1426f9247bb680e5fe0c8",
+            "67E55044-10B1-426F-9247-BB680E5FE0C8",
+            "{67e55044-10b1-426f-9247-bb680e5fe0c8}",
+            "urn:uuid:67e55044-10b1-426f-9247-bb680e5fe0c8",
+            "67e55044-10b1-426f-9247-bb680e5fe0c8/../x",
+        ] {
+            assert!(
+                matches!(StorageId::parse(s), Err { .. }),
+                "{:?} accepted",
+                s
+            );
+        }
+    }
+
+    #[test]
+    fn file_hash() {
+        init();
+        let mut bytes = [0; 32];
+        bytes[0] = 0xab;
+        bytes[31] = 0x01;
+
+        let hash = FileHash::from_bytes(bytes);
+        assert_eq!(hash.as_bytes(), &bytes);
+        assert_eq!(
+            hash.to_string(),
+            "ab00000000000000000000000000000000000000000000000000000000000001"
+        );
+        assert_eq!(hash, FileHash::from_bytes(bytes));
+        assert_ne!(hash, FileHash::default());
+    }
+
+    #[test]
+    fn file_size_display() {
+        for (size, expected) in [
+            (0, "0 B"),
+            (1023, "1023 B"),
+            (1024, "1.0 KiB"),
+            (1536, "1.5 KiB"),
+            (3 * 1024 * 1024 * 1024 / 2, "1.5 GiB"),
+            (u64::MAX, "16.0 EiB"),
+        ] {
+            let file_size = FileSize { size };
+            assert_eq!(file_size.bytes(), size);
+            assert_eq!(file_size.to_string(), expected);
+        }
+    }
 }
@@ -0,0 +1,478 @@
+use crate::crypto::hash::{ChunkedHash, HashKeys, HashKind};
+use crate::crypto::master_key::MasterKey;
+use crate::provider::*;
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::stream::{self, BoxStream, StreamExt};
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Failure {
+    // Call fails without touching stored data.
+    Network,
+    // Transferred data is damaged: uploads store it, downloads fail with hash mismatch.
+    Corruption,
+}
+
+// In-memory provider for testing code built on CloudProvider, computes real keyed hashes.
+#[derive(Debug)]
+pub struct MockProvider {
+    hash_keys: HashKeys,
+    objects: Mutex<HashMap<StorageId, Bytes>>,
+    calls: AtomicUsize,
+    failures: Mutex<HashMap<usize, Failure>>,
+    temp_dir: Option<PathBuf>,
+    // Uploads get StorageId::seeded ids if set, indexed by upload.
+    id_seed: Option<u64>,
+    // Uploads get ids derived from content hash, so identical files share an object.
+    content_ids: bool,
+    // Only reported, stored data is kept as uploaded.
+    compression: Option<Compression>,
+    // Receipts carry object keys if set, like S3 with namers depending on upload meta.
+    object_keys: bool,
+    // Keys passed to add_object_keys.
+    added_keys: Mutex<BTreeMap<StorageId, String>>,
+    uploads: AtomicU64,
+}
+
+impl MockProvider {
+    pub fn new(master_key: &MasterKey) -> Result<MockProvider> {
+        Ok(MockProvider {
+            hash_keys: HashKeys::new(master_key, 0)?,
+            objects: Mutex::new(HashMap::new()),
+            calls: AtomicUsize::new(0),
+            failures: Mutex::new(HashMap::new()),
+            temp_dir: None,
+            id_seed: None,
+            content_ids: false,
+            compression: None,
+            object_keys: false,
+            added_keys: Mutex::new(BTreeMap::new()),
+            uploads: AtomicU64::new(0),
+        })
+    }
+
+    // Inject failure into Nth (1-based) provider call, counting all trait methods.
+    pub fn fail_call(&self, call: usize, failure: Failure) {
+        self.failures.lock().unwrap().insert(call, failure);
+    }
+
+    pub fn set_temp_dir(&mut self, temp_dir: PathBuf) {
+        self.temp_dir = Some(temp_dir);
+    }
+
+    pub fn set_id_seed(&mut self, seed: u64) {
+        self.id_seed = Some(seed);
+    }
+
+    pub fn set_content_ids(&mut self) {
+        self.content_ids = true;
+    }
+
+    pub fn set_compression(&mut self, compression: Compression) {
+        self.compression = Some(compression);
+    }
+
+    pub fn set_object_keys(&mut self) {
+        self.object_keys = true;
+    }
+
+    pub fn added_keys(&self) -> BTreeMap<StorageId, String> {
+        self.added_keys.lock().unwrap().clone()
+    }
+
+    pub fn calls(&self) -> usize {
+        self.calls.load(Ordering::SeqCst)
+    }
+
+    pub fn object(&self, storage_id: &StorageId) -> Option<Bytes> {
+        self.objects.lock().unwrap().get(storage_id).cloned()
+    }
+
+    pub fn object_count(&self) -> usize {
+        self.objects.lock().unwrap().len()
+    }
+
+    fn next_call(&self) -> Result<Option<Failure>> {
+        let call = self.calls.fetch_add(1, Ordering::SeqCst) + 1;
+
+        match self.failures.lock().unwrap().remove(&call) {
+            Some(Failure::Network) => Err(anyhow!("Injected network error on call {}", call)),
+            failure => Ok(failure),
+        }
+    }
+
+    fn hash(&self, data: &Bytes) -> FileHash {
+        let mut hash = ChunkedHash::keyed(self.hash_keys.get(HashKind::File));
+        hash.update(data.clone());
+
+        FileHash::from_bytes(hash.finalize())
+    }
+
+    // Keeps uploaded data, damaged if the call has corruption injected.
+    fn store(&self, data: Bytes, failure: Option<Failure>) -> UploadReceipt {
+        let size = FileSize {
+            size: data.len() as u64,
+        };
+        let hash = self.hash(&data);
+
+        let stored = match failure {
+            Some(Failure::Corruption) => corrupt(&data),
+            _ => data,
+        };
+
+        let index = self.uploads.fetch_add(1, Ordering::SeqCst);
+        let storage_id = match self.id_seed {
+            _ if self.content_ids => content_storage_id(&hash),
+            Some(seed) => StorageId::seeded(seed, index),
+            None => StorageId::generate(),
+        };
+        self.objects
+            .lock()
+            .unwrap()
+            .insert(storage_id.to_owned(), stored);
+
+        UploadReceipt {
+            object_key: self.object_keys.then(|| mock_object_key(&storage_id)),
+            ..UploadReceipt::new(storage_id, size, hash)
+        }
+    }
+}
+
+// Key reported for objects when object_keys is set.
+pub fn mock_object_key(storage_id: &StorageId) -> String {
+    format!("keys/{}", storage_id.as_str())
+}
+
+fn content_storage_id(hash: &FileHash) -> StorageId {
+    let mut bytes = [0; 16];
+    bytes.copy_from_slice(&hash.as_bytes()[..16]);
+
+    StorageId::parse(&Uuid::from_bytes(bytes).hyphenated().to_string())
+        .expect("hyphenated UUID is a valid storage id")
+}
+
+fn corrupt(data: &Bytes) -> Bytes {
+    let mut damaged = data.to_vec();
+
+    match damaged.first_mut() {
+        Some(b) => *b ^= 1,
+        None => damaged.push(0),
+    }
+
+    Bytes::from(damaged)
+}
+
+#[async_trait]
+impl CloudProviderFactory for MockProvider {
+    // Config data is hex master key.
+    async fn load_from_config(config: CloudProviderConfig) -> Result<Self> {
+        crate::crypto::init();
+
+        let master_key = MasterKey::from(std::str::from_utf8(&config.data)?)?;
+
+        MockProvider::new(&master_key)
+    }
+}
+
+#[async_trait]
+impl CloudProvider for MockProvider {
+    async fn connect_check(&self) -> Result<()> {
+        self.next_call()?;
+
+        Ok(())
+    }
+
+    async fn upload_file(&self, path: &std::path::Path) -> Result<UploadReceipt> {
+        let failure = self.next_call()?;
+        let data = Bytes::from(tokio::fs::read(path).await?);
+
+        Ok(self.store(data, failure))
+    }
+
+    async fn upload_stream(
+        &self,
+        source: &mut (dyn AsyncRead + Unpin + Send),
+    ) -> Result<UploadReceipt> {
+        let failure = self.next_call()?;
+        let mut data = vec![];
+        source.read_to_end(&mut data).await?;
+
+        Ok(self.store(Bytes::from(data), failure))
+    }
+
+    async fn download_file(
+        &self,
+        storage_id: StorageId,
+        expected_hash: &FileHash,
+        expected_size: &FileSize,
+        path: &std::path::Path,
+    ) -> Result<()> {
+        let failure = self.next_call()?;
+        let mut data = self
+            .object(&storage_id)
+            .ok_or_else(|| anyhow!("Object {} not found", storage_id.as_str()))?;
+
+        if failure == Some(Failure::Corruption) {
+            data = corrupt(&data);
+        }
+
+        if data.len() as u64 != expected_size.size {
+            return Err(anyhow!(
+                "File size mismatch: expected {}, got {}",
+                expected_size.size,
+                data.len(),
+            ));
+        }
+
+        let actual_hash = self.hash(&data);
+
+        if actual_hash != *expected_hash {
+            return Err(anyhow!(
+                "File hash mismatch: expected {}, got {}",
+                expected_hash,
+                actual_hash,
+            ));
+        }
+
+        tokio::fs::write(path, data).await?;
+
+        Ok(())
+    }
+
+    async fn download_range(
+        &self,
+        storage_id: &StorageId,
+        offset: u64,
+        len: u64,
+        path: &std::path::Path,
+    ) -> Result<()> {
+        let failure = self.next_call()?;
+        let data = self
+            .object(storage_id)
+            .ok_or_else(|| anyhow!("Object {} not found", storage_id.as_str()))?;
+
+        let range = usize::try_from(offset)?..usize::try_from(offset + len)?;
+        let mut part = data
+            .get(range)
+            .map(Bytes::copy_from_slice)
+            .ok_or_else(|| anyhow!("Range at {} is out of object bounds", offset))?;
+
+        if failure == Some(Failure::Corruption) {
+            part = corrupt(&part);
+        }
+
+        tokio::fs::write(path, part).await?;
+
+        Ok(())
+    }
+
+    async fn verify_all(
+        &self,
+        manifest: &[(StorageId, FileHash, FileSize)],
+        _concurrency: usize,
+    ) -> Result<Vec<(StorageId, Result<VerifyResult>)>> {
+        let failure = self.next_call()?;
+        let mut results = vec![];
+
+        for (storage_id, expected_hash, expected_size) in manifest {
+            let mut data = match self.object(storage_id) {
+                Some(data) => data,
+                None => {
+                    results.push((storage_id.to_owned(), Ok(VerifyResult::Missing)));
+                    continue;
+                }
+            };
+
+            if failure == Some(Failure::Corruption) {
+                data = corrupt(&data);
+            }
+
+            let actual_size = FileSize {
+                size: data.len() as u64,
+            };
+            let actual_hash = self.hash(&data);
+
+            let result = if actual_size != *expected_size {
+                VerifyResult::SizeMismatch {
+                    expected: *expected_size,
+                    actual: actual_size,
+                }
+            } else if actual_hash != *expected_hash {
+                VerifyResult::HashMismatch {
+                    expected: expected_hash.to_owned(),
+                    actual: actual_hash,
+                }
+            } else {
+                VerifyResult::Ok
+            };
+            results.push((storage_id.to_owned(), Ok(result)));
+        }
+
+        Ok(results)
+    }
+
+    async fn delete_file(&self, storage_id: &StorageId) -> Result<()> {
+        self.next_call()?;
+        self.objects
+            .lock()
+            .unwrap()
+            .remove(storage_id)
+            .ok_or_else(|| anyhow!("Object {} not found", storage_id.as_str()))?;
+
+        Ok(())
+    }
+
+    fn compression(&self) -> Option<Compression> {
+        self.compression
+    }
+
+    fn content_addressed(&self) -> bool {
+        self.content_ids
+    }
+
+    fn segment_hasher(&self) -> ChunkedHash {
+        ChunkedHash::keyed(self.hash_keys.get(HashKind::Manifest))
+    }
+
+    fn add_object_keys(&self, keys: &BTreeMap<StorageId, String>) {
+        self.added_keys
+            .lock()
+            .unwrap()
+            .extend(keys.iter().map(|(id, key)| (id.to_owned(), key.to_owned())));
+    }
+
+    fn temp_dir(&self) -> PathBuf {
+        self.temp_dir.clone().unwrap_or_else(std::env::temp_dir)
+    }
+
+    fn list_files_stream(&self) -> BoxStream<'_, Result<StorageId>> {
+        let ids: Vec<_> = match self.next_call() {
+            Ok(_) => self
+                .objects
+                .lock()
+                .unwrap()
+                .keys()
+                .cloned()
+                .map(Ok)
+                .collect(),
+            Err(e) => vec![Err(e)],
+        };
+
+        stream::iter(ids).boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::crypto::init;
+    use crate::crypto::master_key::MasterKey;
+    use crate::provider::{CloudProvider, StorageId, UploadReceipt, VerifyResult};
+    use crate::testing::{Failure, MockProvider};
+
+    fn provider() -> MockProvider {
+        init();
+        let master_key = MasterKey::new().expect("failed to create master key");
+
+        MockProvider::new(&master_key).expect("failed to create provider")
+    }
+
+    #[tokio::test]
+    async fn seeded_ids() {
+        let mut provider = provider();
+        provider.set_id_seed(7);
+
+        for index in 0..3 {
+            let UploadReceipt { storage_id: id, .. } = provider
+                .upload_stream(&mut &b"data"[..])
+                .await
+                .expect("upload failed");
+            assert_eq!(id, StorageId::seeded(7, index));
+        }
+    }
+
+    #[tokio::test]
+    async fn upload_download() {
+        let provider = provider();
+        let dir = tempfile::tempdir().expect("failed to create temp dir");
+        let source = dir.path().join("source");
+        let target = dir.path().join("target");
+        std::fs::write(&source, b"This is test message").expect("failed to write file");
+
+        let UploadReceipt {
+            storage_id: id,
+            size,
+            hash,
+            ..
+        } = provider.upload_file(&source).await.expect("upload failed");
+        assert_eq!(size.size, 20);
+
+        provider
+            .download_file(id.clone(), &hash, &size, &target)
+            .await
+            .expect("download failed");
+        assert_eq!(
+            std::fs::read(&target).expect("failed to read file"),
+            b"This is test message"
+        );
+        assert_eq!(provider.calls(), 2);
+
+        let ids = provider.list_files().await.expect("list failed");
+        assert_eq!(ids, [id]);
+    }
+
+    #[tokio::test]
+    async fn upload_stream() {
+        let provider = provider();
+        let mut source = &b"This is test message"[..];
+
+        let UploadReceipt {
+            storage_id: id,
+            size,
+            ..
+        } = provider
+            .upload_stream(&mut source)
+            .await
+            .expect("upload failed");
+        assert_eq!(size.size, 20);
+        assert_eq!(
+            provider.object(&id).as_deref(),
+            Some(&b"This is test message"[..])
+        );
+    }
+
+    #[tokio::test]
+    async fn injected_failures() {
+        let provider = provider();
+        let dir = tempfile::tempdir().expect("failed to create temp dir");
+        let source = dir.path().join("source");
+        let target = dir.path().join("target");
+        std::fs::write(&source, b"This is test message").expect("failed to write file");
+
+        provider.fail_call(1, Failure::Network);
+        assert!(matches!(provider.upload_file(&source).await, Err { .. }));
+        assert_eq!(provider.object_count(), 0);
+
+        provider.fail_call(3, Failure::Corruption);
+        let UploadReceipt {
+            storage_id: id,
+            size,
+            hash,
+            ..
+        } = provider.upload_file(&source).await.expect("upload failed");
+        let result = provider.download_file(id.to_owned(), &hash, &size, &target);
+        assert!(matches!(result.await, Err { .. }));
+
+        let results = provider
+            .verify_all(&[(id.to_owned(), hash, size)], 1)
+            .await
+            .expect("verify failed");
+        assert!(matches!(&results[..], [(result_id, Ok(VerifyResult::Ok))] if *result_id == id));
+    }
+}